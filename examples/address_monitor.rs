@@ -12,7 +12,9 @@ fn main() {
 
     let mut counter = 0;
     loop {
-        stack.process();
+        if let Err(err) = stack.process() {
+            println!("Stack error: {}", err);
+        }
         std::thread::sleep(std::time::Duration::from_millis(10));
         counter += 1;
         // run every 1s
@@ -21,7 +23,9 @@ fn main() {
             println!("{:#?}", stack.control_function_list());
             // Send a Address Request to update the address list
             let req = Request::new(PGN_ADDRESSCLAIM, 0xFE, 0xFF);
-            stack.send_frame(req.into());
+            if let Err(err) = stack.send_frame(req.into()) {
+                println!("Could not send request: {}", err);
+            }
             println!("Request addresses");
         }
     }
@@ -1,5 +1,6 @@
 use j1939::{
     self,
+    filter::FilterRule,
     frame::{Frame, PGN},
 };
 use socketcan::{CanSocket, Socket};
@@ -38,14 +39,15 @@ fn main() {
     let socket = CanSocket::open("vcan0").unwrap();
     socket.set_nonblocking(true).unwrap();
     let mut stack = j1939::stack::Stack::new(socket, j1939::time::std::StdTimerDriver::new());
+    // the stack only monitors the bus, only the EEC1 message is received
+    stack.add_filter(FilterRule::new().pgn(PGN_ELECTRONICENGINECONTROLLER));
 
     loop {
-        stack.process();
-        // ToDo: Change to CF and use pgn filter
+        if let Err(err) = stack.process() {
+            println!("Stack error: {}", err);
+        }
         while let Some(msg) = stack.get_frame() {
-            if msg.header().pgn() == PGN_ELECTRONICENGINECONTROLLER {
-                println!("{:?}", EEC1::from(msg));
-            }
+            println!("{:?}", EEC1::from(msg));
         }
 
         std::thread::sleep(std::time::Duration::from_millis(10));
@@ -128,7 +128,9 @@ impl Display {
                     Request::new(led_status::PGN_LED_STATUS, 0xFF, 0x80).into()
                 }
             };
-            cf.send_frame(output_frame);
+            if let Err(err) = cf.send_frame(output_frame) {
+                println!("Could not send frame: {:?}", err);
+            }
         }
     }
 }
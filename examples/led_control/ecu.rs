@@ -61,13 +61,19 @@ impl ECU {
         while let Some(msg) = cf.get_frame() {
             match msg.header().pgn() {
                 PGN_REQUEST => {
-                    cf.send_frame(self.handle_request(msg));
+                    if let Err(err) = cf.send_frame(self.handle_request(msg)) {
+                        println!("Could not send frame: {:?}", err);
+                    }
                 }
                 led_control::PGN_LED_CONTROL => {
-                    cf.send_frame(self.handle_led_control(msg));
+                    if let Err(err) = cf.send_frame(self.handle_led_control(msg)) {
+                        println!("Could not send frame: {:?}", err);
+                    }
                 }
                 led_command::PGN_LED_COMMAND => {
-                    cf.send_frame(self.handle_led_command(msg));
+                    if let Err(err) = cf.send_frame(self.handle_led_command(msg)) {
+                        println!("Could not send frame: {:?}", err);
+                    }
                 }
                 _ => (),
             }
@@ -81,7 +87,9 @@ impl ECU {
                 color: self.led_color,
                 cycle_time_ms: self.led_cycle_time_ms,
             };
-            cf.send_frame(status.into());
+            if let Err(err) = cf.send_frame(status.into()) {
+                println!("Could not send frame: {:?}", err);
+            }
         }
     }
 
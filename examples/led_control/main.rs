@@ -18,7 +18,9 @@ fn main() {
 
     loop {
         // running the j1939 stack does the actually heavy lifting including transport protocols and address management
-        stack.process();
+        if let Err(err) = stack.process() {
+            println!("Stack error: {}", err);
+        }
 
         // our ecu logic
         // the ecu provides values for different parameters
@@ -0,0 +1,38 @@
+use j1939::{
+    config::StackConfig,
+    name::{Functions, IndustryGroup, ManufacturerCodes, Name, VehicleSystem2Agriculture},
+    tokio::TokioStack,
+};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    // the stack is driven by a task reading and writing the socket
+    let (stack, task) = TokioStack::spawn("vcan0", StackConfig::default()).unwrap();
+    let (_handle, mut cf_frames) = stack.register_control_function(
+        0x85,
+        Name {
+            address_capable: false,
+            industry_group: IndustryGroup::AgriculturalAndForestry.into(),
+            vehicle_system_instance: 0,
+            vehicle_system: VehicleSystem2Agriculture::Tractor.into(),
+            function: Functions::NotAvailable255.into(),
+            function_instance: 0,
+            ecu_instance: 0,
+            manufacturer_coder: ManufacturerCodes::Reserved0.into(),
+            identity_number: 0x85,
+        },
+    );
+    let mut frames = stack.receiver();
+
+    loop {
+        tokio::select! {
+            Some(frame) = frames.recv() => println!("Stack: {:?}", frame),
+            Some(frame) = cf_frames.recv() => println!("Control function: {:?}", frame),
+            else => break,
+        }
+        if task.is_finished() {
+            println!("Stack error: {}", task.await.unwrap());
+            break;
+        }
+    }
+}
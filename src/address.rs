@@ -1,21 +1,33 @@
 use crate::frame::{Frame, Request, PGN_ADDRESSCLAIM, PGN_REQUEST};
 use crate::name::Name;
+use crate::time::{Duration, Instant};
+use crate::Error;
 use alloc::collections::BTreeMap;
 
 pub struct AddressMonitor {
     cf: BTreeMap<u8, Name>,
+    /// instant of the last global request for address claimed
+    requested: Option<Instant>,
 }
 
+/// Time to wait for the address claims of other control functions after a request for address claimed
+/// The responses of control functions which cannot claim an address are delayed by up to 153 ms
+pub(crate) const ADDRESS_REQUEST_TIMEOUT: Duration = Duration::millis(1500);
+
 impl AddressMonitor {
     pub fn new() -> Self {
         Self {
             cf: BTreeMap::new(),
+            requested: None,
         }
     }
-    pub fn handle_frame(&mut self, frame: &Frame) {
+    /// Returns an error if an address claim or request is malformed
+    pub fn handle_frame(&mut self, frame: &Frame, now: Instant) -> Result<(), Error> {
         if frame.header().pgn() == PGN_ADDRESSCLAIM {
             let sa = frame.header().source_address();
-            let name = Name::from(u64::from_le_bytes(frame.data().try_into().unwrap()));
+            let name = Name::from(u64::from_le_bytes(
+                frame.data().try_into().map_err(|_| Error::MalformedFrame)?,
+            ));
             let mut old_sa = None;
             for (isa, iname) in &self.cf {
                 if *iname == name {
@@ -29,16 +41,77 @@ impl AddressMonitor {
             self.cf.insert(sa, name);
         } else if frame.header().pgn() == PGN_REQUEST {
             // clear list of active ecus, because all active ecus must response the to addressclaim request
-            let req: Request = frame
-                .clone()
-                .try_into()
-                .expect("Could not serialize Request");
+            let req: Request = frame.clone().try_into()?;
             if *req.pgn() == PGN_ADDRESSCLAIM {
                 self.cf.clear();
+                if frame.header().destination_address() == Some(0xFF) {
+                    self.requested = Some(now);
+                }
             }
         }
+        Ok(())
     }
     pub fn control_function_list(&self) -> &BTreeMap<u8, Name> {
         &self.cf
     }
+    /// Returns true if all control functions had the time to answer a global request for address claimed
+    /// Before that, a control function missing in the list may still be online
+    pub fn list_complete(&self, now: Instant) -> bool {
+        self.requested
+            .is_some_and(|requested| requested + ADDRESS_REQUEST_TIMEOUT < now)
+    }
+    /// A peer to peer destination is offline, if it did not answer a completed address claim round
+    /// Otherwise an offline destination is reported by the timeout of the transport protocol
+    pub fn destination_offline(&self, destination_address: Option<u8>, now: Instant) -> bool {
+        match destination_address {
+            Some(da) if da != 0xFF => self.list_complete(now) && !self.cf.contains_key(&da),
+            _ => false,
+        }
+    }
+}
+
+/// Pseudo-random transmit delay (RTxD) of J1939-81, 0.6 ms times a random number 0 to 255
+/// The sequence is seeded by the NAME, so control functions with different NAMEs use different delays
+pub struct RandomDelay {
+    state: u64,
+}
+
+impl RandomDelay {
+    pub fn new(name: Name) -> Self {
+        Self { state: name.into() }
+    }
+    /// Returns the next delay in the range of 0 to 153 ms
+    pub fn next_delay(&mut self) -> Duration {
+        // splitmix64
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        let random = (z >> 56) as u32;
+        Duration::millis(u64::from(random * 6 / 10))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_delay() {
+        let name = Name::default();
+        let mut delay = RandomDelay::new(name);
+        let delays: alloc::vec::Vec<Duration> = (0..100).map(|_| delay.next_delay()).collect();
+        assert!(delays.iter().all(|d| *d <= Duration::millis(153)));
+        assert!(delays.iter().any(|d| *d != delays[0]));
+
+        // the sequence is deterministic per NAME
+        let mut same = RandomDelay::new(name);
+        assert!(delays.iter().all(|d| *d == same.next_delay()));
+        let mut other = RandomDelay::new(Name {
+            identity_number: 1,
+            ..name
+        });
+        assert!(delays.iter().any(|d| *d != other.next_delay()));
+    }
 }
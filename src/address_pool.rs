@@ -0,0 +1,107 @@
+use crate::name::Name;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Order in which the addresses of an [`AddressPool`] are tried
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SearchStrategy {
+    /// The free address following the current address, wrapping around at the end of the pool
+    #[default]
+    Next,
+    /// The lowest free address of the pool
+    Lowest,
+}
+
+/// Addresses claimed by a control function with a configurable address, if its address is taken
+/// The pool is used for the initial claim and after an address claim was lost
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressPool {
+    /// sorted addresses without duplicates
+    addresses: Vec<u8>,
+    strategy: SearchStrategy,
+}
+
+impl Default for AddressPool {
+    /// The self-configurable addresses 128 to 247 of J1939
+    fn default() -> Self {
+        Self::range(128, 247)
+    }
+}
+
+impl AddressPool {
+    /// Creates a pool of the given addresses, the null and global address are ignored
+    pub fn new(addresses: &[u8]) -> Self {
+        let mut addresses: Vec<u8> = addresses
+            .iter()
+            .copied()
+            .filter(|address| *address < 0xFE)
+            .collect();
+        addresses.sort_unstable();
+        addresses.dedup();
+        Self {
+            addresses,
+            strategy: SearchStrategy::Next,
+        }
+    }
+    /// Creates a pool of the inclusive address range
+    pub fn range(first: u8, last: u8) -> Self {
+        Self::new(&(first..=last).collect::<Vec<u8>>())
+    }
+    /// Sets the order in which the addresses are tried
+    pub fn strategy(mut self, strategy: SearchStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+    /// Returns the addresses of the pool
+    pub fn addresses(&self) -> &[u8] {
+        &self.addresses
+    }
+
+    /// Returns a free address of the pool other than the current address
+    /// `control_functions` is the list of claimed addresses and their NAME
+    pub(crate) fn find(&self, current: u8, control_functions: &BTreeMap<u8, Name>) -> Option<u8> {
+        let start = match self.strategy {
+            SearchStrategy::Next => self
+                .addresses
+                .partition_point(|address| *address <= current),
+            SearchStrategy::Lowest => 0,
+        };
+        self.addresses[start..]
+            .iter()
+            .chain(&self.addresses[..start])
+            .copied()
+            .find(|address| *address != current && !control_functions.contains_key(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_address() {
+        let mut list = BTreeMap::new();
+        list.insert(0x81, Name::default());
+        let pool = AddressPool::range(0x80, 0x82);
+        assert_eq!(pool.find(0x80, &list), Some(0x82));
+        // the search wraps around at the end of the pool
+        assert_eq!(pool.find(0x82, &list), Some(0x80));
+        // the current address may be outside of the pool
+        assert_eq!(pool.find(0x10, &list), Some(0x80));
+        assert_eq!(pool.find(0x90, &list), Some(0x80));
+        list.insert(0x80, Name::default());
+        assert_eq!(pool.find(0x82, &list), None);
+    }
+
+    #[test]
+    fn lowest_address() {
+        let mut list = BTreeMap::new();
+        list.insert(0x81, Name::default());
+        let pool =
+            AddressPool::new(&[0x84, 0x81, 0x80, 0xFE, 0x84]).strategy(SearchStrategy::Lowest);
+        assert_eq!(pool.addresses(), &[0x80, 0x81, 0x84]);
+        assert_eq!(pool.find(0x84, &list), Some(0x80));
+        assert_eq!(pool.find(0x80, &list), Some(0x84));
+        assert_eq!(AddressPool::default().addresses().len(), 120);
+    }
+}
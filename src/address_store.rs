@@ -0,0 +1,135 @@
+use crate::name::Name;
+use crate::Error;
+use alloc::collections::BTreeMap;
+
+/// Storage of the last claimed address per NAME
+/// The stack uses the stored address as preferred address of a registered control function
+/// and stores the address whenever a control function claims an address
+pub trait AddressStore {
+    /// Returns the stored address of the NAME
+    fn load(&self, name: Name) -> Option<u8>;
+    /// Stores the claimed address of the NAME
+    fn store(&mut self, name: Name, address: u8) -> Result<(), Error>;
+}
+
+/// Address store in memory, the addresses are lost on power-down
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryAddressStore {
+    addresses: BTreeMap<u64, u8>,
+}
+
+impl MemoryAddressStore {
+    /// Creates an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AddressStore for MemoryAddressStore {
+    fn load(&self, name: Name) -> Option<u8> {
+        self.addresses.get(&name.into()).copied()
+    }
+
+    fn store(&mut self, name: Name, address: u8) -> Result<(), Error> {
+        self.addresses.insert(name.into(), address);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+pub use self::file::FileAddressStore;
+
+#[cfg(feature = "std")]
+mod file {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Address store in a text file, each line contains a NAME and its address in hex, e.g. `A00C810000000000 86`
+    /// The file is created by the first stored address
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct FileAddressStore {
+        path: PathBuf,
+    }
+
+    impl FileAddressStore {
+        /// Creates a store in the file of the path
+        pub fn new(path: impl Into<PathBuf>) -> Self {
+            Self { path: path.into() }
+        }
+
+        /// A missing or unreadable file is an empty store, malformed lines are ignored
+        fn read(&self) -> BTreeMap<u64, u8> {
+            let content = std::fs::read_to_string(&self.path).unwrap_or_default();
+            content
+                .lines()
+                .filter_map(|line| {
+                    let (name, address) = line.trim().split_once(' ')?;
+                    Some((
+                        u64::from_str_radix(name, 16).ok()?,
+                        u8::from_str_radix(address.trim(), 16).ok()?,
+                    ))
+                })
+                .collect()
+        }
+    }
+
+    impl AddressStore for FileAddressStore {
+        fn load(&self, name: Name) -> Option<u8> {
+            self.read().get(&name.into()).copied()
+        }
+
+        fn store(&mut self, name: Name, address: u8) -> Result<(), Error> {
+            let mut addresses = self.read();
+            if addresses.insert(name.into(), address) == Some(address) {
+                return Ok(());
+            }
+            let content: String = addresses
+                .iter()
+                .map(|(name, address)| format!("{:016X} {:02X}\n", name, address))
+                .collect();
+            std::fs::write(&self.path, content).map_err(|_| Error::AddressStore)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_store() {
+        let name = Name::default();
+        let mut store = MemoryAddressStore::new();
+        assert_eq!(store.load(name), None);
+        store.store(name, 0x86).unwrap();
+        assert_eq!(store.load(name), Some(0x86));
+        assert_eq!(
+            store.load(Name {
+                identity_number: 1,
+                ..name
+            }),
+            None
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn file_store() {
+        let path = std::env::temp_dir().join(format!("j1939_address_store_{}", std::process::id()));
+        let name = Name::default();
+        let other = Name {
+            identity_number: 1,
+            ..name
+        };
+        let mut store = FileAddressStore::new(&path);
+        assert_eq!(store.load(name), None);
+        store.store(name, 0x86).unwrap();
+        store.store(other, 0x90).unwrap();
+        store.store(name, 0x87).unwrap();
+        // the addresses are read from the file
+        let store = FileAddressStore::new(&path);
+        assert_eq!(store.load(name), Some(0x87));
+        assert_eq!(store.load(other), Some(0x90));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
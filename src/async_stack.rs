@@ -0,0 +1,580 @@
+use crate::address_pool::AddressPool;
+use crate::config::StackConfig;
+use crate::driver::Driver;
+use crate::frame::Frame;
+use crate::name::Name;
+use crate::stack::{ControlFunctionHandle, Stack};
+use crate::time::{Instant, TimerDriver};
+use crate::transport::{TransferHandle, TransferState, TransportEvent};
+use crate::Error;
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::{Poll, Waker};
+
+/// Async can driver used by the [`AsyncStack`]
+pub trait AsyncDriver {
+    /// Frame type of the can driver
+    type Frame: embedded_can::Frame;
+
+    /// Waits till the frame is put into the transmit buffer
+    fn transmit(&mut self, frame: &Self::Frame) -> impl Future<Output = Result<(), Error>>;
+
+    /// Waits for a received frame
+    /// The future must be cancel safe, it is dropped if a timer of the stack expires first
+    fn receive(&mut self) -> impl Future<Output = Result<Self::Frame, Error>>;
+}
+
+/// Async time base of the [`AsyncStack`]
+pub trait AsyncTimer: TimerDriver {
+    /// Waits till the instant is reached
+    fn sleep_until(&self, deadline: Instant) -> impl Future<Output = ()>;
+}
+
+/// Buffers the frames between the [`Stack`] and the [`AsyncDriver`]
+/// Frames to send stay in the transmit queue of the stack till the async driver has transmitted them
+pub struct BufferDriver<CanFrame: embedded_can::Frame> {
+    rx: VecDeque<CanFrame>,
+    /// first frame of the transmit queue, offered to the async driver
+    tx: Option<CanFrame>,
+    /// a frame is transmitted by the async driver
+    transmitting: bool,
+}
+
+impl<CanFrame: embedded_can::Frame> BufferDriver<CanFrame> {
+    pub(crate) fn new() -> Self {
+        Self {
+            rx: VecDeque::new(),
+            tx: None,
+            transmitting: false,
+        }
+    }
+
+    /// Hands a frame received by the async driver to the stack
+    pub(crate) fn push_received(&mut self, frame: CanFrame) {
+        self.rx.push_back(frame);
+    }
+
+    /// Takes the next frame sent by the stack, no frame is offered till the transmit is finished
+    pub(crate) fn pop_transmit(&mut self) -> Option<CanFrame> {
+        let frame = self.tx.take();
+        self.transmitting = frame.is_some();
+        frame
+    }
+
+    /// The async driver has transmitted the frame or failed
+    pub(crate) fn finish_transmit(&mut self) {
+        self.transmitting = false;
+    }
+}
+
+impl<CanFrame: embedded_can::Frame> Driver for BufferDriver<CanFrame> {
+    type Frame = CanFrame;
+
+    /// The frame is only offered, it is removed from the transmit queue by `Stack::finish_transmit`
+    fn transmit(&mut self, frame: &Self::Frame) -> nb::Result<Option<Self::Frame>, Error> {
+        if !self.transmitting {
+            self.tx = Some(
+                CanFrame::new(frame.id(), frame.data())
+                    .ok_or(nb::Error::Other(Error::MalformedFrame))?,
+            );
+        }
+        Err(nb::Error::WouldBlock)
+    }
+
+    fn receive(&mut self) -> nb::Result<Self::Frame, Error> {
+        self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+/// Access of a runner to a stack shared with the tasks using it, e.g. by a `RefCell` or a `Mutex`
+pub(crate) trait SharedStack {
+    type Frame: embedded_can::Frame;
+    type Time: AsyncTimer + Clone;
+
+    /// Calls the function with the locked stack
+    fn with_stack<R>(
+        &self,
+        f: impl FnOnce(&mut Stack<BufferDriver<Self::Frame>, Self::Time>) -> R,
+    ) -> R;
+
+    /// Waits till the stack was changed by a task, e.g. a frame was sent
+    fn changed(&self) -> impl Future<Output = ()>;
+}
+
+/// Transmits all frames of the stack, waits for a received frame, a change of the stack or the next timer
+/// and processes the stack once
+/// Returns an error of the can driver, otherwise the result of processing the stack
+pub(crate) async fn process_once<
+    Shared: SharedStack,
+    CanDriver: AsyncDriver<Frame = Shared::Frame>,
+>(
+    shared: &Shared,
+    driver: &mut CanDriver,
+    time: &Shared::Time,
+) -> Result<Result<(), Error>, Error> {
+    while let Some(frame) = shared.with_stack(|stack| stack.driver_mut().pop_transmit()) {
+        let result = driver.transmit(&frame).await;
+        // a frame which could not be transmitted fails its transfer
+        shared.with_stack(|stack| {
+            stack.driver_mut().finish_transmit();
+            if let Some(frame) = Frame::from_can(&frame) {
+                stack.finish_transmit(&frame, result)
+            }
+        });
+        result?;
+    }
+
+    let deadline = shared.with_stack(|stack| stack.next_deadline());
+    let received = {
+        let mut receive = pin!(driver.receive());
+        let mut changed = pin!(shared.changed());
+        let mut sleep = pin!(async {
+            match deadline {
+                Some(deadline) => time.sleep_until(deadline).await,
+                None => core::future::pending().await,
+            }
+        });
+        poll_fn(|cx| {
+            if let Poll::Ready(frame) = receive.as_mut().poll(cx) {
+                return Poll::Ready(Some(frame));
+            }
+            if changed.as_mut().poll(cx).is_ready() || sleep.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(None);
+            }
+            Poll::Pending
+        })
+        .await
+    };
+    let received = received.transpose()?;
+    Ok(shared.with_stack(|stack| {
+        if let Some(frame) = received {
+            stack.driver_mut().push_received(frame);
+        }
+        stack.process()
+    }))
+}
+
+struct Shared<CanFrame: embedded_can::Frame, TimeDriver: AsyncTimer + Clone> {
+    stack: RefCell<Stack<BufferDriver<CanFrame>, TimeDriver>>,
+    /// tasks waiting for received frames, woken after each processing step
+    receivers: RefCell<Vec<Waker>>,
+    /// the runner waits for new work
+    runner: RefCell<Option<Waker>>,
+    notified: Cell<bool>,
+}
+
+impl<CanFrame: embedded_can::Frame, TimeDriver: AsyncTimer + Clone> Shared<CanFrame, TimeDriver> {
+    fn notify_runner(&self) {
+        self.notified.set(true);
+        if let Some(waker) = self.runner.borrow_mut().take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_receivers(&self) {
+        for waker in self.receivers.borrow_mut().drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn register_receiver(&self, waker: &Waker) {
+        let mut receivers = self.receivers.borrow_mut();
+        if !receivers.iter().any(|w| w.will_wake(waker)) {
+            receivers.push(waker.clone());
+        }
+    }
+}
+
+impl<CanFrame: embedded_can::Frame, TimeDriver: AsyncTimer + Clone> SharedStack
+    for Shared<CanFrame, TimeDriver>
+{
+    type Frame = CanFrame;
+    type Time = TimeDriver;
+
+    fn with_stack<R>(
+        &self,
+        f: impl FnOnce(&mut Stack<BufferDriver<CanFrame>, TimeDriver>) -> R,
+    ) -> R {
+        f(&mut self.stack.borrow_mut())
+    }
+
+    async fn changed(&self) {
+        poll_fn(|cx| {
+            if self.notified.take() {
+                return Poll::Ready(());
+            }
+            *self.runner.borrow_mut() = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// Async front end of a [`Stack`]
+/// Frames are received by awaiting [`AsyncStack::recv`] or [`AsyncStack::recv_cf`],
+/// while the [`StackRunner`] transmits and receives frames and awaits the timers of the transport protocols and the address management
+/// The stack is cheap to clone and shared by the tasks of a single threaded executor
+pub struct AsyncStack<CanFrame: embedded_can::Frame, TimeDriver: AsyncTimer + Clone> {
+    shared: Rc<Shared<CanFrame, TimeDriver>>,
+}
+
+impl<CanFrame: embedded_can::Frame, TimeDriver: AsyncTimer + Clone> Clone
+    for AsyncStack<CanFrame, TimeDriver>
+{
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<CanFrame: embedded_can::Frame, TimeDriver: AsyncTimer + Clone>
+    AsyncStack<CanFrame, TimeDriver>
+{
+    /// Creates a new stack and its runner, capturing the can and timer driver
+    pub fn new<CanDriver: AsyncDriver<Frame = CanFrame>>(
+        can: CanDriver,
+        time: TimeDriver,
+    ) -> (Self, StackRunner<CanDriver, TimeDriver>) {
+        Self::new_with_config(can, time, StackConfig::default())
+    }
+
+    /// Creates a new stack and its runner, configured by the given [`StackConfig`]
+    pub fn new_with_config<CanDriver: AsyncDriver<Frame = CanFrame>>(
+        can: CanDriver,
+        time: TimeDriver,
+        config: StackConfig,
+    ) -> (Self, StackRunner<CanDriver, TimeDriver>) {
+        let shared = Rc::new(Shared {
+            stack: RefCell::new(Stack::new_with_config(
+                BufferDriver::new(),
+                time.clone(),
+                config,
+            )),
+            receivers: RefCell::new(Vec::new()),
+            runner: RefCell::new(None),
+            notified: Cell::new(false),
+        });
+        let runner = StackRunner {
+            shared: shared.clone(),
+            driver: can,
+            time,
+        };
+        (Self { shared }, runner)
+    }
+
+    /// Gives access to the synchronous stack, e.g. to set the accepted addresses or poll transfers
+    /// The runner processes the stack afterwards
+    pub fn with_stack<R>(
+        &self,
+        f: impl FnOnce(&mut Stack<BufferDriver<CanFrame>, TimeDriver>) -> R,
+    ) -> R {
+        let result = f(&mut self.shared.stack.borrow_mut());
+        self.shared.notify_runner();
+        result
+    }
+
+    /// Creates a new [`ControlFunction`](crate::control_function::ControlFunction) with a preferred address and Name
+    pub fn register_control_function(
+        &self,
+        preferred_address: u8,
+        name: Name,
+    ) -> ControlFunctionHandle {
+        self.with_stack(|stack| stack.register_control_function(preferred_address, name))
+    }
+
+    /// Creates a new [`ControlFunction`](crate::control_function::ControlFunction) with a preferred address, Name and [`AddressPool`]
+    pub fn register_control_function_with_pool(
+        &self,
+        preferred_address: u8,
+        name: Name,
+        address_pool: AddressPool,
+    ) -> ControlFunctionHandle {
+        self.with_stack(|stack| {
+            stack.register_control_function_with_pool(preferred_address, name, address_pool)
+        })
+    }
+
+    /// Send a frame directly with the stack, see [`Stack::send_frame`]
+    pub fn send_frame(&self, frame: Frame) -> Result<TransferHandle, Error> {
+        self.with_stack(|stack| stack.send_frame(frame))
+    }
+
+    /// Send a frame with a control function, see [`ControlFunction::send_frame`](crate::control_function::ControlFunction::send_frame)
+    pub fn send_frame_cf(
+        &self,
+        handle: &ControlFunctionHandle,
+        frame: Frame,
+    ) -> Result<TransferHandle, Error> {
+        self.with_stack(|stack| stack.control_function(handle).send_frame(frame))
+    }
+
+    /// Returns the state of a transfer, see [`Stack::transfer_state`]
+    pub fn transfer_state(&self, handle: &TransferHandle) -> Option<TransferState> {
+        self.shared.stack.borrow().transfer_state(handle)
+    }
+
+    /// Waits for the next frame received by the stack, see [`Stack::get_frame`]
+    pub async fn recv(&self) -> Frame {
+        self.wait_for(|stack| stack.get_frame()).await
+    }
+
+    /// Waits for the next frame received by a control function
+    pub async fn recv_cf(&self, handle: &ControlFunctionHandle) -> Frame {
+        self.wait_for(|stack| stack.control_function(handle).get_frame())
+            .await
+    }
+
+    /// Waits for the next event of the transport protocols, see [`Stack::get_transport_event`]
+    pub async fn recv_transport_event(&self) -> TransportEvent {
+        self.wait_for(|stack| stack.get_transport_event()).await
+    }
+
+    /// Waits till the control function has claimed an address
+    pub async fn wait_online(&self, handle: &ControlFunctionHandle) -> u8 {
+        self.wait_for(|stack| stack.control_function(handle).is_online())
+            .await
+    }
+
+    async fn wait_for<T>(
+        &self,
+        mut f: impl FnMut(&mut Stack<BufferDriver<CanFrame>, TimeDriver>) -> Option<T>,
+    ) -> T {
+        poll_fn(|cx| match f(&mut self.shared.stack.borrow_mut()) {
+            Some(value) => Poll::Ready(value),
+            None => {
+                self.shared.register_receiver(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// Drives an [`AsyncStack`], created together with the stack
+/// [`StackRunner::run`] must be polled by a task of the same executor as the tasks using the stack
+pub struct StackRunner<CanDriver: AsyncDriver, TimeDriver: AsyncTimer + Clone> {
+    shared: Rc<Shared<CanDriver::Frame, TimeDriver>>,
+    driver: CanDriver,
+    time: TimeDriver,
+}
+
+impl<CanDriver: AsyncDriver, TimeDriver: AsyncTimer + Clone> StackRunner<CanDriver, TimeDriver> {
+    /// Runs the stack till an error occurs, calling `run` again continues with the next frame
+    /// Waits for received frames, frames to send or the next timer of the stack instead of polling
+    pub async fn run(&mut self) -> Result<(), Error> {
+        loop {
+            self.process().await?;
+        }
+    }
+
+    /// Transmits all frames of the stack, waits for a received frame, new frames to send or the next timer
+    /// and processes the stack once
+    pub async fn process(&mut self) -> Result<(), Error> {
+        let result = process_once(&*self.shared, &mut self.driver, &self.time).await;
+        self.shared.wake_receivers();
+        result.and_then(|processed| processed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{Header, PGN};
+    use crate::test_utils::can_driver::TestDriver;
+    use crate::test_utils::frame::TestFrame;
+    use crate::test_utils::test_time::TestTimer;
+    use crate::transport::TransferError;
+    use core::task::Context;
+
+    struct TestAsyncDriver(TestDriver);
+
+    impl AsyncDriver for TestAsyncDriver {
+        type Frame = TestFrame;
+
+        async fn transmit(&mut self, frame: &TestFrame) -> Result<(), Error> {
+            embedded_can::blocking::Can::transmit(&mut self.0, frame).map_err(Error::driver)
+        }
+
+        async fn receive(&mut self) -> Result<TestFrame, Error> {
+            poll_fn(
+                |_| match embedded_can::blocking::Can::receive(&mut self.0) {
+                    Ok(frame) => Poll::Ready(Ok(frame)),
+                    Err(_) => Poll::Pending,
+                },
+            )
+            .await
+        }
+    }
+
+    impl AsyncTimer for TestTimer {
+        async fn sleep_until(&self, deadline: Instant) {
+            poll_fn(|_| {
+                if self.now() >= deadline {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            })
+            .await
+        }
+    }
+
+    fn poll<F: Future>(future: core::pin::Pin<&mut F>) -> Poll<F::Output> {
+        future.poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test]
+    fn address_claim() {
+        let mut timer = TestTimer::new();
+        let mut driver = TestDriver::new();
+        let (stack, mut runner) = AsyncStack::new(TestAsyncDriver(driver.clone()), timer.clone());
+        let handle = stack.register_control_function(
+            0x85,
+            Name {
+                address_capable: false,
+                ..Name::default()
+            },
+        );
+        let mut run = pin!(runner.run());
+        let mut online = pin!(stack.wait_online(&handle));
+        assert!(poll(run.as_mut()).is_pending());
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 32]))
+        );
+        // the stack waits for the veto time
+        assert!(poll(run.as_mut()).is_pending());
+        assert!(poll(online.as_mut()).is_pending());
+        timer.set_time(300);
+        assert!(poll(run.as_mut()).is_pending());
+        assert_eq!(poll(online.as_mut()), Poll::Ready(0x85));
+        assert_eq!(driver.get_can_frame(), None);
+    }
+
+    #[test]
+    fn receive_and_send() {
+        let timer = TestTimer::new();
+        let mut driver = TestDriver::new();
+        let (stack, mut runner) = AsyncStack::new(TestAsyncDriver(driver.clone()), timer.clone());
+        let mut run = pin!(runner.run());
+        let mut recv = pin!(stack.recv());
+        assert!(poll(run.as_mut()).is_pending());
+        assert!(poll(recv.as_mut()).is_pending());
+        driver.push_can_frame(TestFrame::new2(0x00FEB221, &[1, 2, 3, 4, 5, 6, 7, 8]));
+        assert!(poll(run.as_mut()).is_pending());
+        assert_eq!(
+            poll(recv.as_mut()),
+            Poll::Ready(Frame::new(
+                Header::new(PGN::new(0xFEB2), 0, 0x21, None),
+                &[1, 2, 3, 4, 5, 6, 7, 8]
+            ))
+        );
+        let handle = stack
+            .send_frame(Frame::new(
+                Header::new(PGN::new(0xFEB3), 6, 0x22, None),
+                &[8, 7, 6, 5, 4, 3, 2, 1],
+            ))
+            .unwrap();
+        assert!(poll(run.as_mut()).is_pending());
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x18FEB322, &[8, 7, 6, 5, 4, 3, 2, 1]))
+        );
+        assert_eq!(
+            stack.transfer_state(&handle),
+            Some(TransferState::Completed)
+        );
+    }
+
+    #[test]
+    fn broadcast_timer() {
+        let mut timer = TestTimer::new();
+        let mut driver = TestDriver::new();
+        let (stack, mut runner) = AsyncStack::new(TestAsyncDriver(driver.clone()), timer.clone());
+        let mut run = pin!(runner.run());
+        stack
+            .send_frame(Frame::new(
+                Header::new(PGN::new(0xFEB0), 0, 0x21, None),
+                &[1, 2, 3, 4, 5, 6, 7, 1, 2],
+            ))
+            .unwrap();
+        assert!(poll(run.as_mut()).is_pending());
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(
+                0x1CECFF21,
+                &[32, 9, 0, 2, 255, 0xB0, 0xFE, 0]
+            ))
+        );
+        // the next packet waits for the packet gap
+        assert!(poll(run.as_mut()).is_pending());
+        assert_eq!(driver.get_can_frame(), None);
+        timer.set_time(50);
+        assert!(poll(run.as_mut()).is_pending());
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x1CEBFF21, &[1, 1, 2, 3, 4, 5, 6, 7]))
+        );
+    }
+    #[test]
+    fn transmit_priority() {
+        let timer = TestTimer::new();
+        let mut driver = TestDriver::new();
+        let (stack, mut runner) = AsyncStack::new(TestAsyncDriver(driver.clone()), timer.clone());
+        let mut run = pin!(runner.run());
+        let low = stack
+            .send_frame(Frame::new(
+                Header::new(PGN::new(0xFEB3), 6, 0x22, None),
+                &[1],
+            ))
+            .unwrap();
+        stack
+            .send_frame(Frame::new(
+                Header::new(PGN::new(0xFEB3), 3, 0x22, None),
+                &[2],
+            ))
+            .unwrap();
+        // the frames wait in the transmit queue till the async driver sends them
+        assert_eq!(stack.transfer_state(&low), Some(TransferState::Queued));
+        assert!(poll(run.as_mut()).is_pending());
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x0CFEB322, &[2]))
+        );
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x18FEB322, &[1]))
+        );
+        assert_eq!(stack.transfer_state(&low), Some(TransferState::Completed));
+    }
+
+    #[test]
+    fn transmit_error() {
+        let timer = TestTimer::new();
+        let mut driver = TestDriver::new();
+        let (stack, mut runner) = AsyncStack::new(TestAsyncDriver(driver.clone()), timer.clone());
+        driver.set_transmit_error(true);
+        let handle = stack
+            .send_frame(Frame::new(
+                Header::new(PGN::new(0xFEB3), 6, 0x22, None),
+                &[1],
+            ))
+            .unwrap();
+        let error = Error::Driver(embedded_can::ErrorKind::Other);
+        assert_eq!(poll(pin!(runner.process())), Poll::Ready(Err(error)));
+        assert_eq!(
+            stack.transfer_state(&handle),
+            Some(TransferState::Failed(TransferError::Send(error)))
+        );
+        // the failed frame is not sent again
+        driver.set_transmit_error(false);
+        assert_eq!(poll(pin!(runner.process())), Poll::Ready(Ok(())));
+        assert!(poll(pin!(runner.process())).is_pending());
+        assert_eq!(driver.get_can_frame(), None);
+    }
+}
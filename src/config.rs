@@ -0,0 +1,120 @@
+use crate::time::Duration;
+
+/// Minimum time between two packets of a broadcast transfer defined by J1939-21
+const BAM_PACKET_GAP_MIN: Duration = Duration::millis(50);
+/// Maximum time between two packets of a broadcast transfer defined by J1939-21
+const BAM_PACKET_GAP_MAX: Duration = Duration::millis(200);
+
+/// Behaviour of the transmit queue if a frame is added to the full queue
+/// Control and data frames of transport sessions are never dropped, they use a reserved capacity of the queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The new frame is rejected with [`Error::QueueFull`](crate::Error::QueueFull)
+    Reject,
+    /// The oldest queued frame is dropped
+    DropOldest,
+    /// The queued frame with the lowest priority is dropped, the new frame is rejected if it has the lowest priority
+    DropLowestPriority,
+}
+
+/// Configuration of a [`Stack`](crate::stack::Stack)
+/// Created with `StackConfig::default()` and adjusted by the builder functions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackConfig {
+    pub(crate) tp_max_packets_per_cts: u8,
+    pub(crate) tp_receive_window: u8,
+    pub(crate) etp_receive_window: u8,
+    pub(crate) bam_packet_gap: Duration,
+    pub(crate) tx_queue_capacity: usize,
+    pub(crate) tx_queue_overflow: OverflowPolicy,
+    pub(crate) rx_queue_capacity: usize,
+    pub(crate) event_queue_capacity: usize,
+    pub(crate) cf_rx_queue_capacity: usize,
+    pub(crate) cf_tx_queue_capacity: usize,
+    pub(crate) cf_address_event_queue_capacity: usize,
+}
+
+impl Default for StackConfig {
+    fn default() -> Self {
+        Self {
+            tp_max_packets_per_cts: 1,
+            tp_receive_window: 0xFF,
+            etp_receive_window: 16,
+            bam_packet_gap: BAM_PACKET_GAP_MIN,
+            tx_queue_capacity: 32,
+            tx_queue_overflow: OverflowPolicy::Reject,
+            rx_queue_capacity: 20,
+            event_queue_capacity: 20,
+            cf_rx_queue_capacity: 20,
+            cf_tx_queue_capacity: 20,
+            cf_address_event_queue_capacity: 8,
+        }
+    }
+}
+
+impl StackConfig {
+    /// Maximum number of packets a receiver may request with a single CTS from this stack
+    /// The value is sent in the RTS of outgoing transport protocol transfers, 0xFF means no limit
+    pub fn tp_max_packets_per_cts(mut self, packets: u8) -> Self {
+        self.tp_max_packets_per_cts = packets.max(1);
+        self
+    }
+    /// Maximum number of packets requested by this stack with a single CTS
+    /// The window is further limited by the maximum announced in the RTS of the sender
+    pub fn tp_receive_window(mut self, packets: u8) -> Self {
+        self.tp_receive_window = packets.max(1);
+        self
+    }
+    /// Maximum number of packets requested by this stack with a single extended transport protocol CTS
+    pub fn etp_receive_window(mut self, packets: u8) -> Self {
+        self.etp_receive_window = packets.max(1);
+        self
+    }
+    /// Time between two packets of an outgoing broadcast transfer (BAM)
+    /// The value is limited to the range of 50ms to 200ms required by J1939-21
+    pub fn bam_packet_gap(mut self, gap: Duration) -> Self {
+        self.bam_packet_gap = gap.max(BAM_PACKET_GAP_MIN).min(BAM_PACKET_GAP_MAX);
+        self
+    }
+    /// Maximum number of frames waiting in the transmit queue for the can driver
+    /// Transport sessions may queue a few control frames beyond the capacity
+    pub fn tx_queue_capacity(mut self, capacity: usize) -> Self {
+        self.tx_queue_capacity = capacity.max(1);
+        self
+    }
+    /// Behaviour if a frame is added to the full transmit queue
+    pub fn tx_queue_overflow(mut self, policy: OverflowPolicy) -> Self {
+        self.tx_queue_overflow = policy;
+        self
+    }
+    /// Maximum number of received frames waiting in the stack for `get_frame()`
+    /// The oldest frame is dropped and counted as overflow if the queue is full
+    pub fn rx_queue_capacity(mut self, capacity: usize) -> Self {
+        self.rx_queue_capacity = capacity.max(1);
+        self
+    }
+    /// Maximum number of transport events waiting for `get_transport_event()`
+    /// The oldest event is dropped and counted as overflow if the queue is full
+    pub fn event_queue_capacity(mut self, capacity: usize) -> Self {
+        self.event_queue_capacity = capacity.max(1);
+        self
+    }
+    /// Maximum number of received frames waiting in each control function
+    /// The oldest frame is dropped and counted as overflow if the queue is full
+    pub fn cf_rx_queue_capacity(mut self, capacity: usize) -> Self {
+        self.cf_rx_queue_capacity = capacity.max(1);
+        self
+    }
+    /// Maximum number of frames waiting in each control function for `process()`
+    /// Sending a frame with the full queue fails with [`Error::QueueFull`](crate::Error::QueueFull)
+    pub fn cf_tx_queue_capacity(mut self, capacity: usize) -> Self {
+        self.cf_tx_queue_capacity = capacity.max(1);
+        self
+    }
+    /// Maximum number of address events waiting in each control function for `get_address_event()`
+    /// The oldest event is dropped and counted as overflow if the queue is full
+    pub fn cf_address_event_queue_capacity(mut self, capacity: usize) -> Self {
+        self.cf_address_event_queue_capacity = capacity.max(1);
+        self
+    }
+}
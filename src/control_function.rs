@@ -1,9 +1,37 @@
-use crate::address::AddressMonitor;
-use crate::frame::{Frame, Header, Request, PGN_ADDRESSCLAIM, PGN_REQUEST};
+use crate::address::{AddressMonitor, RandomDelay, ADDRESS_REQUEST_TIMEOUT};
+use crate::address_pool::AddressPool;
+use crate::config::StackConfig;
+use crate::filter::FilterRule;
+use crate::frame::{
+    AddressCommand, Frame, Header, Request, PGN, PGN_ADDRESSCLAIM, PGN_ADDRESSCOMMAND, PGN_REQUEST,
+};
+use crate::handler::{Handler, Responder};
 use crate::name::Name;
 use crate::time::{Duration, Instant};
+use crate::transport::TransferHandle;
+use crate::Error;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use crossbeam_queue::ArrayQueue;
 
+/// Changes of the address of a control function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressEvent {
+    /// The address was claimed, the control function is online
+    Claimed(u8),
+    /// The address was lost to a control function with a higher priority, the control function is offline
+    Lost(u8),
+    /// An other address was claimed after the address was lost or commanded, the control function is online again
+    Moved {
+        /// previously claimed address
+        from: u8,
+        /// claimed address
+        to: u8,
+    },
+    /// No address could be claimed, the control function stays offline
+    CannotClaim,
+}
+
 #[derive(Debug, PartialEq)]
 pub(crate) enum AddressState {
     Requested(Instant),
@@ -13,28 +41,99 @@ pub(crate) enum AddressState {
     CannotClaim,
 }
 
+/// Decides if a control function accepts a commanded address for its NAME
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressCommandPolicy {
+    /// Commanded addresses are ignored
+    #[default]
+    Reject,
+    /// Commanded addresses of all senders are accepted
+    Accept,
+    /// Commanded addresses are accepted if the command matches the rule, e.g. the NAME of a service tool
+    AcceptMatching(FilterRule),
+}
+
+/// Frames handled by a [`Handler`]
+#[derive(Debug, PartialEq)]
+enum HandledPgn {
+    Frame(PGN),
+    /// requests of the PGN
+    Request(PGN),
+}
+
 /// `ControlFunction` is a entity with an own address on a J1939 bus
 /// Each `ControlFunction` participate in the J1939 address management
 /// Send and receive frames with a address
 pub struct ControlFunction<TimeDriver: crate::time::TimerDriver> {
     name: Name,
-    pub(crate) send_queue: ArrayQueue<Frame>,
+    /// index of the control function in the stack, used to create unique transfer handles
+    index: usize,
+    send_queue: ArrayQueue<(Frame, Option<TransferHandle>)>,
+    /// id of the next transfer handle
+    next_transfer_id: u32,
+    /// transfers with a smaller id are moved to the stack
+    dequeued_transfer_id: u32,
     receive_queue: ArrayQueue<Frame>,
+    /// number of received frames dropped by the full receive queue
+    receive_overflows: u32,
+    /// number of frames dropped from the full send queue by the address management
+    send_overflows: u32,
+    /// transfers dropped from the full send queue, their state is updated by the stack
+    dropped_transfers: Vec<TransferHandle>,
+    /// frames matching one of the rules are received, all frames are received without rules
+    filter: Vec<FilterRule>,
+    handlers: Vec<(HandledPgn, Handler)>,
     address_state: AddressState,
     address: u8,
     address_configurable: bool,
+    /// addresses claimed if the address is taken
+    address_pool: AddressPool,
+    address_command_policy: AddressCommandPolicy,
+    address_events: ArrayQueue<AddressEvent>,
+    /// number of address events dropped by the full event queue
+    address_event_overflows: u32,
+    /// address of the last successful claim
+    claimed_address: Option<u8>,
+    /// the claimed address was not yet stored in the address store of the stack
+    claim_unstored: bool,
+    random_delay: RandomDelay,
+    /// a cannot claim address frame is sent at this instant, delayed by a RTxD
+    cannot_claim: Option<Instant>,
     time: TimeDriver,
 }
 
 impl<TimeDriver: crate::time::TimerDriver> ControlFunction<TimeDriver> {
-    pub(crate) fn new(name: Name, preferred_address: u8, time: TimeDriver) -> Self {
+    pub(crate) fn new(
+        name: Name,
+        preferred_address: u8,
+        index: usize,
+        time: TimeDriver,
+        config: &StackConfig,
+        address_pool: AddressPool,
+    ) -> Self {
         Self {
             name,
-            send_queue: ArrayQueue::new(20),
-            receive_queue: ArrayQueue::new(20),
+            index,
+            send_queue: ArrayQueue::new(config.cf_tx_queue_capacity),
+            next_transfer_id: 0,
+            dequeued_transfer_id: 0,
+            receive_queue: ArrayQueue::new(config.cf_rx_queue_capacity),
+            receive_overflows: 0,
+            send_overflows: 0,
+            dropped_transfers: Vec::new(),
+            filter: Vec::new(),
+            handlers: Vec::new(),
             address_state: AddressState::Preferred,
             address: preferred_address,
             address_configurable: name.address_capable,
+            address_pool,
+            address_command_policy: AddressCommandPolicy::Reject,
+            address_events: ArrayQueue::new(config.cf_address_event_queue_capacity),
+            address_event_overflows: 0,
+            claimed_address: None,
+            claim_unstored: false,
+            random_delay: RandomDelay::new(name),
+            cannot_claim: None,
             time,
         }
     }
@@ -48,39 +147,152 @@ impl<TimeDriver: crate::time::TimerDriver> ControlFunction<TimeDriver> {
     }
     /// Send a frame using this control function
     /// The source address in the frame is overwritten by the address of the local bus
-    /// Returns false if the `ControlFunction` has not a valid address
+    /// Returns an error if the `ControlFunction` has not a valid address or the send queue is full
     /// The frame is send as soon as stack.process() is called.
-    pub fn send_frame(&mut self, mut frame: Frame) -> bool {
-        if self.address_state == AddressState::AddressClaimed {
-            frame.update_source_address(self.address);
-            self.send_queue.force_push(frame);
-            true
-        } else {
-            false
+    /// The returned [`TransferHandle`] is used to poll or cancel the transfer with the stack
+    pub fn send_frame(&mut self, mut frame: Frame) -> Result<TransferHandle, Error> {
+        if self.address_state != AddressState::AddressClaimed {
+            return Err(Error::NoAddress);
         }
+        frame.update_source_address(self.address);
+        let handle = TransferHandle::new(Some(self.index), self.next_transfer_id);
+        self.send_queue
+            .push((frame, Some(handle)))
+            .map_err(|_| Error::QueueFull)?;
+        self.next_transfer_id = self.next_transfer_id.wrapping_add(1);
+        Ok(handle)
     }
     /// Returns the last received frame if any
     pub fn get_frame(&mut self) -> Option<Frame> {
         self.receive_queue.pop()
     }
+    /// Returns the NAME of the control function
+    pub fn name(&self) -> Name {
+        self.name
+    }
+    /// Returns the next change of the address, e.g. to announce the control function again after it moved
+    pub fn get_address_event(&mut self) -> Option<AddressEvent> {
+        self.address_events.pop()
+    }
+    /// Returns the number of received frames dropped because the receive queue was full
+    pub fn receive_overflows(&self) -> u32 {
+        self.receive_overflows
+    }
+    /// Returns the number of address events dropped because the event queue was full
+    pub fn address_event_overflows(&self) -> u32 {
+        self.address_event_overflows
+    }
+    /// Returns the number of queued frames dropped to send an address claim, because the send queue was full
+    /// The transfers of dropped frames fail with [`TransferError::Dropped`](crate::transport::TransferError::Dropped)
+    pub fn send_overflows(&self) -> u32 {
+        self.send_overflows
+    }
+    /// Adds a rule to the receive filter, only frames matching one of the rules are received
+    /// Without rules all broadcast frames and frames addressed to the control function are received
+    /// Address management frames are handled by the control function regardless of the filter
+    pub fn add_filter(&mut self, rule: FilterRule) {
+        self.filter.push(rule);
+    }
+    /// Removes all rules of the receive filter, all frames are received again
+    pub fn clear_filter(&mut self) {
+        self.filter.clear();
+    }
+    /// Registers a handler for received frames of the PGN, the handler is called by `stack.process()`
+    /// Handled frames are not put into the receive queue, the responses are sent by this control function
+    pub fn add_handler(
+        &mut self,
+        pgn: PGN,
+        handler: impl FnMut(&Frame, &mut Responder) + Send + 'static,
+    ) {
+        self.handlers
+            .push((HandledPgn::Frame(pgn), Box::new(handler)));
+    }
+    /// Registers a handler for requests of the PGN, e.g. to reply with the requested PGN
+    /// Request handlers are preferred over a handler of the request PGN
+    pub fn add_request_handler(
+        &mut self,
+        pgn: PGN,
+        handler: impl FnMut(&Frame, &mut Responder) + Send + 'static,
+    ) {
+        self.handlers
+            .push((HandledPgn::Request(pgn), Box::new(handler)));
+    }
+    /// Removes the handlers of frames and requests of the PGN
+    pub fn remove_handlers(&mut self, pgn: PGN) {
+        self.handlers.retain(|(handled, _)| {
+            *handled != HandledPgn::Frame(pgn) && *handled != HandledPgn::Request(pgn)
+        });
+    }
+
+    /// Sets the policy for commanded addresses, by default commanded addresses are rejected
+    /// An accepted command claims the new address, even if the address is not configurable
+    pub fn set_address_command_policy(&mut self, policy: AddressCommandPolicy) {
+        self.address_command_policy = policy;
+    }
 
     // ------------------------------ private ------------------------------------------------------
+    /// Returns the next frame to send
+    pub(crate) fn pop_send_queue(&mut self) -> Option<(Frame, Option<TransferHandle>)> {
+        let (frame, handle) = self.send_queue.pop()?;
+        if let Some(handle) = handle {
+            self.dequeued_transfer_id = handle.id().wrapping_add(1);
+        }
+        Some((frame, handle))
+    }
+
+    /// Returns the transfers dropped from the send queue since the last call
+    pub(crate) fn take_dropped_transfers(&mut self) -> Vec<TransferHandle> {
+        core::mem::take(&mut self.dropped_transfers)
+    }
+
+    /// Returns the claimed address till it is marked as stored, to update the address store
+    pub(crate) fn unstored_claim(&self) -> Option<u8> {
+        self.claimed_address.filter(|_| self.claim_unstored)
+    }
+
+    /// The claimed address was stored by the address store
+    pub(crate) fn mark_claim_stored(&mut self) {
+        self.claim_unstored = false;
+    }
+
+    /// Returns true if the frame of the transfer is still in the send queue
+    pub(crate) fn is_queued(&self, handle: &TransferHandle) -> bool {
+        handle.control_function() == Some(self.index)
+            && (self.dequeued_transfer_id..self.next_transfer_id).contains(&handle.id())
+    }
+
     #[cfg(test)]
     pub(crate) fn address_state(&self) -> &AddressState {
         &self.address_state
     }
 
-    pub(crate) fn handle_new_frame(&mut self, frame: &Frame) {
+    /// Returns an error if the frame is malformed or another control function claims an address with the same NAME
+    pub(crate) fn handle_new_frame(
+        &mut self,
+        frame: &Frame,
+        address_monitor: &AddressMonitor,
+    ) -> Result<(), Error> {
+        let addressed = |da: u8| {
+            da == 0xFF || (self.address_state == AddressState::AddressClaimed && self.address == da)
+        };
+        // commanded addresses are broadcast, or sent to the control function by a transport session
+        if frame.header().pgn() == PGN_ADDRESSCOMMAND
+            && frame.header().destination_address().is_none_or(addressed)
+        {
+            let command = AddressCommand::try_from(frame.clone())?;
+            if *command.name() == self.name {
+                self.handle_addresscommand(frame, &command, address_monitor);
+                return Ok(());
+            }
+            return self.receive(frame, address_monitor);
+        }
         // check if the message targets this cf
         if let Some(da) = frame.header().destination_address() {
-            if da == 0xFF
-                || (self.address_state == AddressState::AddressClaimed && self.address == da)
-            {
+            if addressed(da) {
                 if frame.header().pgn() == PGN_ADDRESSCLAIM {
-                    self.handle_addressclaim(frame);
+                    self.handle_addressclaim(frame, address_monitor)?;
                 } else if frame.header().pgn() == PGN_REQUEST
-                    && TryInto::<Request>::try_into(frame.clone()).unwrap().pgn()
-                        == &PGN_ADDRESSCLAIM
+                    && TryInto::<Request>::try_into(frame.clone())?.pgn() == &PGN_ADDRESSCLAIM
                 {
                     match self.address_state {
                         AddressState::AddressClaimed | AddressState::WaitForVeto(_) => {
@@ -90,12 +302,94 @@ impl<TimeDriver: crate::time::TimerDriver> ControlFunction<TimeDriver> {
                         _ => (),
                     }
                 } else {
-                    self.receive_queue.force_push(frame.clone());
+                    return self.receive(frame, address_monitor);
                 }
             }
         } else {
             // broadcast
-            self.receive_queue.force_push(frame.clone());
+            return self.receive(frame, address_monitor);
+        }
+        Ok(())
+    }
+
+    /// claims the commanded address if the policy accepts the command
+    fn handle_addresscommand(
+        &mut self,
+        frame: &Frame,
+        command: &AddressCommand,
+        address_monitor: &AddressMonitor,
+    ) {
+        let accepted = match self.address_command_policy {
+            AddressCommandPolicy::Reject => false,
+            AddressCommandPolicy::Accept => true,
+            AddressCommandPolicy::AcceptMatching(rule) => {
+                rule.matches(frame, address_monitor.control_function_list())
+            }
+        };
+        // 254 and 255 are no valid source addresses
+        if accepted && command.address() < 0xFE {
+            self.address = command.address();
+            self.cannot_claim = None;
+            self.send_addressclaim();
+            self.address_state = AddressState::WaitForVeto(self.time.now());
+        }
+    }
+
+    /// Returns an error if a response of a handler could not be sent
+    fn receive(&mut self, frame: &Frame, address_monitor: &AddressMonitor) -> Result<(), Error> {
+        if let Some(responses) = self.call_handler(frame) {
+            return responses
+                .into_iter()
+                .try_for_each(|response| self.send_frame(response).map(|_| ()));
+        }
+        let accepted = self.filter.is_empty()
+            || self
+                .filter
+                .iter()
+                .any(|rule| rule.matches(frame, address_monitor.control_function_list()));
+        if accepted && self.receive_queue.force_push(frame.clone()).is_some() {
+            self.receive_overflows = self.receive_overflows.saturating_add(1);
+        }
+        Ok(())
+    }
+
+    /// Returns the responses if a handler is registered for the frame
+    fn call_handler(&mut self, frame: &Frame) -> Option<Vec<Frame>> {
+        let request = if frame.header().pgn() == PGN_REQUEST {
+            Request::try_from(frame.clone())
+                .ok()
+                .map(|request| HandledPgn::Request(*request.pgn()))
+        } else {
+            None
+        };
+        let handled = HandledPgn::Frame(frame.header().pgn());
+        let index = self
+            .handlers
+            .iter()
+            .position(|(pgn, _)| Some(pgn) == request.as_ref())
+            .or_else(|| self.handlers.iter().position(|(pgn, _)| *pgn == handled))?;
+        let handler = &mut self.handlers[index].1;
+        let mut responder = Responder::new(frame.header().source_address());
+        handler(frame, &mut responder);
+        Some(responder.into_responses())
+    }
+
+    /// Returns the next instant the address management or the send queue has to be processed
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        if !self.send_queue.is_empty() {
+            return Some(Instant::from_ticks(0));
+        }
+        match self.address_state {
+            AddressState::Preferred => Some(Instant::from_ticks(0)),
+            // the states are left after the duration has elapsed
+            AddressState::Requested(requested) => {
+                Some(requested + ADDRESS_REQUEST_TIMEOUT + Duration::millis(1))
+            }
+            AddressState::WaitForVeto(requested) => {
+                Some(requested + Duration::millis(250) + Duration::millis(1))
+            }
+            AddressState::CannotClaim => self.cannot_claim,
+            AddressState::AddressClaimed => None,
         }
     }
 
@@ -105,8 +399,7 @@ impl<TimeDriver: crate::time::TimerDriver> ControlFunction<TimeDriver> {
             AddressState::Preferred => {
                 if self.address_configurable {
                     // we have a configurable address, send address request and wait for responses
-                    self.send_queue
-                        .force_push(Request::new(PGN_ADDRESSCLAIM, 0xFE, 0xFF).into());
+                    self.push_address_frame(Request::new(PGN_ADDRESSCLAIM, 0xFE, 0xFF).into());
                     self.address_state = AddressState::Requested(self.time.now());
                 } else {
                     // we have a fixed address, therefore send addressclaim asap
@@ -114,51 +407,79 @@ impl<TimeDriver: crate::time::TimerDriver> ControlFunction<TimeDriver> {
                     self.address_state = AddressState::WaitForVeto(self.time.now());
                 }
             }
-            AddressState::Requested(requested) => {
-                // ToDo: change 1500 to RTxD?
-                if Duration::millis(1500) < (self.time.now() - requested) {
-                    // check if our preferred address is in the list
-                    if address_monitor
-                        .control_function_list()
-                        .contains_key(&self.address)
-                    {
-                        // select an other address
-                        let mut next_address = 127;
-                        while address_monitor
-                            .control_function_list()
-                            .contains_key(&next_address)
-                            || next_address >= 147
-                        {
-                            next_address += 1;
-                        }
-                        if next_address >= 147 {
-                            // we did not found a free address
-                            self.address_state = AddressState::CannotClaim;
-                            self.send_cannotclaim();
-                        } else {
-                            // use a free address in range 127..247
-                            self.address = next_address;
-                            self.send_addressclaim();
-                            self.address_state = AddressState::WaitForVeto(self.time.now());
-                        }
-                    } else {
-                        // Use our preferred address
-                        self.send_addressclaim();
-                        self.address_state = AddressState::WaitForVeto(self.time.now());
-                    }
+            AddressState::Requested(requested)
+                if ADDRESS_REQUEST_TIMEOUT < (self.time.now() - requested) =>
+            {
+                // check if our preferred address is in the list
+                if address_monitor
+                    .control_function_list()
+                    .contains_key(&self.address)
+                {
+                    // select an other address
+                    self.claim_pool_address(address_monitor);
+                } else {
+                    // Use our preferred address
+                    self.send_addressclaim();
+                    self.address_state = AddressState::WaitForVeto(self.time.now());
                 }
             }
-            AddressState::WaitForVeto(requested) => {
-                if Duration::millis(250) < (self.time.now() - requested) {
-                    self.address_state = AddressState::AddressClaimed;
-                }
+            AddressState::WaitForVeto(requested)
+                if Duration::millis(250) < (self.time.now() - requested) =>
+            {
+                self.address_state = AddressState::AddressClaimed;
+                let event = match self.claimed_address {
+                    Some(from) if from != self.address => AddressEvent::Moved {
+                        from,
+                        to: self.address,
+                    },
+                    _ => AddressEvent::Claimed(self.address),
+                };
+                self.claimed_address = Some(self.address);
+                self.claim_unstored = true;
+                self.push_address_event(event);
             }
             _ => {} /* Nothing to do */
         }
+        if let Some(instant) = self.cannot_claim {
+            if self.time.now() >= instant {
+                self.cannot_claim = None;
+                let name_raw: u64 = self.name.into();
+                self.push_address_frame(Frame::new(
+                    Header::new(PGN_ADDRESSCLAIM, 6, 0xFE, Some(255)),
+                    &name_raw.to_le_bytes(),
+                ));
+            }
+        }
+    }
+
+    /// claims a free address of the pool, cannot claim an address if the pool is exhausted
+    fn claim_pool_address(&mut self, address_monitor: &AddressMonitor) {
+        match self
+            .address_pool
+            .find(self.address, address_monitor.control_function_list())
+        {
+            Some(address) => {
+                self.address = address;
+                self.send_addressclaim();
+                self.address_state = AddressState::WaitForVeto(self.time.now());
+            }
+            None => self.enter_cannot_claim(),
+        }
+    }
+
+    fn enter_cannot_claim(&mut self) {
+        self.address_state = AddressState::CannotClaim;
+        self.push_address_event(AddressEvent::CannotClaim);
+        self.send_cannotclaim();
     }
 
-    fn handle_addressclaim(&mut self, frame: &Frame) {
-        let name_raw = u64::from_le_bytes(frame.data().try_into().unwrap());
+    fn handle_addressclaim(
+        &mut self,
+        frame: &Frame,
+        address_monitor: &AddressMonitor,
+    ) -> Result<(), Error> {
+        let name_raw =
+            u64::from_le_bytes(frame.data().try_into().map_err(|_| Error::MalformedFrame)?);
         if matches!(
             self.address_state,
             AddressState::AddressClaimed | AddressState::WaitForVeto(_)
@@ -167,18 +488,13 @@ impl<TimeDriver: crate::time::TimerDriver> ControlFunction<TimeDriver> {
             match name_raw.cmp(&self.name.into()) {
                 core::cmp::Ordering::Less => {
                     // we lose the address
+                    if self.address_state == AddressState::AddressClaimed {
+                        self.push_address_event(AddressEvent::Lost(self.address));
+                    }
                     if self.address_configurable {
-                        // auto configured address should be in range 128 to 247
-                        if self.address < 128 || self.address >= 247 {
-                            self.address = 128;
-                        } else {
-                            self.address += 1;
-                        };
-                        self.address_state = AddressState::WaitForVeto(self.time.now());
-                        self.send_addressclaim();
+                        self.claim_pool_address(address_monitor);
                     } else {
-                        self.address_state = AddressState::CannotClaim;
-                        self.send_cannotclaim();
+                        self.enter_cannot_claim();
                     }
                 }
                 core::cmp::Ordering::Greater => {
@@ -186,10 +502,11 @@ impl<TimeDriver: crate::time::TimerDriver> ControlFunction<TimeDriver> {
                     self.send_addressclaim();
                 }
                 core::cmp::Ordering::Equal => {
-                    panic!("Same Name on Bus")
+                    return Err(Error::NameConflict);
                 }
             }
         }
+        Ok(())
     }
 
     fn send_addressclaim(&mut self) {
@@ -198,15 +515,30 @@ impl<TimeDriver: crate::time::TimerDriver> ControlFunction<TimeDriver> {
             Header::new(PGN_ADDRESSCLAIM, 6, self.address, Some(255)),
             &name_raw.to_le_bytes(),
         );
-        self.send_queue.force_push(frame);
+        self.push_address_frame(frame);
     }
+    /// the frame is sent by `process()` after a RTxD of 0 to 153 ms
     fn send_cannotclaim(&mut self) {
-        // ToDo RTxD delay before sending
-        let name_raw: u64 = self.name.into();
-        let frame = Frame::new(
-            Header::new(PGN_ADDRESSCLAIM, 6, 0xFE, Some(255)),
-            &name_raw.to_le_bytes(),
-        );
-        self.send_queue.force_push(frame);
+        if self.cannot_claim.is_none() {
+            self.cannot_claim = Some(self.time.now() + self.random_delay.next_delay());
+        }
+    }
+    /// the oldest event is dropped if the queue is full
+    fn push_address_event(&mut self, event: AddressEvent) {
+        if self.address_events.force_push(event).is_some() {
+            self.address_event_overflows = self.address_event_overflows.saturating_add(1);
+        }
+    }
+    /// address management frames are always queued, the oldest frame is dropped if the queue is full
+    /// the transfer of a dropped frame fails
+    fn push_address_frame(&mut self, frame: Frame) {
+        if let Some((_, handle)) = self.send_queue.force_push((frame, None)) {
+            self.send_overflows = self.send_overflows.saturating_add(1);
+            if let Some(handle) = handle {
+                // the oldest frame is dropped, it is no longer queued
+                self.dequeued_transfer_id = handle.id().wrapping_add(1);
+                self.dropped_transfers.push(handle);
+            }
+        }
     }
 }
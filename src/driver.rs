@@ -0,0 +1,70 @@
+use crate::Error;
+
+/// Can driver used by the [`Stack`](crate::stack::Stack)
+/// Implemented for all [`embedded_can::blocking::Can`] drivers, [`embedded_can::nb::Can`] drivers are wrapped by [`NbDriver`]
+pub trait Driver {
+    /// Frame type of the can driver
+    type Frame: embedded_can::Frame;
+
+    /// True if `transmit` may replace a pending frame with a lower priority, e.g. by aborting a mailbox
+    /// Transfers of frames handed to such a driver are completed once they can no longer be replaced
+    const REPLACES_PENDING: bool = false;
+
+    /// Puts a frame into the transmit buffer
+    /// Returns `WouldBlock` if the transmit buffer is full, a replaced pending frame is returned to be sent later
+    fn transmit(&mut self, frame: &Self::Frame) -> nb::Result<Option<Self::Frame>, Error>;
+
+    /// Returns a received frame, `WouldBlock` if no frame is available
+    fn receive(&mut self) -> nb::Result<Self::Frame, Error>;
+}
+
+/// Blocking drivers report an empty receive buffer by an error, e.g. a nonblocking socket
+/// therefore receive errors are handled as `WouldBlock`
+impl<CanDriver: embedded_can::blocking::Can> Driver for CanDriver {
+    type Frame = CanDriver::Frame;
+
+    fn transmit(&mut self, frame: &Self::Frame) -> nb::Result<Option<Self::Frame>, Error> {
+        embedded_can::blocking::Can::transmit(self, frame)
+            .map(|_| None)
+            .map_err(|error| nb::Error::Other(Error::driver(error)))
+    }
+
+    fn receive(&mut self) -> nb::Result<Self::Frame, Error> {
+        embedded_can::blocking::Can::receive(self).map_err(|_| nb::Error::WouldBlock)
+    }
+}
+
+/// Wraps a non blocking [`embedded_can::nb::Can`] driver, e.g. a bxCAN or FDCAN driver of a HAL
+/// `WouldBlock` of the driver is not reported as error, other errors are reported as [`Error::Driver`]
+pub struct NbDriver<CanDriver: embedded_can::nb::Can>(CanDriver);
+
+impl<CanDriver: embedded_can::nb::Can> NbDriver<CanDriver> {
+    /// Wraps a non blocking can driver
+    pub fn new(can_driver: CanDriver) -> Self {
+        Self(can_driver)
+    }
+    /// Returns a mutable reference of the wrapped driver, e.g. to handle interrupts
+    pub fn inner(&mut self) -> &mut CanDriver {
+        &mut self.0
+    }
+    /// Returns the wrapped driver
+    pub fn into_inner(self) -> CanDriver {
+        self.0
+    }
+}
+
+impl<CanDriver: embedded_can::nb::Can> Driver for NbDriver<CanDriver> {
+    type Frame = CanDriver::Frame;
+
+    const REPLACES_PENDING: bool = true;
+
+    fn transmit(&mut self, frame: &Self::Frame) -> nb::Result<Option<Self::Frame>, Error> {
+        self.0
+            .transmit(frame)
+            .map_err(|error| error.map(Error::driver))
+    }
+
+    fn receive(&mut self) -> nb::Result<Self::Frame, Error> {
+        self.0.receive().map_err(|error| error.map(Error::driver))
+    }
+}
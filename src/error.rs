@@ -0,0 +1,47 @@
+use core::fmt;
+
+/// Errors reported by the stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The can driver failed to send or receive a frame, e.g. a full tx mailbox or bus off
+    Driver(embedded_can::ErrorKind),
+    /// A queue of the stack or a control function is full
+    QueueFull,
+    /// The control function has no claimed address
+    NoAddress,
+    /// Another control function on the bus claims an address with the same NAME
+    NameConflict,
+    /// A received frame does not match the format of its PGN
+    MalformedFrame,
+    /// The [`AddressStore`](crate::address_store::AddressStore) failed to store an address
+    AddressStore,
+}
+
+impl Error {
+    /// Converts an error of the can driver
+    pub(crate) fn driver<E: embedded_can::Error>(error: E) -> Self {
+        Error::Driver(error.kind())
+    }
+
+    /// Converts an error of a socket
+    #[cfg(any(feature = "tokio", feature = "linux-j1939"))]
+    pub(crate) fn io(_: std::io::Error) -> Self {
+        Error::Driver(embedded_can::ErrorKind::Other)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Driver(kind) => write!(f, "can driver error: {}", kind),
+            Error::QueueFull => write!(f, "queue is full"),
+            Error::NoAddress => write!(f, "control function has no claimed address"),
+            Error::NameConflict => write!(f, "same NAME on bus"),
+            Error::MalformedFrame => write!(f, "malformed frame"),
+            Error::AddressStore => write!(f, "address could not be stored"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
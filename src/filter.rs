@@ -0,0 +1,129 @@
+use crate::frame::{Frame, PGN};
+use crate::name::Name;
+use alloc::collections::BTreeMap;
+
+/// Rule of a receive filter, a frame matches the rule if all set conditions match
+/// A new rule without conditions matches all frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilterRule {
+    /// inclusive range of PGNs
+    pgns: Option<(PGN, PGN)>,
+    source_address: Option<u8>,
+    /// NAME of the source and the mask of the compared bits
+    source_name: Option<(u64, u64)>,
+}
+
+impl Default for FilterRule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FilterRule {
+    /// Creates a rule matching all frames
+    pub const fn new() -> Self {
+        Self {
+            pgns: None,
+            source_address: None,
+            source_name: None,
+        }
+    }
+    /// Matches frames with the given PGN
+    pub const fn pgn(self, pgn: PGN) -> Self {
+        self.pgn_range(pgn, pgn)
+    }
+    /// Matches frames with a PGN in the inclusive range
+    pub const fn pgn_range(mut self, first: PGN, last: PGN) -> Self {
+        self.pgns = Some((first, last));
+        self
+    }
+    /// Matches frames sent with the given source address
+    pub const fn source_address(mut self, source_address: u8) -> Self {
+        self.source_address = Some(source_address);
+        self
+    }
+    /// Matches frames sent by the control function with the given NAME
+    /// The NAME is resolved by the address claims on the bus, frames of unknown sources do not match
+    pub fn source_name(self, name: Name) -> Self {
+        self.source_name_masked(name, u64::MAX)
+    }
+    /// Matches frames sent by a control function whose NAME equals the given NAME in the bits set in the mask,
+    /// e.g. all control functions with the same function and industry group
+    pub fn source_name_masked(mut self, name: Name, mask: u64) -> Self {
+        self.source_name = Some((u64::from(name) & mask, mask));
+        self
+    }
+
+    /// `control_functions` is the list of claimed addresses and their NAME
+    pub(crate) fn matches(&self, frame: &Frame, control_functions: &BTreeMap<u8, Name>) -> bool {
+        let header = frame.header();
+        if let Some((first, last)) = self.pgns {
+            if header.pgn() < first || header.pgn() > last {
+                return false;
+            }
+        }
+        if let Some(source_address) = self.source_address {
+            if header.source_address() != source_address {
+                return false;
+            }
+        }
+        if let Some((name, mask)) = self.source_name {
+            match control_functions.get(&header.source_address()) {
+                Some(source) if u64::from(*source) & mask == name => (),
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Header;
+
+    fn frame(pgn: u32, source_address: u8) -> Frame {
+        Frame::new(Header::new(PGN::new(pgn), 6, source_address, None), &[0; 8])
+    }
+
+    #[test]
+    fn pgn_filter() {
+        let list = BTreeMap::new();
+        let rule = FilterRule::new().pgn(PGN::new(0xF004));
+        assert!(rule.matches(&frame(0xF004, 0x00), &list));
+        assert!(!rule.matches(&frame(0xF003, 0x00), &list));
+
+        let rule = FilterRule::new().pgn_range(PGN::new(0xFF00), PGN::new(0xFFFF));
+        assert!(rule.matches(&frame(0xFF00, 0x00), &list));
+        assert!(rule.matches(&frame(0xFFFF, 0x00), &list));
+        assert!(!rule.matches(&frame(0xFEFF, 0x00), &list));
+        assert!(FilterRule::new().matches(&frame(0xFEFF, 0x00), &list));
+    }
+
+    #[test]
+    fn source_filter() {
+        let name = Name {
+            identity_number: 0x1234,
+            ..Name::default()
+        };
+        let mut list = BTreeMap::new();
+        list.insert(0x21, name);
+        list.insert(0x22, Name::default());
+
+        let rule = FilterRule::new().pgn(PGN::new(0xF004)).source_address(0x21);
+        assert!(rule.matches(&frame(0xF004, 0x21), &list));
+        assert!(!rule.matches(&frame(0xF004, 0x22), &list));
+        assert!(!rule.matches(&frame(0xF005, 0x21), &list));
+
+        let rule = FilterRule::new().source_name(name);
+        assert!(rule.matches(&frame(0xF004, 0x21), &list));
+        assert!(!rule.matches(&frame(0xF004, 0x22), &list));
+        // unknown source address
+        assert!(!rule.matches(&frame(0xF004, 0x23), &list));
+
+        // only the identity number is ignored
+        let rule = FilterRule::new().source_name_masked(name, !0x1F_FFFF);
+        assert!(rule.matches(&frame(0xF004, 0x21), &list));
+        assert!(rule.matches(&frame(0xF004, 0x22), &list));
+    }
+}
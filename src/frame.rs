@@ -1,3 +1,6 @@
+use crate::name::Name;
+use crate::time::Instant;
+use crate::Error;
 use smallvec::SmallVec;
 
 /// PGN contains a unique id, describing the content of a J1939 frame
@@ -15,8 +18,9 @@ impl PGN {
         self.0
     }
     /// Checks if the pgn is a broadcast pgn as defined by the j1939 standard
+    /// Broadcast pgns use the PDU2 format (PDU format >= 240) without destination address
     pub const fn is_broadcast(&self) -> bool {
-        ((self.0 >> 8) & 0xFF) > 240
+        ((self.0 >> 8) & 0xFF) >= 240
     }
 }
 /// Transport Protocol Control Flow PGN
@@ -114,14 +118,48 @@ impl From<Header> for u32 {
     }
 }
 
+/// Receive time of a frame
+/// Frames of a transport protocol are stamped with the time of their first and last can frame
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Timestamp {
+    /// time of the first can frame, e.g. the RTS or BAM of a transfer
+    pub first: Instant,
+    /// time of the last can frame
+    pub last: Instant,
+}
+
+impl Timestamp {
+    /// Creates a timestamp of a frame received in a single can frame
+    pub const fn new(instant: Instant) -> Self {
+        Self {
+            first: instant,
+            last: instant,
+        }
+    }
+    /// Creates a timestamp of a frame received in multiple can frames
+    pub const fn transfer(first: Instant, last: Instant) -> Self {
+        Self { first, last }
+    }
+}
+
 /// Decoded J1929 Frame.
 /// If the data length is higher than 8, this frame is disassembled for transport over can
-#[derive(Debug, PartialEq, Eq, Clone)]
+/// Frames are compared by their header and data, the timestamp is ignored
+#[derive(Debug, Clone)]
 pub struct Frame {
     header: Header,
     data: SmallVec<[u8; 8]>,
+    timestamp: Option<Timestamp>,
 }
 
+impl PartialEq for Frame {
+    fn eq(&self, other: &Self) -> bool {
+        self.header == other.header && self.data == other.data
+    }
+}
+
+impl Eq for Frame {}
+
 impl Frame {
     /// Creates a new Frame with given Header and data
     /// The data is copied
@@ -129,6 +167,7 @@ impl Frame {
         Self {
             header,
             data: SmallVec::from_slice(data),
+            timestamp: None,
         }
     }
     /// Returns frame header
@@ -139,14 +178,33 @@ impl Frame {
     pub fn data(&self) -> &[u8] {
         &self.data
     }
+    /// Returns the receive time, None for frames not received by a stack
+    pub const fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+    /// Sets the receive time
+    pub fn with_timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
 
     pub(crate) fn update_source_address(&mut self, address: u8) {
         self.header.source_address = address;
     }
 
-    pub(crate) fn can<CanFrame: embedded_can::Frame>(self) -> CanFrame {
+    pub(crate) fn can<CanFrame: embedded_can::Frame>(&self) -> Result<CanFrame, Error> {
         let id: u32 = (*self.header()).into();
-        CanFrame::new(embedded_can::ExtendedId::new(id).unwrap(), self.data()).unwrap()
+        embedded_can::ExtendedId::new(id)
+            .and_then(|id| CanFrame::new(id, self.data()))
+            .ok_or(Error::MalformedFrame)
+    }
+
+    /// Converts a can frame with an extended id
+    pub(crate) fn from_can<CanFrame: embedded_can::Frame>(frame: &CanFrame) -> Option<Self> {
+        match frame.id() {
+            embedded_can::Id::Extended(id) => Some(Self::new(id.as_raw().into(), frame.data())),
+            embedded_can::Id::Standard(_) => None,
+        }
     }
 }
 
@@ -181,17 +239,17 @@ impl Request {
     }
 }
 impl TryFrom<Frame> for Request {
-    type Error = ();
+    type Error = Error;
     fn try_from(frame: Frame) -> Result<Self, Self::Error> {
-        if frame.header.pgn() == PGN_REQUEST {
+        if frame.header.pgn() == PGN_REQUEST && frame.data().len() >= 3 {
             let mut bytes: [u8; 4] = [0; 4];
-            bytes[0..3].copy_from_slice(frame.data());
+            bytes[0..3].copy_from_slice(&frame.data()[0..3]);
             Ok(Self {
                 header: frame.header,
                 pgn: PGN::new(u32::from_le_bytes(bytes)),
             })
         } else {
-            Err(())
+            Err(Error::MalformedFrame)
         }
     }
 }
@@ -298,9 +356,9 @@ impl Ack {
     }
 }
 impl TryFrom<Frame> for Ack {
-    type Error = ();
+    type Error = Error;
     fn try_from(frame: Frame) -> Result<Self, Self::Error> {
-        if frame.header.pgn() == PGN_ACK {
+        if frame.header.pgn() == PGN_ACK && frame.data().len() >= 8 {
             let mut bytes: [u8; 4] = [0; 4];
             bytes[0..3].copy_from_slice(&frame.data()[5..8]);
 
@@ -317,7 +375,7 @@ impl TryFrom<Frame> for Ack {
                 address: frame.data()[4],
             })
         } else {
-            Err(())
+            Err(Error::MalformedFrame)
         }
     }
 }
@@ -333,6 +391,71 @@ impl From<Ack> for Frame {
     }
 }
 
+/// Commanded address, assigns a new source address to the control function with the NAME
+/// The frame has 9 bytes and is sent with the broadcast transport protocol
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AddressCommand {
+    header: Header,
+    name: Name,
+    address: u8,
+}
+
+impl AddressCommand {
+    /// Creates a new Commanded Address Frame
+    /// name is the NAME of the commanded control function, address the new source address
+    /// source address should be the valid local address of a control function, e.g. of a service tool
+    pub const fn new(name: Name, address: u8, source_address: u8) -> Self {
+        let header = Header {
+            pgn: PGN_ADDRESSCOMMAND,
+            priority: 6,
+            source_address,
+            destination_address: None,
+        };
+        Self {
+            header,
+            name,
+            address,
+        }
+    }
+    /// Returns the [Header]
+    pub const fn header(&self) -> &Header {
+        &self.header
+    }
+    /// Returns the NAME of the commanded control function
+    pub const fn name(&self) -> &Name {
+        &self.name
+    }
+    /// Returns the new source address
+    pub const fn address(&self) -> u8 {
+        self.address
+    }
+}
+impl TryFrom<Frame> for AddressCommand {
+    type Error = Error;
+    fn try_from(frame: Frame) -> Result<Self, Self::Error> {
+        if frame.header.pgn() == PGN_ADDRESSCOMMAND && frame.data().len() >= 9 {
+            let mut bytes: [u8; 8] = [0; 8];
+            bytes.copy_from_slice(&frame.data()[0..8]);
+            Ok(Self {
+                header: frame.header,
+                name: Name::from(u64::from_le_bytes(bytes)),
+                address: frame.data()[8],
+            })
+        } else {
+            Err(Error::MalformedFrame)
+        }
+    }
+}
+
+impl From<AddressCommand> for Frame {
+    fn from(command: AddressCommand) -> Self {
+        let mut bytes = [0; 9];
+        bytes[0..8].copy_from_slice(&u64::from(command.name).to_le_bytes());
+        bytes[8] = command.address;
+        Self::new(command.header, &bytes)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::frame::*;
@@ -375,4 +498,35 @@ mod tests {
         assert_eq!(header, Header::from(id));
         assert_eq!(id, header.into());
     }
+
+    #[test]
+    fn frame_timestamp() {
+        let frame = Frame::new(Header::new(PGN::new(0xFEB2), 6, 0x01, None), &[1, 2, 3]);
+        assert_eq!(frame.timestamp(), None);
+        let stamped = frame
+            .clone()
+            .with_timestamp(Timestamp::new(Instant::from_ticks(10)));
+        assert_eq!(stamped.timestamp().unwrap().last, Instant::from_ticks(10));
+        // the timestamp is not compared
+        assert_eq!(frame, stamped);
+    }
+
+    #[test]
+    fn address_command() {
+        let command = AddressCommand::new(Name::default(), 0x90, 0x80);
+        let frame: Frame = command.clone().into();
+        assert_eq!(
+            frame.header(),
+            &Header::new(PGN_ADDRESSCOMMAND, 6, 0x80, None)
+        );
+        assert_eq!(frame.data(), &[0, 0, 0, 0, 0, 255, 2, 160, 0x90]);
+        assert_eq!(AddressCommand::try_from(frame), Ok(command));
+        assert_eq!(
+            AddressCommand::try_from(Frame::new(
+                Header::new(PGN_ADDRESSCOMMAND, 6, 0x80, None),
+                &[0; 8]
+            )),
+            Err(Error::MalformedFrame)
+        );
+    }
 }
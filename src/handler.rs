@@ -0,0 +1,51 @@
+use crate::frame::{Ack, AckType, Frame, Header, PGN};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Handler of received frames, registered per PGN on a [`ControlFunction`](crate::control_function::ControlFunction)
+pub type Handler = Box<dyn FnMut(&Frame, &mut Responder) + Send>;
+
+/// Collects the responses of a [`Handler`], which are sent by the control function after the handler returns
+pub struct Responder {
+    /// source address of the handled frame
+    requester: u8,
+    responses: Vec<Frame>,
+}
+
+impl Responder {
+    pub(crate) fn new(requester: u8) -> Self {
+        Self {
+            requester,
+            responses: Vec::new(),
+        }
+    }
+    /// Returns the source address of the handled frame
+    pub fn requester(&self) -> u8 {
+        self.requester
+    }
+    /// Replies with the PGN and data to the requester, broadcast PGNs are sent to all control functions
+    pub fn reply(&mut self, pgn: PGN, data: &[u8]) {
+        let destination_address = if pgn.is_broadcast() {
+            None
+        } else {
+            Some(self.requester)
+        };
+        // the source address is inserted by the control function
+        self.send(Frame::new(
+            Header::new(pgn, 6, 0xFF, destination_address),
+            data,
+        ));
+    }
+    /// Acknowledges a PGN to the requester, e.g. a negative acknowledgement for an unsupported request
+    pub fn ack(&mut self, ack_type: AckType, group_function_value: Option<u8>, pgn: PGN) {
+        self.send(Ack::new(ack_type, group_function_value, pgn, 0xFF, self.requester).into());
+    }
+    /// Sends any frame, the source address is replaced by the address of the control function
+    pub fn send(&mut self, frame: Frame) {
+        self.responses.push(frame);
+    }
+
+    pub(crate) fn into_responses(self) -> Vec<Frame> {
+        self.responses
+    }
+}
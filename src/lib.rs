@@ -2,22 +2,48 @@
 #![warn(missing_docs)]
 
 //! A J1939 Stack
-//! Uses a [`embedded_can::blocking::Can`] can driver
+//! Uses a [`embedded_can::blocking::Can`] or [`embedded_can::nb::Can`] can driver
 
 extern crate alloc;
 
+/// Address pools of control functions with a configurable address
+pub mod address_pool;
+/// Persistent storage of claimed addresses
+pub mod address_store;
+/// Async front end of the stack
+pub mod async_stack;
+/// Stack configuration
+pub mod config;
 /// Control Function
 pub mod control_function;
+/// Can driver abstraction
+pub mod driver;
+/// Receive filters of control functions
+pub mod filter;
 /// J1939 Frames
 pub mod frame;
+/// Handlers of received frames
+pub mod handler;
+/// Stack on top of the J1939 sockets of the linux kernel, gated by the linux-j1939 feature
+#[cfg(all(feature = "linux-j1939", target_os = "linux"))]
+pub mod linux_j1939;
 /// J1939 Name and enums
 pub mod name;
 /// J1939 Stack
 pub mod stack;
 /// Time utilities for the stack
 pub mod time;
+/// Tokio runtime for the stack, gated by the tokio feature
+#[cfg(feature = "tokio")]
+pub mod tokio;
+/// Transport protocol events
+pub mod transport;
+
+pub use error::Error;
 
 mod address;
+mod error;
+mod stack_core;
 #[cfg(test)]
 mod test_utils;
-mod transport;
+mod tx_queue;
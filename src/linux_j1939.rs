@@ -0,0 +1,713 @@
+use crate::address_pool::AddressPool;
+use crate::address_store::AddressStore;
+use crate::config::StackConfig;
+use crate::control_function::ControlFunction;
+use crate::filter::FilterRule;
+use crate::frame::{AddressCommand, Frame, Header, Timestamp, PGN};
+use crate::name::Name;
+use crate::stack::{ControlFunctionHandle, Overflows};
+use crate::stack_core::StackCore;
+use crate::time::{Instant, TimerDriver};
+use crate::transport::{TransferError, TransferHandle, TransferState, Transfers};
+use crate::Error;
+use std::collections::BTreeMap;
+use std::ffi::{c_void, CString};
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+/// `J1939_NO_NAME`, which is a c_ulong
+const NO_NAME: u64 = 0;
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Header of a frame received by a socket
+/// The kernel reports the destination address of peer to peer PGNs as control message
+fn header(pgn: u32, priority: u8, source_address: u8, destination_address: Option<u8>) -> Header {
+    let pgn = PGN::new(pgn);
+    if pgn.is_broadcast() {
+        Header::new(pgn, priority, source_address, None)
+    } else {
+        Header::new(
+            PGN::new(pgn.raw() & 0x3FF00),
+            priority,
+            source_address,
+            Some(destination_address.unwrap_or(libc::J1939_NO_ADDR)),
+        )
+    }
+}
+
+/// Destination address of a frame to send, broadcasts are sent to no address
+fn destination(header: &Header) -> u8 {
+    header.destination_address().unwrap_or(libc::J1939_NO_ADDR)
+}
+
+/// J1939 datagram socket of the linux kernel (`CAN_J1939`)
+/// The kernel sends frames longer than 8 bytes with the transport protocols and reassembles received frames
+pub struct J1939Socket {
+    fd: OwnedFd,
+    ifindex: libc::c_int,
+}
+
+impl J1939Socket {
+    /// Opens a socket on the can interface with the given name, e.g. `vcan0`
+    /// Sending broadcasts is allowed, the socket must be bound before it is used
+    pub fn open(ifname: &str) -> io::Result<Self> {
+        let ifname =
+            CString::new(ifname).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        // SAFETY: the interface name is a valid C string
+        let ifindex = unsafe { libc::if_nametoindex(ifname.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: creates a new socket, the arguments are plain values
+        let fd = check(unsafe { libc::socket(libc::PF_CAN, libc::SOCK_DGRAM, libc::CAN_J1939) })?;
+        let socket = Self {
+            // SAFETY: the file descriptor was just created and is not owned by anything else
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            ifindex: ifindex as libc::c_int,
+        };
+        socket.set_option(libc::SOL_SOCKET, libc::SO_BROADCAST, &(1 as libc::c_int))?;
+        Ok(socket)
+    }
+
+    /// Binds the socket to a NAME and source address, frames without NAME are sent with the source address
+    /// Frames of a bound NAME are sent with the address claimed by the NAME
+    /// Binding again changes the source address, e.g. before the address claim of a new address is sent
+    pub fn bind(&self, name: Option<Name>, address: u8) -> io::Result<()> {
+        let name = name.map_or(NO_NAME, u64::from);
+        let addr = self.address(name, libc::J1939_NO_PGN, address);
+        // SAFETY: the address is a valid sockaddr_can and its size is passed
+        check(unsafe {
+            libc::bind(
+                self.fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_can as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_can>() as libc::socklen_t,
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Receives all frames of the bus, not only the frames addressed to the socket
+    pub fn set_promiscuous(&self, promiscuous: bool) -> io::Result<()> {
+        self.set_option(
+            libc::SOL_CAN_J1939,
+            libc::SO_J1939_PROMISC,
+            &(promiscuous as libc::c_int),
+        )
+    }
+
+    /// In non blocking mode `receive` returns an error of kind `WouldBlock` if no frame is available
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let fd = self.fd.as_raw_fd();
+        // SAFETY: reads the flags of an owned file descriptor
+        let flags = check(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+        let flags = if nonblocking {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        };
+        // SAFETY: sets the flags of an owned file descriptor
+        check(unsafe { libc::fcntl(fd, libc::F_SETFL, flags) })?;
+        Ok(())
+    }
+
+    /// Sends a frame, the destination address and priority are taken from the header
+    /// The source address of the header is replaced by the address of the socket
+    pub fn send(&self, frame: &Frame) -> io::Result<()> {
+        let header = frame.header();
+        self.set_option(
+            libc::SOL_CAN_J1939,
+            libc::SO_J1939_SEND_PRIO,
+            &(header.priority() as libc::c_int),
+        )?;
+        let addr = self.address(NO_NAME, header.pgn().raw(), destination(header));
+        // SAFETY: the data and the address are valid for the given sizes
+        let sent = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                frame.data().as_ptr() as *const c_void,
+                frame.data().len(),
+                0,
+                &addr as *const libc::sockaddr_can as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_can>() as libc::socklen_t,
+            )
+        };
+        if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Receives a frame, frames longer than 8 bytes are already reassembled
+    pub fn receive(&self) -> io::Result<Frame> {
+        self.receive_message().map(|(frame, _)| frame)
+    }
+
+    /// Receives a frame, the flag is set if the frame was sent by a socket of this host
+    pub(crate) fn receive_message(&self) -> io::Result<(Frame, bool)> {
+        let fd = self.fd.as_raw_fd();
+        // the length of a transfer is only known by peeking, the extended transport protocol transfers up to 117 MB
+        // SAFETY: a zero sized read with a null buffer does not write any data
+        let length = unsafe {
+            libc::recv(
+                fd,
+                core::ptr::null_mut(),
+                0,
+                libc::MSG_PEEK | libc::MSG_TRUNC,
+            )
+        };
+        if length < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut data = vec![0u8; length as usize];
+        // SAFETY: sockaddr_can is a plain C struct, all zero is a valid value
+        let mut addr: libc::sockaddr_can = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut c_void,
+            iov_len: data.len(),
+        };
+        // aligned buffer for the destination address, priority and NAME control messages
+        let mut control = [0u64; 16];
+        // SAFETY: msghdr is a plain C struct, all zero is a valid value
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut addr as *mut libc::sockaddr_can as *mut c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_can>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        // SAFETY: all buffers of the message are valid for their given sizes
+        let length = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+        if length < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut destination_address = None;
+        let mut priority = 6;
+        // SAFETY: the control messages were written by the kernel into the control buffer of the message
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_CAN_J1939 {
+                    match (*cmsg).cmsg_type {
+                        libc::SCM_J1939_DEST_ADDR => {
+                            destination_address = Some(*libc::CMSG_DATA(cmsg));
+                        }
+                        libc::SCM_J1939_PRIO => priority = *libc::CMSG_DATA(cmsg),
+                        _ => (),
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        // SAFETY: the kernel writes the j1939 source address of the frame
+        let source = unsafe { addr.can_addr.j1939 };
+        let header = header(source.pgn, priority, source.addr, destination_address);
+        let data = &data[..(length as usize).min(data.len())];
+        let local = msg.msg_flags & libc::MSG_DONTROUTE != 0;
+        Ok((Frame::new(header, data), local))
+    }
+
+    /// Drops all received frames, used by sockets which only send frames
+    fn ignore_received(&self) -> io::Result<()> {
+        // no frame is sent with the source address 0xFF
+        let filter = libc::j1939_filter {
+            name: 0,
+            name_mask: 0,
+            pgn: 0,
+            pgn_mask: 0,
+            addr: 0xFF,
+            addr_mask: 0xFF,
+        };
+        self.set_option(libc::SOL_CAN_J1939, libc::SO_J1939_FILTER, &filter)
+    }
+
+    fn address(&self, name: u64, pgn: u32, address: u8) -> libc::sockaddr_can {
+        // SAFETY: sockaddr_can is a plain C struct, all zero is a valid value
+        let mut addr: libc::sockaddr_can = unsafe { mem::zeroed() };
+        addr.can_family = libc::AF_CAN as libc::sa_family_t;
+        addr.can_ifindex = self.ifindex;
+        addr.can_addr.j1939 = libc::__c_anonymous_sockaddr_can_j1939 {
+            name,
+            pgn,
+            addr: address,
+        };
+        addr
+    }
+
+    fn set_option<T>(&self, level: libc::c_int, option: libc::c_int, value: &T) -> io::Result<()> {
+        // SAFETY: the value is valid for its size
+        check(unsafe {
+            libc::setsockopt(
+                self.fd.as_raw_fd(),
+                level,
+                option,
+                value as *const T as *const c_void,
+                mem::size_of::<T>() as libc::socklen_t,
+            )
+        })?;
+        Ok(())
+    }
+}
+
+impl AsRawFd for J1939Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Socket sending the frames of a control function or the stack
+/// The socket is opened by the first frame, errors are reported by the send
+struct SendSocket {
+    socket: Option<J1939Socket>,
+    name: Option<Name>,
+    /// bound source address
+    address: Option<u8>,
+}
+
+impl SendSocket {
+    fn new(name: Option<Name>) -> Self {
+        Self {
+            socket: None,
+            name,
+            address: None,
+        }
+    }
+
+    /// the socket is bound again if the source address of the frame changed, e.g. by an address claim
+    fn send(&mut self, ifname: &str, frame: &Frame) -> io::Result<()> {
+        let socket = match &mut self.socket {
+            Some(socket) => socket,
+            socket => {
+                let opened = J1939Socket::open(ifname)?;
+                opened.ignore_received()?;
+                socket.insert(opened)
+            }
+        };
+        let source_address = frame.header().source_address();
+        if self.address != Some(source_address) {
+            socket.bind(self.name, source_address)?;
+            self.address = Some(source_address);
+        }
+        socket.send(frame)
+    }
+}
+
+/// J1939 stack on top of the J1939 sockets of the linux kernel
+/// Provides the API of [`Stack`](crate::stack::Stack), the kernel transports frames longer than 8 bytes
+/// while the address management of the control functions is done by the stack
+/// Frames are sent when the kernel accepts them, frames sent by other sockets of the host are not received
+/// Transport events are not reported, because the transport sessions are handled by the kernel
+pub struct J1939Stack<TimeDriver: TimerDriver> {
+    /// control functions, address monitor and receive queue
+    core: StackCore<TimeDriver>,
+    transfers: Transfers,
+    cf_sockets: Vec<SendSocket>,
+    /// receives all frames of the bus
+    monitor: J1939Socket,
+    /// sends the frames of the stack
+    socket: SendSocket,
+    ifname: String,
+}
+
+impl<TimeDriver: Clone + TimerDriver> J1939Stack<TimeDriver> {
+    /// Creates a new stack on the can interface with the given name, e.g. `vcan0`
+    /// The standard configuration receives all broadcast frames
+    pub fn new(ifname: &str, time: TimeDriver) -> Result<Self, Error> {
+        Self::new_with_config(ifname, time, StackConfig::default())
+    }
+    /// Creates a new stack on the can interface with the given name
+    /// Only the queue capacities of the [`StackConfig`] are used, the transport protocols are configured in the kernel
+    pub fn new_with_config(
+        ifname: &str,
+        time: TimeDriver,
+        config: StackConfig,
+    ) -> Result<Self, Error> {
+        let monitor = J1939Socket::open(ifname).map_err(Error::io)?;
+        monitor.set_promiscuous(true).map_err(Error::io)?;
+        monitor.set_nonblocking(true).map_err(Error::io)?;
+        monitor.bind(None, libc::J1939_NO_ADDR).map_err(Error::io)?;
+        Ok(Self {
+            core: StackCore::new(config, time),
+            transfers: Transfers::new(),
+            cf_sockets: Vec::new(),
+            monitor,
+            socket: SendSocket::new(None),
+            ifname: ifname.into(),
+        })
+    }
+
+    /// Receives frames and runs the address management of the control functions
+    /// should be called periodically
+    /// All tasks are processed even if one fails, the first error is returned
+    pub fn process(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+        loop {
+            match self.monitor.receive_message() {
+                // frames of the control functions are looped back before they are sent
+                Ok((_, true)) => (),
+                Ok((frame, false)) => {
+                    // transfers are reassembled by the kernel, the time of the first can frame is unknown
+                    let frame = frame.with_timestamp(Timestamp::new(self.core.time().now()));
+                    if self
+                        .core
+                        .check_destination(frame.header().destination_address())
+                    {
+                        result = result.and(self.core.handle_new_frame(frame));
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    result = result.and(Err(Error::io(error)));
+                    break;
+                }
+            }
+        }
+        result.and(self.process_control_functions())
+    }
+
+    /// Returns the next instant `process()` has to run the address management of the control functions
+    /// Received frames are not covered, the socket has to be polled for them
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.core.next_deadline()
+    }
+
+    /// Returns the number of frames dropped because the receive queue of the stack was full
+    pub fn overflows(&self) -> Overflows {
+        Overflows {
+            received_frames: self.core.dropped_frames(),
+            ..Overflows::default()
+        }
+    }
+
+    /// Provides a map with all ecus on the bus
+    /// key is the source address, value the name of that ecu
+    pub fn control_function_list(&self) -> &BTreeMap<u8, Name> {
+        self.core.control_function_list()
+    }
+
+    // ---------------------- control functions ----------------------------------------------------
+    /// Sets the store of the last claimed addresses, which is used by control functions registered afterwards
+    /// A stored address of the NAME replaces the preferred address of `register_control_function`
+    pub fn set_address_store(&mut self, store: impl AddressStore + Send + 'static) {
+        self.core.set_address_store(store);
+    }
+    /// Creates a new [`ControlFunction`] with a preferred address and Name
+    /// Each control function sends with an own socket bound to its NAME, which is opened by its first frame
+    pub fn register_control_function(
+        &mut self,
+        preferred_address: u8,
+        name: Name,
+    ) -> ControlFunctionHandle {
+        self.register_control_function_with_pool(preferred_address, name, AddressPool::default())
+    }
+    /// Creates a new [`ControlFunction`] with a preferred address, Name and the [`AddressPool`]
+    /// searched for a free address if the preferred address is taken
+    pub fn register_control_function_with_pool(
+        &mut self,
+        preferred_address: u8,
+        name: Name,
+        address_pool: AddressPool,
+    ) -> ControlFunctionHandle {
+        self.cf_sockets.push(SendSocket::new(Some(name)));
+        self.core
+            .register_control_function(preferred_address, name, address_pool)
+    }
+    /// Returns a mutable reference of a [`ControlFunction`].
+    /// `ControlsFunctions` are identified by a [`ControlFunctionHandle`]
+    pub fn control_function(
+        &mut self,
+        handle: &ControlFunctionHandle,
+    ) -> &mut ControlFunction<TimeDriver> {
+        self.core.control_function(handle)
+    }
+
+    fn process_control_functions(&mut self) -> Result<(), Error> {
+        let cf_sockets = &mut self.cf_sockets;
+        let transfers = &mut self.transfers;
+        let ifname = &self.ifname;
+        let result = self
+            .core
+            .process_control_functions(|_, cf_index, frame, handle| {
+                if handle.is_some_and(|handle| {
+                    transfers.state(&handle)
+                        == Some(TransferState::Failed(TransferError::Cancelled))
+                }) {
+                    return Ok(false);
+                }
+                let sent = cf_sockets[cf_index].send(ifname, frame);
+                finish_transfer(transfers, handle, sent).map(|_| true)
+            });
+        // frames of transfers dropped by the address management of the control functions
+        for handle in self.core.take_dropped_transfers() {
+            self.transfers
+                .update(handle, TransferState::Failed(TransferError::Dropped));
+        }
+        result
+    }
+
+    // --------------------------- direct stack usage ----------------------------------------------
+    /// Returns a received J1939 Frame
+    /// Frames longer than 8 Bytes are already assembled by the kernel
+    pub fn get_frame(&mut self) -> Option<Frame> {
+        self.core.get_frame()
+    }
+    /// Send a J1939 Frame with the source address of its header
+    /// Control functions are strongly preferred to send frames
+    /// Frames longer than 8 bytes are send by the transport protocols of the kernel
+    /// Returns an error if the kernel rejects the frame
+    pub fn send_frame(&mut self, frame: Frame) -> Result<TransferHandle, Error> {
+        let handle = self.core.next_transfer_handle();
+        let sent = self.socket.send(&self.ifname, &frame);
+        finish_transfer(&mut self.transfers, Some(handle), sent)?;
+        Ok(handle)
+    }
+    /// Sends a commanded address, which assigns the address to the control function with the NAME
+    /// The command is sent with the given source address, e.g. the address of a service tool
+    pub fn send_address_command(
+        &mut self,
+        source_address: u8,
+        name: Name,
+        address: u8,
+    ) -> Result<TransferHandle, Error> {
+        self.send_frame(AddressCommand::new(name, address, source_address).into())
+    }
+    /// Returns the state of a transfer started by `send_frame` of the stack or a control function
+    /// Transfers are completed as soon as the kernel accepts the frame
+    pub fn transfer_state(&self, handle: &TransferHandle) -> Option<TransferState> {
+        self.core
+            .transfer_state(handle, self.transfers.state(handle))
+    }
+    /// Cancels a transfer waiting in the send queue of a control function
+    /// Returns false if the transfer is already handed to the kernel or unknown
+    pub fn cancel_transfer(&mut self, handle: &TransferHandle) -> bool {
+        if self.transfer_state(handle) != Some(TransferState::Queued) {
+            return false;
+        }
+        self.transfers
+            .update(*handle, TransferState::Failed(TransferError::Cancelled));
+        true
+    }
+    /// Set if the stack accepts messages to all destination addresses
+    /// If false broadcasts messages are accepted
+    /// This has no effect for control functions
+    pub fn set_accepted_all(&mut self, accept_all: bool) {
+        self.core.set_accepted_all(accept_all);
+    }
+    /// Adds a rule to the receive filter of the stack, only frames matching one of the rules are received
+    /// This has no effect for control functions
+    pub fn add_filter(&mut self, rule: FilterRule) {
+        self.core.add_filter(rule);
+    }
+    /// Removes all rules of the receive filter of the stack, all frames are received again
+    pub fn clear_filter(&mut self) {
+        self.core.clear_filter();
+    }
+}
+
+/// Completes the transfer if the kernel accepted the frame
+fn finish_transfer(
+    transfers: &mut Transfers,
+    handle: Option<TransferHandle>,
+    sent: io::Result<()>,
+) -> Result<(), Error> {
+    let result = sent.map_err(Error::io);
+    if let Some(handle) = handle {
+        let state = match result {
+            Ok(()) => TransferState::Completed,
+            Err(error) => TransferState::Failed(TransferError::Send(error)),
+        };
+        transfers.update(handle, state);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn received_header() {
+        assert_eq!(
+            header(0xFEF1, 3, 0x21, None),
+            Header::new(PGN::new(0xFEF1), 3, 0x21, None)
+        );
+        // the destination address is not part of the pgn
+        assert_eq!(
+            header(0xEF22, 6, 0x21, Some(0x22)),
+            Header::new(PGN::new(0xEF00), 6, 0x21, Some(0x22))
+        );
+        assert_eq!(
+            header(0xEA00, 6, 0x21, None),
+            Header::new(PGN::new(0xEA00), 6, 0x21, Some(0xFF))
+        );
+    }
+
+    #[test]
+    fn destination_address() {
+        assert_eq!(
+            destination(&Header::new(PGN::new(0xFEF1), 3, 0x21, None)),
+            libc::J1939_NO_ADDR
+        );
+        assert_eq!(
+            destination(&Header::new(PGN::new(0xEF00), 3, 0x21, Some(0x22))),
+            0x22
+        );
+    }
+
+    /// Tests with the sockets of the kernel, ignored by default because they need the interface `vcan0`
+    /// and the J1939 protocol: `ip link add vcan0 type vcan && ip link set vcan0 up && modprobe can-j1939`
+    /// Run them with `cargo test --features linux-j1939 -- --ignored`
+    mod socket {
+        use super::*;
+        use crate::test_utils::test_time::TestTimer;
+        use embedded_can::{ExtendedId, Frame as _, Id};
+        use socketcan::{CanFrame, CanSocket, Socket};
+        use std::time::Duration;
+
+        /// Returns the stack and a raw can socket, which sends and receives the frames of other ECUs
+        fn open() -> (J1939Stack<TestTimer>, CanSocket) {
+            let stack = J1939Stack::new("vcan0", TestTimer::new()).expect("J1939 socket on vcan0");
+            let raw = CanSocket::open("vcan0").expect("raw socket on vcan0");
+            (stack, raw)
+        }
+
+        fn write(raw: &CanSocket, id: u32, data: &[u8]) {
+            let frame = CanFrame::new(ExtendedId::new(id).unwrap(), data).unwrap();
+            raw.write_frame(&frame).unwrap();
+        }
+
+        /// Reads frames of the raw socket till a frame with the id is read
+        fn read(raw: &CanSocket, id: u32) -> Option<CanFrame> {
+            let id = Id::Extended(ExtendedId::new(id).unwrap());
+            while let Ok(frame) = raw.read_frame_timeout(Duration::from_millis(500)) {
+                if frame.id() == id {
+                    return Some(frame);
+                }
+            }
+            None
+        }
+
+        /// Processes the stack till a frame of the source address is received
+        fn receive(stack: &mut J1939Stack<TestTimer>, source_address: u8) -> Option<Frame> {
+            for _ in 0..500 {
+                stack.process().unwrap();
+                while let Some(frame) = stack.get_frame() {
+                    if frame.header().source_address() == source_address {
+                        return Some(frame);
+                    }
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            None
+        }
+
+        #[test]
+        #[ignore = "needs the interface vcan0 and the kernel module can-j1939"]
+        fn receive_frame() {
+            let (mut stack, raw) = open();
+            write(&raw, 0x18FEB221, &[1, 2, 3, 4, 5, 6, 7, 8]);
+            assert_eq!(
+                receive(&mut stack, 0x21),
+                Some(Frame::new(
+                    Header::new(PGN::new(0xFEB2), 6, 0x21, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 8]
+                ))
+            );
+        }
+
+        #[test]
+        #[ignore = "needs the interface vcan0 and the kernel module can-j1939"]
+        fn send_frame() {
+            let (mut stack, raw) = open();
+            let handle = stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB3), 6, 0x22, None),
+                    &[8, 7, 6, 5, 4, 3, 2, 1],
+                ))
+                .unwrap();
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::Completed)
+            );
+            assert_eq!(
+                read(&raw, 0x18FEB322).map(|frame| frame.data().to_vec()),
+                Some(vec![8, 7, 6, 5, 4, 3, 2, 1])
+            );
+        }
+
+        #[test]
+        #[ignore = "needs the interface vcan0 and the kernel module can-j1939"]
+        fn address_claim() {
+            let (mut stack, raw) = open();
+            let name = Name {
+                address_capable: false,
+                identity_number: 0x1939,
+                ..Name::default()
+            };
+            let handle = stack.register_control_function(0x23, name);
+            stack.process().unwrap();
+            let name_raw: u64 = name.into();
+            assert_eq!(
+                read(&raw, 0x18EEFF23).map(|frame| frame.data().to_vec()),
+                Some(name_raw.to_le_bytes().to_vec())
+            );
+            assert_eq!(stack.control_function(&handle).is_online(), None);
+
+            // the address claim of another ECU is monitored
+            let other: u64 = Name {
+                identity_number: 0x1940,
+                ..Name::default()
+            }
+            .into();
+            write(&raw, 0x18EEFF24, &other.to_le_bytes());
+            assert!(receive(&mut stack, 0x24).is_some());
+            assert_eq!(
+                stack.control_function_list().get(&0x24),
+                Some(&other.into())
+            );
+        }
+
+        #[test]
+        #[ignore = "needs the interface vcan0 and the kernel module can-j1939"]
+        fn cancel_transfer() {
+            let mut timer = TestTimer::new();
+            let mut stack = J1939Stack::new("vcan0", timer.clone()).expect("J1939 socket on vcan0");
+            let handle = stack.register_control_function(
+                0x25,
+                Name {
+                    address_capable: false,
+                    identity_number: 0x1941,
+                    ..Name::default()
+                },
+            );
+            stack.process().unwrap();
+            timer.set_time(300);
+            stack.process().unwrap();
+            let transfer = stack
+                .control_function(&handle)
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB4), 6, 0x25, None),
+                    &[1],
+                ))
+                .unwrap();
+            assert_eq!(stack.transfer_state(&transfer), Some(TransferState::Queued));
+            assert!(stack.cancel_transfer(&transfer));
+            // the cancelled frame is not handed to the kernel
+            stack.process().unwrap();
+            assert_eq!(
+                stack.transfer_state(&transfer),
+                Some(TransferState::Failed(TransferError::Cancelled))
+            );
+            assert!(!stack.cancel_transfer(&transfer));
+        }
+    }
+}
@@ -1,78 +1,131 @@
-use crate::address::AddressMonitor;
+use crate::address_pool::AddressPool;
+use crate::address_store::AddressStore;
+use crate::config::StackConfig;
 use crate::control_function::ControlFunction;
+use crate::driver::Driver;
+use crate::filter::FilterRule;
 use crate::frame::*;
 use crate::name::Name;
-use crate::transport::TransportManager;
+use crate::stack_core::StackCore;
+use crate::time::Instant;
+use crate::transport::{
+    TransferError, TransferHandle, TransferState, TransportEvent, TransportManager,
+};
+use crate::tx_queue::TxQueue;
+use crate::Error;
 use alloc::collections::BTreeMap;
-use alloc::vec::Vec;
-use crossbeam_queue::ArrayQueue;
 
 /// Represents a single J1939 stack
 /// The stack itself manages transport protocols
 /// get and setter for frames and source address based filtering is implemented.
 /// It is possible to register a `ControlFunction`, which handles address management and provides a pgn based filter utility
 /// The stacks process() functions must be called on a regular basis to perform internal long running tasks
-pub struct Stack<CanDriver: embedded_can::blocking::Can, TimeDriver: crate::time::TimerDriver> {
-    received_frames: ArrayQueue<Frame>,
-    accept_all_da: bool,
-    transport: TransportManager,
-    cf: Vec<ControlFunction<TimeDriver>>,
-    address_monitor: AddressMonitor,
+pub struct Stack<CanDriver: Driver, TimeDriver: crate::time::TimerDriver> {
+    /// control functions, address monitor and receive queue
+    core: StackCore<TimeDriver>,
+    transport: TransportManager<TimeDriver>,
+    /// frames waiting for the can driver
+    tx_queue: TxQueue,
     can_driver: CanDriver,
-    time: TimeDriver,
 }
 
-impl<CanDriver: embedded_can::blocking::Can, TimeDriver: Clone + crate::time::TimerDriver>
-    Stack<CanDriver, TimeDriver>
-{
+impl<CanDriver: Driver, TimeDriver: Clone + crate::time::TimerDriver> Stack<CanDriver, TimeDriver> {
     /// Creates a new Stack object, capturing the can and timer driver
     /// The standard configuration receives all broadcast frames
     pub fn new(can: CanDriver, time: TimeDriver) -> Self {
+        Self::new_with_config(can, time, StackConfig::default())
+    }
+    /// Creates a new Stack object, capturing the can and timer driver
+    /// The transport protocols are configured by the given [`StackConfig`]
+    pub fn new_with_config(can: CanDriver, time: TimeDriver, config: StackConfig) -> Self {
         Self {
-            received_frames: ArrayQueue::new(20),
-            accept_all_da: false,
-            transport: TransportManager::new(&[]),
-            cf: Vec::new(),
-            address_monitor: AddressMonitor::new(),
+            transport: TransportManager::new(&[], &config, time.clone()),
+            tx_queue: TxQueue::new(config.tx_queue_capacity, config.tx_queue_overflow),
+            core: StackCore::new(config, time),
             can_driver: can,
-            time,
         }
     }
     /// Creates a new Stack object, capturing the can and timer driver
     /// The standard configuration receives all broadcast frames
     pub fn new_with_nema2000(can: CanDriver, time: TimeDriver, pgns: &[PGN]) -> Self {
+        let config = StackConfig::default();
         Self {
-            received_frames: ArrayQueue::new(20),
-            accept_all_da: false,
-            transport: TransportManager::new(pgns),
-            cf: Vec::new(),
-            address_monitor: AddressMonitor::new(),
+            transport: TransportManager::new(pgns, &config, time.clone()),
+            tx_queue: TxQueue::new(config.tx_queue_capacity, config.tx_queue_overflow),
+            core: StackCore::new(config, time),
             can_driver: can,
-            time,
         }
     }
 
     /// run long running tasks like sending with transport protocol and address management
     /// should be called periodically
-    pub fn process(&mut self) {
-        while let Ok(frame) = self.can_driver.receive() {
-            self.push_can_frame(frame);
+    /// All tasks are processed even if one fails, the first error is returned
+    pub fn process(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+        loop {
+            match self.can_driver.receive() {
+                Ok(frame) => result = result.and(self.push_can_frame(frame)),
+                Err(nb::Error::WouldBlock) => break,
+                // e.g. a receive overrun, the remaining frames are received with the next call
+                Err(nb::Error::Other(error)) => {
+                    result = result.and(Err(error));
+                    break;
+                }
+            }
         }
 
         // check cf for ongoing work
-        self.process_control_functions();
+        result = result.and(self.process_control_functions());
 
         // handle ongoing transport protocol transactions
-        self.transport.process(&mut self.can_driver);
+        result = result.and(self.transport.process(&mut self.tx_queue));
+
+        // send all queued frames, frames which could not be sent are retried with the next call
+        result.and(self.transmit_queued())
+    }
+
+    /// Returns the can driver, used by the async stack to exchange frames
+    pub(crate) fn driver_mut(&mut self) -> &mut CanDriver {
+        &mut self.can_driver
+    }
+
+    /// Returns the next instant `process()` has work to do besides handling received frames,
+    /// e.g. a timeout of a transport session, a pending packet or an address claim
+    /// None is returned if the stack only waits for received frames
+    /// Allows to sleep till a frame is received or the deadline is reached instead of polling
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if !self.tx_queue.is_empty() {
+            return Some(Instant::from_ticks(0));
+        }
+        self.core
+            .next_deadline()
+            .into_iter()
+            .chain(self.transport.next_deadline())
+            .min()
+    }
+
+    /// Returns the number of frames and events dropped because a queue of the stack was full
+    /// The queues of the control functions report their overflows themselves
+    pub fn overflows(&self) -> Overflows {
+        Overflows {
+            received_frames: self.core.dropped_frames(),
+            transport_events: self.transport.dropped_events(),
+            transmit_frames: self.tx_queue.dropped(),
+        }
     }
 
     /// Provides a map with all ecus on the bus
     /// key is the source address, value the name of that ecu
     pub fn control_function_list(&self) -> &BTreeMap<u8, Name> {
-        self.address_monitor.control_function_list()
+        self.core.control_function_list()
     }
 
     // ---------------------- control functions ----------------------------------------------------
+    /// Sets the store of the last claimed addresses, which is used by control functions registered afterwards
+    /// A stored address of the NAME replaces the preferred address of `register_control_function`
+    pub fn set_address_store(&mut self, store: impl AddressStore + Send + 'static) {
+        self.core.set_address_store(store);
+    }
     /// Creates a new [`ControlFunction`] with a preferred address and Name
     /// The functions returns a [`ControlFunctionHandle`] which can be used to access the created `ControlFunction`
     pub fn register_control_function(
@@ -80,12 +133,18 @@ impl<CanDriver: embedded_can::blocking::Can, TimeDriver: Clone + crate::time::Ti
         preferred_address: u8,
         name: Name,
     ) -> ControlFunctionHandle {
-        self.cf.push(ControlFunction::new(
-            name,
-            preferred_address,
-            self.time.clone(),
-        ));
-        ControlFunctionHandle(self.cf.len() - 1)
+        self.register_control_function_with_pool(preferred_address, name, AddressPool::default())
+    }
+    /// Creates a new [`ControlFunction`] with a preferred address, Name and the [`AddressPool`]
+    /// searched for a free address if the preferred address is taken
+    pub fn register_control_function_with_pool(
+        &mut self,
+        preferred_address: u8,
+        name: Name,
+        address_pool: AddressPool,
+    ) -> ControlFunctionHandle {
+        self.core
+            .register_control_function(preferred_address, name, address_pool)
     }
     /// Returns a mutable reference of a [`ControlFunction`].
     /// `ControlsFunctions` are identified by a [`ControlFunctionHandle`]
@@ -93,26 +152,32 @@ impl<CanDriver: embedded_can::blocking::Can, TimeDriver: Clone + crate::time::Ti
         &mut self,
         handle: &ControlFunctionHandle,
     ) -> &mut ControlFunction<TimeDriver> {
-        &mut self.cf[handle.0]
+        self.core.control_function(handle)
     }
 
-    fn process_control_functions(&mut self) {
-        for cf_index in 0..self.cf.len() {
-            // check cf address management for ongoing transactions
-            self.cf[cf_index].process(&self.address_monitor);
-            // check cf send queues and move them into stack queue
-            while let Some(frame) = self.cf[cf_index].send_queue.pop() {
-                self.send_frame(frame.clone());
-                for receiver_index in 0..self.cf.len() {
-                    // Skip own message for control functions
-                    if cf_index == receiver_index {
-                        continue;
-                    }
-                    self.cf[receiver_index].handle_new_frame(&frame);
+    fn process_control_functions(&mut self) -> Result<(), Error> {
+        let now = self.core.time().now();
+        let transport = &mut self.transport;
+        let tx_queue = &mut self.tx_queue;
+        let result = self
+            .core
+            .process_control_functions(|address_monitor, _, frame, handle| {
+                if handle.is_some_and(|handle| {
+                    transport.transfer_state(&handle)
+                        == Some(TransferState::Failed(TransferError::Cancelled))
+                }) {
+                    return Ok(false);
                 }
-                self.handle_new_frame_stack(frame);
-            }
+                let offline =
+                    address_monitor.destination_offline(frame.header().destination_address(), now);
+                Self::transmit(transport, tx_queue, offline, frame.clone(), handle).map(|_| true)
+            });
+        // frames of transfers dropped by the address management of the control functions
+        for handle in self.core.take_dropped_transfers() {
+            self.transport
+                .set_transfer_state(handle, TransferState::Failed(TransferError::Dropped));
         }
+        result
     }
 
     // --------------------------- direct stack usage ----------------------------------------------
@@ -120,37 +185,158 @@ impl<CanDriver: embedded_can::blocking::Can, TimeDriver: Clone + crate::time::Ti
     /// Frames longer than 8 Bytes are already assembled
     /// By default only broadcast messages are received, to receive additional message the source address must be set using `set_accepted_sa`
     pub fn get_frame(&mut self) -> Option<Frame> {
-        self.received_frames.pop()
+        self.core.get_frame()
+    }
+    /// Returns the oldest event of the transport protocols (TP, BAM and ETP)
+    /// Events are reported for received and transmitted messages, the oldest events are dropped if they are not fetched
+    pub fn get_transport_event(&mut self) -> Option<TransportEvent> {
+        self.transport.get_event()
     }
     /// Send a J1939 Frame
     /// Control functions are strongly preferred to send frames
     /// Frames longer than 8 bytes are send by a transport protocol
     /// Frames are not loop backed to control functions!
-    pub fn send_frame(&mut self, frame: Frame) {
-        if frame.data().len() > 8 {
-            self.transport.send_frame(frame, &mut self.can_driver);
-        } else {
-            self.can_driver
-                .transmit(&frame.can())
-                .expect("Can Transmit Error!");
+    /// The returned [`TransferHandle`] is used to poll or cancel the transfer
+    /// Frames are sent immediately if the can driver accepts them, otherwise they wait in the transmit queue
+    /// Returns an error if the frame or the first frame of a transport protocol could not be queued
+    pub fn send_frame(&mut self, frame: Frame) -> Result<TransferHandle, Error> {
+        let handle = self.core.next_transfer_handle();
+        let offline = self
+            .core
+            .destination_offline(frame.header().destination_address());
+        Self::transmit(
+            &mut self.transport,
+            &mut self.tx_queue,
+            offline,
+            frame,
+            Some(handle),
+        )?;
+        // errors of the can driver are reported by the next process() call, which retries the transmit
+        let _ = self.transmit_queued();
+        Ok(handle)
+    }
+    /// Sends a commanded address, which assigns the address to the control function with the NAME
+    /// The command is sent with the given source address, e.g. the address of a service tool
+    pub fn send_address_command(
+        &mut self,
+        source_address: u8,
+        name: Name,
+        address: u8,
+    ) -> Result<TransferHandle, Error> {
+        self.send_frame(AddressCommand::new(name, address, source_address).into())
+    }
+    /// Returns the state of a transfer started by `send_frame` of the stack or a control function
+    /// None is returned for unknown transfers, the state of finished transfers is kept for the last 20 transfers
+    pub fn transfer_state(&self, handle: &TransferHandle) -> Option<TransferState> {
+        self.core
+            .transfer_state(handle, self.transport.transfer_state(handle))
+    }
+    /// Cancels a queued or active transfer, active peer to peer transport sessions are aborted
+    /// Returns false if the transfer is already finished or unknown
+    /// The transfer is cancelled even if the abort of the session could not be transmitted
+    pub fn cancel_transfer(&mut self, handle: &TransferHandle) -> Result<bool, Error> {
+        match self.transfer_state(handle) {
+            Some(TransferState::Queued) | Some(TransferState::InProgress) => {
+                if self.tx_queue.remove(handle)
+                    || !self
+                        .transport
+                        .cancel_transfer(*handle, &mut self.tx_queue)?
+                {
+                    // still in the send queue of the control function or the stack
+                    self.transport.set_transfer_state(
+                        *handle,
+                        TransferState::Failed(TransferError::Cancelled),
+                    );
+                }
+                // errors of the can driver are reported by the next process() call
+                let _ = self.transmit_queued();
+                Ok(true)
+            }
+            _ => Ok(false),
         }
     }
     /// Set if the stack accepts messages to all destination addresses
     /// If false broadcasts messages are accepted
     /// This has no effect for control functions
     pub fn set_accepted_all(&mut self, accept_all: bool) {
-        self.accept_all_da = accept_all;
+        self.core.set_accepted_all(accept_all);
+    }
+    /// Adds a rule to the receive filter of the stack, only frames matching one of the rules are received
+    /// This has no effect for control functions
+    pub fn add_filter(&mut self, rule: FilterRule) {
+        self.core.add_filter(rule);
+    }
+    /// Removes all rules of the receive filter of the stack, all frames are received again
+    pub fn clear_filter(&mut self) {
+        self.core.clear_filter();
     }
 
     // ------------------------private--------------------------------------------------------------
+    /// queue a frame directly or send it with a transport protocol
+    /// the transfer fails if the frame could not be queued or the destination is offline
+    fn transmit(
+        transport: &mut TransportManager<TimeDriver>,
+        tx_queue: &mut TxQueue,
+        destination_offline: bool,
+        frame: Frame,
+        handle: Option<TransferHandle>,
+    ) -> Result<(), Error> {
+        let result = if frame.data().len() > 8 {
+            if destination_offline {
+                if let Some(handle) = handle {
+                    transport.set_transfer_state(
+                        handle,
+                        TransferState::Failed(TransferError::DestinationOffline),
+                    );
+                }
+                return Ok(());
+            }
+            transport.send_frame(frame, handle, tx_queue)
+        } else {
+            let result = tx_queue.push_transfer(frame, handle);
+            if let (Some(handle), Ok(())) = (handle, result) {
+                transport.set_transfer_state(handle, TransferState::Queued);
+            }
+            result
+        };
+        if let (Some(handle), Err(error)) = (handle, result) {
+            transport.set_transfer_state(handle, TransferState::Failed(TransferError::Send(error)));
+        }
+        result
+    }
+
+    /// Removes a frame transmitted outside of the can driver from the transmit queue, e.g. by an async driver
+    /// The transfer of the frame is completed or failed by the result of the transmit
+    pub(crate) fn finish_transmit(&mut self, frame: &Frame, result: Result<(), Error>) {
+        let state = match result {
+            Ok(()) => TransferState::Completed,
+            Err(error) => TransferState::Failed(TransferError::Send(error)),
+        };
+        self.tx_queue.finish(frame, state);
+        // errors of the can driver are reported by the next process() call
+        let _ = self.transmit_queued();
+    }
+
+    /// transmit the queued frames, sent and dropped frames finish their transfers
+    fn transmit_queued(&mut self) -> Result<(), Error> {
+        let result = self.tx_queue.transmit(&mut self.can_driver);
+        for (handle, state) in self.tx_queue.take_finished() {
+            self.transport.set_transfer_state(handle, state);
+        }
+        result
+    }
+
     /// process a new incoming can frame
-    fn push_can_frame<CanFrame: embedded_can::Frame>(&mut self, frame: CanFrame) {
+    fn push_can_frame<CanFrame: embedded_can::Frame>(
+        &mut self,
+        frame: CanFrame,
+    ) -> Result<(), Error> {
         if let embedded_can::Id::Extended(eid) = frame.id() {
             let header: Header = eid.as_raw().into();
             // 1. check if the frame is addressed to me
             // broadcast or da == 0xFF or address of a registered control function
-            if !self.check_destination(header.destination_address()) {
-                return;
+            if !self.core.check_destination(header.destination_address()) {
+                return Ok(());
             }
             // 2. is it a transport protocol message?
             // yes -> handle transport protocol
@@ -158,58 +344,40 @@ impl<CanDriver: embedded_can::blocking::Can, TimeDriver: Clone + crate::time::Ti
             if self.transport.is_tp_frame(header.pgn()) {
                 if let Some(decoded_frame) =
                     self.transport
-                        .handle_frame(header, frame.data(), &mut self.can_driver)
+                        .handle_frame(header, frame.data(), &mut self.tx_queue)?
                 {
-                    self.handle_new_frame(decoded_frame);
+                    return self.core.handle_new_frame(decoded_frame);
                 }
             // just a normal message
             } else {
-                let frame = Frame::new(header, frame.data());
-                self.handle_new_frame(frame);
-            }
-        }
-    }
-
-    /// got a new j1939 frame decoded from can frames
-    fn handle_new_frame(&mut self, frame: Frame) {
-        // check if the new frame should be handled by the cf
-        for cf in &mut self.cf {
-            cf.handle_new_frame(&frame);
-        }
-        self.handle_new_frame_stack(frame);
-    }
-
-    fn handle_new_frame_stack(&mut self, frame: Frame) {
-        // check if the new frame is address related
-        if frame.header().pgn() == PGN_ADDRESSCLAIM || frame.header().pgn() == PGN_REQUEST {
-            self.address_monitor.handle_frame(&frame);
-        }
-        // check if the new frame should be handled by the stack
-        if let Some(da) = frame.header().destination_address() {
-            if !(da == 0xFF || self.accept_all_da) {
-                return;
+                let frame = Frame::new(header, frame.data())
+                    .with_timestamp(Timestamp::new(self.core.time().now()));
+                return self.core.handle_new_frame(frame);
             }
         }
-        self.received_frames.force_push(frame);
+        Ok(())
     }
+}
 
-    fn check_destination(&self, destination_address: Option<u8>) -> bool {
-        if let Some(da) = destination_address {
-            let cf_address = self.cf.iter().any(|cf| cf.is_online() == Some(da));
-
-            self.accept_all_da || cf_address || da == 0xFF
-        } else {
-            true
-        }
-    }
+/// Number of frames and events dropped since the stack was created, because a queue was full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Overflows {
+    /// received frames dropped by the receive queue of the stack
+    pub received_frames: u32,
+    /// events dropped by the transport event queue
+    pub transport_events: u32,
+    /// frames dropped by the transmit queue, see [`OverflowPolicy`](crate::config::OverflowPolicy)
+    pub transmit_frames: u32,
 }
 
 /// Handle to identify a control function
-pub struct ControlFunctionHandle(usize);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlFunctionHandle(pub(crate) usize);
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::driver::NbDriver;
     use crate::frame::Frame;
     use crate::test_utils::can_driver::TestDriver;
     use crate::test_utils::frame::TestFrame;
@@ -217,8 +385,11 @@ mod tests {
 
     mod address {
         use super::*;
-        use crate::control_function::AddressState;
+        use crate::address_store::MemoryAddressStore;
+        use crate::control_function::{AddressCommandPolicy, AddressEvent, AddressState};
+        use crate::filter::FilterRule;
         use crate::time::Instant;
+        use std::sync::{Arc, Mutex};
 
         #[test]
         fn response_to_addressclaim_request() {
@@ -236,7 +407,7 @@ mod tests {
                 *stack.control_function(&handle).address_state(),
                 AddressState::Preferred
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 32]))
@@ -246,14 +417,14 @@ mod tests {
                 AddressState::WaitForVeto(Instant::from_ticks(0))
             );
             timer.set_time(300);
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 *stack.control_function(&handle).address_state(),
                 AddressState::AddressClaimed
             );
             // till here we just setup our stack with a valid address
             driver.push_can_frame(TestFrame::new2(0x00EAFF80, &[0, 0xEE, 0]));
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 32]))
@@ -276,7 +447,7 @@ mod tests {
                 *stack.control_function(&handle).address_state(),
                 AddressState::Preferred
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 32]))
@@ -286,7 +457,7 @@ mod tests {
                 AddressState::WaitForVeto(Instant::from_ticks(0))
             );
             timer.set_time(300);
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 *stack.control_function(&handle).address_state(),
                 AddressState::AddressClaimed
@@ -294,7 +465,7 @@ mod tests {
         }
         #[test]
         fn control_function_address_claim_fixed_failed() {
-            let timer = TestTimer::new();
+            let mut timer = TestTimer::new();
             let mut driver = TestDriver::new();
             let mut stack = Stack::new(driver.clone(), timer.clone());
             let handle = stack.register_control_function(
@@ -308,7 +479,7 @@ mod tests {
                 *stack.control_function(&handle).address_state(),
                 AddressState::Preferred
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 32]))
@@ -318,11 +489,34 @@ mod tests {
                 AddressState::WaitForVeto(Instant::from_ticks(0))
             );
             driver.push_can_frame(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 1]));
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 *stack.control_function(&handle).address_state(),
                 AddressState::CannotClaim
             );
+            // the cannot claim address frame is delayed by a RTxD, which is deterministic for the NAME
+            assert_eq!(stack.next_deadline(), Some(Instant::from_ticks(67)));
+            timer.set_time(66);
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            timer.set_time(67);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x18EEFFFE, &[0, 0, 0, 0, 0, 255, 2, 32]))
+            );
+            assert_eq!(stack.next_deadline(), None);
+
+            // the response to a request for address claimed is delayed by the next RTxD
+            timer.set_time(1000);
+            driver.push_can_frame(TestFrame::new2(0x18EAFF80, &[0, 0xEE, 0]));
+            stack.process().unwrap();
+            assert_eq!(stack.next_deadline(), Some(Instant::from_ticks(1040)));
+            timer.set_time(1039);
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            timer.set_time(1040);
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x18EEFFFE, &[0, 0, 0, 0, 0, 255, 2, 32]))
@@ -341,7 +535,7 @@ mod tests {
                 *stack.control_function(&handle).address_state(),
                 AddressState::Preferred
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x0CEAFFFE, &[0, 238, 0]))
@@ -352,18 +546,63 @@ mod tests {
             );
             assert_eq!(driver.get_can_frame(), None);
             timer.set_time(1600);
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 *stack.control_function(&handle).address_state(),
                 AddressState::WaitForVeto(Instant::from_ticks(1600))
             );
             timer.set_time(1900);
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 *stack.control_function(&handle).address_state(),
                 AddressState::AddressClaimed
             );
         }
+        /// An address claim drops the oldest frame of the full send queue, its transfer fails
+        #[test]
+        fn control_function_send_queue_overflow() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new_with_config(
+                driver.clone(),
+                timer.clone(),
+                StackConfig::default().cf_tx_queue_capacity(2),
+            );
+            let cf_handle = stack.register_control_function(0x85, Name::default());
+            stack.process().unwrap();
+            timer.set_time(1600);
+            stack.process().unwrap();
+            timer.set_time(1900);
+            stack.process().unwrap();
+            while driver.get_can_frame().is_some() {}
+
+            let frame = Frame::new(Header::new(PGN::new(0xFEB0), 6, 0, None), &[1, 2, 3]);
+            let dropped = stack
+                .control_function(&cf_handle)
+                .send_frame(frame.clone())
+                .unwrap();
+            let sent = stack
+                .control_function(&cf_handle)
+                .send_frame(frame)
+                .unwrap();
+            // the response to the request for address claimed is queued before the frames are sent
+            driver.push_can_frame(TestFrame::new2(0x18EAFF80, &[0, 0xEE, 0]));
+            stack.process().unwrap();
+            assert_eq!(stack.control_function(&cf_handle).send_overflows(), 1);
+            assert_eq!(
+                stack.transfer_state(&dropped),
+                Some(TransferState::Failed(TransferError::Dropped))
+            );
+            assert_eq!(stack.transfer_state(&sent), Some(TransferState::Completed));
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x18FEB085, &[1, 2, 3]))
+            );
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 160]))
+            );
+        }
         /// Try to claim a address with a configurable address
         /// No response to the addressclaim request
         /// But after sending addressclaim an other addressclaim with higher priority is received
@@ -377,7 +616,7 @@ mod tests {
                 *stack.control_function(&handle).address_state(),
                 AddressState::Preferred
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x0CEAFFFE, &[0, 238, 0]))
@@ -388,7 +627,7 @@ mod tests {
             );
             assert_eq!(driver.get_can_frame(), None);
             timer.set_time(1600);
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 *stack.control_function(&handle).address_state(),
                 AddressState::WaitForVeto(Instant::from_ticks(1600))
@@ -398,7 +637,7 @@ mod tests {
                 Some(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 160]))
             );
             driver.push_can_frame(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 100]));
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 *stack.control_function(&handle).address_state(),
                 AddressState::WaitForVeto(Instant::from_ticks(1600))
@@ -421,7 +660,7 @@ mod tests {
                 *stack.control_function(&handle).address_state(),
                 AddressState::Preferred
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x0CEAFFFE, &[0, 238, 0]))
@@ -432,19 +671,19 @@ mod tests {
             );
             assert_eq!(driver.get_can_frame(), None);
             timer.set_time(1600);
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 *stack.control_function(&handle).address_state(),
                 AddressState::WaitForVeto(Instant::from_ticks(1600))
             );
             driver.push_can_frame(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 180]));
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 160]))
             );
             timer.set_time(1900);
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 *stack.control_function(&handle).address_state(),
                 AddressState::AddressClaimed
@@ -463,7 +702,7 @@ mod tests {
                 *stack.control_function(&handle).address_state(),
                 AddressState::Preferred
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x0CEAFFFE, &[0, 238, 0]))
@@ -475,205 +714,2052 @@ mod tests {
             assert_eq!(driver.get_can_frame(), None);
             driver.push_can_frame(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 100]));
             timer.set_time(1600);
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 *stack.control_function(&handle).address_state(),
                 AddressState::WaitForVeto(Instant::from_ticks(1600))
             );
             assert_eq!(
                 driver.get_can_frame(),
-                Some(TestFrame::new2(0x18EEFF7F, &[0, 0, 0, 0, 0, 255, 2, 160]))
+                Some(TestFrame::new2(0x18EEFF86, &[0, 0, 0, 0, 0, 255, 2, 160]))
             );
             assert_eq!(driver.get_can_frame(), None);
             timer.set_time(1900);
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 *stack.control_function(&handle).address_state(),
                 AddressState::AddressClaimed
             );
             assert_eq!(driver.get_can_frame(), None);
         }
-    }
 
-    mod transport {
-        use super::*;
+        /// The preferred address and the first address of the pool are taken
+        /// The pool is exhausted after the second address is lost
         #[test]
-        fn broadcast_rx_short() {
-            let timer = TestTimer::new();
+        fn control_function_address_pool() {
+            let mut timer = TestTimer::new();
             let mut driver = TestDriver::new();
             let mut stack = Stack::new(driver.clone(), timer.clone());
-            driver.push_can_frame(TestFrame::new2(0x00FEB201, &[1, 2, 3, 4, 5, 6, 7, 8]));
-            stack.process();
+            let handle = stack.register_control_function_with_pool(
+                0x85,
+                Name::default(),
+                AddressPool::new(&[0x90, 0x91]),
+            );
+            stack.process().unwrap();
             assert_eq!(
-                stack.get_frame(),
-                Some(Frame::new(
-                    Header::new(PGN::new(0xFEB2), 0, 0x01, None),
-                    &[1, 2, 3, 4, 5, 6, 7, 8]
-                ))
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x0CEAFFFE, &[0, 238, 0]))
+            );
+            driver.push_can_frame(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 100]));
+            driver.push_can_frame(TestFrame::new2(0x18EEFF90, &[0, 0, 0, 0, 0, 255, 2, 101]));
+            timer.set_time(1600);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x18EEFF91, &[0, 0, 0, 0, 0, 255, 2, 160]))
+            );
+            // the address is lost to a control function with a higher priority
+            driver.push_can_frame(TestFrame::new2(0x18EEFF91, &[0, 0, 0, 0, 0, 255, 2, 102]));
+            stack.process().unwrap();
+            assert_eq!(
+                *stack.control_function(&handle).address_state(),
+                AddressState::CannotClaim
             );
+            let cf = stack.control_function(&handle);
+            assert_eq!(cf.get_address_event(), Some(AddressEvent::CannotClaim));
+            assert_eq!(cf.get_address_event(), None);
         }
 
+        /// The claimed address is lost and an other address of the pool is claimed
         #[test]
-        fn broadcast_rx_long() {
-            let timer = TestTimer::new();
+        fn control_function_address_events() {
+            let mut timer = TestTimer::new();
             let mut driver = TestDriver::new();
             let mut stack = Stack::new(driver.clone(), timer.clone());
-            driver.push_can_frame(TestFrame::new2(
-                0x00ECFF01,
-                &[32, 20, 0, 3, 255, 0xB0, 0xFE, 0],
-            ));
-            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[1, 1, 2, 3, 4, 5, 6, 7]));
-            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[2, 1, 2, 3, 4, 5, 6, 7]));
-            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[3, 1, 2, 3, 4, 5, 6, 255]));
-            stack.process();
+            let handle = stack.register_control_function(0x85, Name::default());
+            stack.process().unwrap();
+            timer.set_time(1600);
+            stack.process().unwrap();
+            assert_eq!(stack.control_function(&handle).get_address_event(), None);
+            timer.set_time(1900);
+            stack.process().unwrap();
             assert_eq!(
-                stack.get_frame(),
-                Some(Frame::new(
-                    Header::new(PGN::new(0xFEB0), 0, 0x01, Some(255)),
-                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6]
-                ))
+                stack.control_function(&handle).get_address_event(),
+                Some(AddressEvent::Claimed(0x85))
             );
-        }
 
-        #[test]
-        fn p2p_rx_short() {
-            let timer = TestTimer::new();
-            let mut driver = TestDriver::new();
-            let mut stack = Stack::new(driver.clone(), timer.clone());
-            stack.set_accepted_all(true);
-            driver.push_can_frame(TestFrame::new2(0x00DC2080, &[1, 2, 3, 4, 5, 6, 7, 8]));
-            stack.process();
+            driver.push_can_frame(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 100]));
+            stack.process().unwrap();
+            assert_eq!(stack.control_function(&handle).is_online(), None);
             assert_eq!(
-                stack.get_frame(),
-                Some(Frame::new(
-                    Header::new(PGN::new(0xDC00), 0, 0x80, Some(0x20)),
-                    &[1, 2, 3, 4, 5, 6, 7, 8]
-                ))
+                stack.control_function(&handle).get_address_event(),
+                Some(AddressEvent::Lost(0x85))
             );
+            timer.set_time(2200);
+            stack.process().unwrap();
+            assert_eq!(stack.control_function(&handle).is_online(), Some(0x86));
+            assert_eq!(
+                stack.control_function(&handle).get_address_event(),
+                Some(AddressEvent::Moved {
+                    from: 0x85,
+                    to: 0x86
+                })
+            );
+            assert_eq!(stack.control_function(&handle).get_address_event(), None);
         }
+
+        /// The oldest address event is dropped and counted if the event queue is full
         #[test]
-        fn p2p_rx_short_without_address() {
-            let timer = TestTimer::new();
+        fn control_function_address_event_overflow() {
+            let mut timer = TestTimer::new();
             let mut driver = TestDriver::new();
-            let mut stack = Stack::new(driver.clone(), timer.clone());
-            driver.push_can_frame(TestFrame::new2(0x00DC2001, &[1, 2, 3, 4, 5, 6, 7, 8]));
-            stack.process();
-            assert_eq!(stack.get_frame(), None);
+            let mut stack = Stack::new_with_config(
+                driver.clone(),
+                timer.clone(),
+                StackConfig::default().cf_address_event_queue_capacity(1),
+            );
+            let handle = stack.register_control_function(0x85, Name::default());
+            stack.process().unwrap();
+            timer.set_time(1600);
+            stack.process().unwrap();
+            timer.set_time(1900);
+            stack.process().unwrap();
+            assert_eq!(stack.control_function(&handle).address_event_overflows(), 0);
+
+            driver.push_can_frame(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 100]));
+            stack.process().unwrap();
+            timer.set_time(2200);
+            stack.process().unwrap();
+            assert_eq!(stack.control_function(&handle).address_event_overflows(), 2);
+            assert_eq!(
+                stack.control_function(&handle).get_address_event(),
+                Some(AddressEvent::Moved {
+                    from: 0x85,
+                    to: 0x86
+                })
+            );
+            assert_eq!(stack.control_function(&handle).get_address_event(), None);
+        }
+
+        /// Address store shared with the test, storing fails while the flag is set
+        #[derive(Clone, Default)]
+        struct SharedStore(Arc<Mutex<MemoryAddressStore>>, Arc<Mutex<bool>>);
+
+        impl AddressStore for SharedStore {
+            fn load(&self, name: Name) -> Option<u8> {
+                self.0.lock().unwrap().load(name)
+            }
+            fn store(&mut self, name: Name, address: u8) -> Result<(), Error> {
+                if *self.1.lock().unwrap() {
+                    return Err(Error::AddressStore);
+                }
+                self.0.lock().unwrap().store(name, address)
+            }
         }
+
         #[test]
-        fn p2p_rx_short_wrong_address() {
-            let timer = TestTimer::new();
-            let mut driver = TestDriver::new();
+        fn control_function_address_store_retry() {
+            let mut timer = TestTimer::new();
+            let driver = TestDriver::new();
             let mut stack = Stack::new(driver.clone(), timer.clone());
-            driver.push_can_frame(TestFrame::new2(0x00DC2001, &[1, 2, 3, 4, 5, 6, 7, 8]));
-            stack.process();
-            assert_eq!(stack.get_frame(), None);
+            let store = SharedStore::default();
+            *store.1.lock().unwrap() = true;
+            stack.set_address_store(store.clone());
+            let name = Name {
+                address_capable: false,
+                ..Name::default()
+            };
+            let handle = stack.register_control_function(0x85, name);
+            stack.process().unwrap();
+            timer.set_time(300);
+            assert_eq!(stack.process(), Err(Error::AddressStore));
+            assert_eq!(stack.control_function(&handle).is_online(), Some(0x85));
+            // the address is stored again till the store succeeds
+            assert_eq!(stack.process(), Err(Error::AddressStore));
+            *store.1.lock().unwrap() = false;
+            stack.process().unwrap();
+            assert_eq!(store.load(name), Some(0x85));
+            stack.process().unwrap();
         }
+
+        /// The stored address is claimed instead of the preferred address and updated after the address moved
         #[test]
-        fn p2p_rx_long() {
-            let timer = TestTimer::new();
+        fn control_function_address_store() {
+            let mut timer = TestTimer::new();
             let mut driver = TestDriver::new();
             let mut stack = Stack::new(driver.clone(), timer.clone());
-            stack.set_accepted_all(true);
-            driver.push_can_frame(TestFrame::new2(0x00EC0201, &[16, 20, 0, 3, 1, 176, 254, 0]));
-            stack.process();
+            let store = SharedStore::default();
+            store.clone().store(Name::default(), 0x90).unwrap();
+            stack.set_address_store(store.clone());
+            let handle = stack.register_control_function(0x85, Name::default());
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
-                Some(TestFrame::new2(
-                    0x1CEC0102,
-                    &[17, 1, 1, 255, 255, 176, 254, 0]
-                ))
+                Some(TestFrame::new2(0x0CEAFFFE, &[0, 238, 0]))
             );
-            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[1, 1, 2, 3, 4, 5, 6, 7]));
-            stack.process();
+            timer.set_time(1600);
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
-                Some(TestFrame::new2(
-                    0x1CEC0102,
-                    &[17, 1, 2, 255, 255, 176, 254, 0]
-                ))
+                Some(TestFrame::new2(0x18EEFF90, &[0, 0, 0, 0, 0, 255, 2, 160]))
             );
-            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[2, 1, 2, 3, 4, 5, 6, 7]));
-            stack.process();
-            assert_eq!(
-                driver.get_can_frame(),
-                Some(TestFrame::new2(
-                    0x1CEC0102,
-                    &[17, 1, 3, 255, 255, 176, 254, 0]
-                ))
+            timer.set_time(1900);
+            stack.process().unwrap();
+            assert_eq!(stack.control_function(&handle).is_online(), Some(0x90));
+
+            driver.push_can_frame(TestFrame::new2(0x18EEFF90, &[0, 0, 0, 0, 0, 255, 2, 100]));
+            stack.process().unwrap();
+            // the lost address is kept till an other address is claimed
+            assert_eq!(store.load(Name::default()), Some(0x90));
+            timer.set_time(2200);
+            stack.process().unwrap();
+            assert_eq!(stack.control_function(&handle).is_online(), Some(0x91));
+            assert_eq!(store.load(Name::default()), Some(0x91));
+        }
+
+        #[test]
+        fn control_function_name_conflict() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            let handle = stack.register_control_function(
+                0x85,
+                Name {
+                    address_capable: false,
+                    ..Name::default()
+                },
             );
-            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[3, 1, 2, 3, 4, 5, 55, 255]));
-            stack.process();
+            stack.process().unwrap();
+            timer.set_time(300);
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
-                Some(TestFrame::new2(
-                    0x1CEC0102,
-                    &[19, 20, 0, 3, 255, 176, 254, 0]
-                ))
+                Some(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 32]))
             );
+            // another control function claims our address with the same NAME
+            driver.push_can_frame(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 32]));
+            assert_eq!(stack.process(), Err(Error::NameConflict));
             assert_eq!(
-                stack.get_frame(),
-                Some(Frame::new(
-                    Header::new(PGN::new(0xFEB0), 0, 0x01, Some(0x2)),
-                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 55]
-                ))
+                *stack.control_function(&handle).address_state(),
+                AddressState::AddressClaimed
             );
-            stack.process();
             assert_eq!(driver.get_can_frame(), None);
-            assert_eq!(stack.get_frame(), None);
         }
+
         #[test]
-        fn p2p_rx_long_abort_already_connected() {
+        fn malformed_request() {
             let timer = TestTimer::new();
             let mut driver = TestDriver::new();
             let mut stack = Stack::new(driver.clone(), timer.clone());
-            stack.set_accepted_all(true);
-            driver.push_can_frame(TestFrame::new2(0x00EC0201, &[16, 20, 0, 3, 1, 176, 254, 0]));
-            stack.process();
-            assert_eq!(
-                driver.get_can_frame(),
-                Some(TestFrame::new2(
-                    0x1CEC0102,
-                    &[17, 1, 1, 255, 255, 176, 254, 0]
-                ))
-            );
-            driver.push_can_frame(TestFrame::new2(0x00EC0201, &[16, 20, 0, 3, 1, 176, 254, 0]));
-            stack.process();
+            driver.push_can_frame(TestFrame::new2(0x00EAFF80, &[0, 0xEE]));
+            driver.push_can_frame(TestFrame::new2(0x00FEB221, &[1, 2, 3, 4, 5, 6, 7, 8]));
+            assert_eq!(stack.process(), Err(Error::MalformedFrame));
+            // the following frames are still received
+            assert_eq!(stack.get_frame().unwrap().header().pgn(), PGN_REQUEST);
             assert_eq!(
-                driver.get_can_frame(),
-                Some(TestFrame::new2(
-                    0x1CEC0102,
-                    &[255, 1, 255, 255, 255, 176, 254, 0]
+                stack.get_frame(),
+                Some(Frame::new(
+                    Header::new(PGN::new(0xFEB2), 0, 0x21, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 8]
                 ))
             );
-            stack.process();
-            assert_eq!(driver.get_can_frame(), None)
+            stack.process().unwrap();
+        }
+
+        /// Commanded address of a service tool at 0x80 to 0x90, sent by BAM
+        fn push_address_command(driver: &mut TestDriver) {
+            driver.push_can_frame(TestFrame::new2(
+                0x18ECFF80,
+                &[32, 9, 0, 2, 255, 0xD8, 0xFE, 0],
+            ));
+            driver.push_can_frame(TestFrame::new2(0x1CEBFF80, &[1, 0, 0, 0, 0, 0, 255, 2]));
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEBFF80,
+                &[2, 32, 0x90, 255, 255, 255, 255, 255],
+            ));
         }
+
         #[test]
-        fn broadcast_tx_short() {
-            let timer = TestTimer::new();
+        fn control_function_address_command() {
+            let mut timer = TestTimer::new();
             let mut driver = TestDriver::new();
             let mut stack = Stack::new(driver.clone(), timer.clone());
-            stack.send_frame(Frame::new(
-                Header::new(PGN::new(0xFEB2), 0, 0x21, None),
-                &[1, 2, 3, 4, 5, 6, 7, 8],
-            ));
-            stack.process();
-            assert_eq!(
-                driver.get_can_frame(),
+            let handle = stack.register_control_function(
+                0x85,
+                Name {
+                    address_capable: false,
+                    ..Name::default()
+                },
+            );
+            stack.process().unwrap();
+            timer.set_time(300);
+            stack.process().unwrap();
+            driver.get_can_frame();
+
+            // commanded addresses are rejected by default
+            push_address_command(&mut driver);
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            assert_eq!(stack.control_function(&handle).is_online(), Some(0x85));
+
+            // the command is not sent by the accepted service tool
+            stack.control_function(&handle).set_address_command_policy(
+                AddressCommandPolicy::AcceptMatching(FilterRule::new().source_address(0x81)),
+            );
+            push_address_command(&mut driver);
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+
+            stack
+                .control_function(&handle)
+                .set_address_command_policy(AddressCommandPolicy::Accept);
+            push_address_command(&mut driver);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x18EEFF90, &[0, 0, 0, 0, 0, 255, 2, 32]))
+            );
+            assert_eq!(
+                *stack.control_function(&handle).address_state(),
+                AddressState::WaitForVeto(Instant::from_ticks(300))
+            );
+            timer.set_time(600);
+            stack.process().unwrap();
+            assert_eq!(stack.control_function(&handle).is_online(), Some(0x90));
+            let cf = stack.control_function(&handle);
+            assert_eq!(cf.get_address_event(), Some(AddressEvent::Claimed(0x85)));
+            assert_eq!(
+                cf.get_address_event(),
+                Some(AddressEvent::Moved {
+                    from: 0x85,
+                    to: 0x90
+                })
+            );
+            assert_eq!(stack.control_function(&handle).get_frame(), None);
+        }
+
+        #[test]
+        fn control_function_address_command_p2p() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            let handle = stack.register_control_function(
+                0x85,
+                Name {
+                    address_capable: false,
+                    ..Name::default()
+                },
+            );
+            stack
+                .control_function(&handle)
+                .set_address_command_policy(AddressCommandPolicy::Accept);
+            stack.process().unwrap();
+            timer.set_time(300);
+            stack.process().unwrap();
+            driver.get_can_frame();
+
+            // the service tool sends the command to the address of the control function
+            driver.push_can_frame(TestFrame::new2(
+                0x18EC8580,
+                &[16, 9, 0, 2, 255, 0xD8, 0xFE, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC8085,
+                    &[17, 2, 1, 255, 255, 0xD8, 0xFE, 0]
+                ))
+            );
+            driver.push_can_frame(TestFrame::new2(0x1CEB8580, &[1, 0, 0, 0, 0, 0, 255, 2]));
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEB8580,
+                &[2, 32, 0x90, 255, 255, 255, 255, 255],
+            ));
+            stack.process().unwrap();
+            // the claim of the commanded address has a higher priority than the acknowledgement
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x18EEFF90, &[0, 0, 0, 0, 0, 255, 2, 32]))
+            );
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC8085,
+                    &[19, 9, 0, 2, 255, 0xD8, 0xFE, 0]
+                ))
+            );
+            timer.set_time(600);
+            stack.process().unwrap();
+            assert_eq!(stack.control_function(&handle).is_online(), Some(0x90));
+            assert_eq!(stack.control_function(&handle).get_frame(), None);
+        }
+
+        #[test]
+        fn send_address_command() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            let name = Name {
+                address_capable: false,
+                ..Name::default()
+            };
+            stack.send_address_command(0x80, name, 0x90).unwrap();
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CECFF80,
+                    &[32, 9, 0, 2, 255, 0xD8, 0xFE, 0]
+                ))
+            );
+            timer.set_time(50);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEBFF80, &[1, 0, 0, 0, 0, 0, 255, 2]))
+            );
+            timer.set_time(100);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEBFF80,
+                    &[2, 32, 0x90, 255, 255, 255, 255, 255]
+                ))
+            );
+        }
+    }
+
+    mod receive {
+        use super::*;
+        use crate::filter::FilterRule;
+        use crate::frame::AckType;
+
+        #[test]
+        fn control_function_filter() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            let handle = stack.register_control_function(
+                0x85,
+                Name {
+                    address_capable: false,
+                    ..Name::default()
+                },
+            );
+            stack.process().unwrap();
+            timer.set_time(300);
+            stack.process().unwrap();
+            driver.get_can_frame();
+            let source = Name {
+                identity_number: 0x1234,
+                ..Name::default()
+            };
+            stack
+                .control_function(&handle)
+                .add_filter(FilterRule::new().pgn(PGN::new(0xF004)).source_name(source));
+            stack
+                .control_function(&handle)
+                .add_filter(FilterRule::new().source_address(0x22));
+
+            let name_raw: u64 = source.into();
+            driver.push_can_frame(TestFrame::new2(0x18EEFF21, &name_raw.to_le_bytes()));
+            driver.push_can_frame(TestFrame::new2(0x0CF00421, &[1; 8]));
+            driver.push_can_frame(TestFrame::new2(0x0CF00321, &[2; 8]));
+            driver.push_can_frame(TestFrame::new2(0x0CF00423, &[3; 8]));
+            driver.push_can_frame(TestFrame::new2(0x18EF8522, &[4; 8]));
+            stack.process().unwrap();
+            let cf = stack.control_function(&handle);
+            assert_eq!(cf.get_frame().unwrap().data(), &[1; 8]);
+            assert_eq!(cf.get_frame().unwrap().data(), &[4; 8]);
+            assert_eq!(cf.get_frame(), None);
+
+            // the stack is not filtered
+            assert!(core::iter::from_fn(|| stack.get_frame()).any(|frame| frame.data() == [2; 8]));
+
+            stack.control_function(&handle).clear_filter();
+            driver.push_can_frame(TestFrame::new2(0x0CF00321, &[2; 8]));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.control_function(&handle).get_frame().unwrap().data(),
+                &[2; 8]
+            );
+        }
+
+        #[test]
+        fn control_function_handler() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            let handle = stack.register_control_function(
+                0x85,
+                Name {
+                    address_capable: false,
+                    ..Name::default()
+                },
+            );
+            stack.process().unwrap();
+            timer.set_time(300);
+            stack.process().unwrap();
+            driver.get_can_frame();
+            let cf = stack.control_function(&handle);
+            cf.add_request_handler(PGN::new(0xFF01), |_, responder| {
+                responder.reply(PGN::new(0xFF01), &[1, 2, 3, 4, 5, 6, 7, 8])
+            });
+            cf.add_handler(PGN::new(0xEF00), |frame, responder| {
+                assert_eq!(frame.data(), &[4; 8]);
+                responder.ack(AckType::PositiveAck, None, PGN::new(0xEF00))
+            });
+
+            // request of the status
+            driver.push_can_frame(TestFrame::new2(0x18EA8521, &[0x01, 0xFF, 0x00]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x18FF0185, &[1, 2, 3, 4, 5, 6, 7, 8]))
+            );
+            // the response is sent to the requester
+            driver.push_can_frame(TestFrame::new2(0x18EF8522, &[4; 8]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x0CE82285,
+                    &[0, 0xFF, 0xFF, 0xFF, 0x22, 0x00, 0xEF, 0x00]
+                ))
+            );
+            // handled frames are not received
+            assert_eq!(stack.control_function(&handle).get_frame(), None);
+
+            stack
+                .control_function(&handle)
+                .remove_handlers(PGN::new(0xEF00));
+            driver.push_can_frame(TestFrame::new2(0x18EF8522, &[4; 8]));
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            assert_eq!(
+                stack.control_function(&handle).get_frame().unwrap().data(),
+                &[4; 8]
+            );
+        }
+
+        #[test]
+        fn stack_filter() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.add_filter(FilterRule::new().pgn(PGN::new(0xF004)));
+            driver.push_can_frame(TestFrame::new2(0x0CF00421, &[1; 8]));
+            driver.push_can_frame(TestFrame::new2(0x0CF00321, &[2; 8]));
+            stack.process().unwrap();
+            assert_eq!(stack.get_frame().unwrap().data(), &[1; 8]);
+            assert_eq!(stack.get_frame(), None);
+
+            stack.clear_filter();
+            driver.push_can_frame(TestFrame::new2(0x0CF00321, &[2; 8]));
+            stack.process().unwrap();
+            assert_eq!(stack.get_frame().unwrap().data(), &[2; 8]);
+        }
+    }
+
+    mod transport {
+        use super::*;
+        use crate::time::Duration;
+        use crate::transport::{
+            AbortReason, TransferError, TransferState, TransportDirection, TransportSession,
+        };
+        #[test]
+        fn broadcast_rx_short() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            driver.push_can_frame(TestFrame::new2(0x00FEB201, &[1, 2, 3, 4, 5, 6, 7, 8]));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.get_frame(),
+                Some(Frame::new(
+                    Header::new(PGN::new(0xFEB2), 0, 0x01, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 8]
+                ))
+            );
+        }
+
+        #[test]
+        fn broadcast_rx_long() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            driver.push_can_frame(TestFrame::new2(
+                0x00ECFF01,
+                &[32, 20, 0, 3, 255, 0xB0, 0xFE, 0],
+            ));
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[1, 1, 2, 3, 4, 5, 6, 7]));
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[2, 1, 2, 3, 4, 5, 6, 7]));
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[3, 1, 2, 3, 4, 5, 6, 255]));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.get_frame(),
+                Some(Frame::new(
+                    Header::new(PGN::new(0xFEB0), 0, 0x01, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6]
+                ))
+            );
+        }
+
+        #[test]
+        fn broadcast_rx_timestamp() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            timer.set_time(100);
+            driver.push_can_frame(TestFrame::new2(0x00FEB201, &[1, 2, 3, 4, 5, 6, 7, 8]));
+            driver.push_can_frame(TestFrame::new2(
+                0x00ECFF01,
+                &[32, 20, 0, 3, 255, 0xB0, 0xFE, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.get_frame().unwrap().timestamp(),
+                Some(Timestamp::new(Instant::from_ticks(100)))
+            );
+            timer.set_time(150);
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[1, 1, 2, 3, 4, 5, 6, 7]));
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[2, 1, 2, 3, 4, 5, 6, 7]));
+            stack.process().unwrap();
+            timer.set_time(200);
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[3, 1, 2, 3, 4, 5, 6, 255]));
+            stack.process().unwrap();
+            // the reassembled frame is stamped with the time of the BAM and the last packet
+            assert_eq!(
+                stack.get_frame().unwrap().timestamp(),
+                Some(Timestamp::transfer(
+                    Instant::from_ticks(100),
+                    Instant::from_ticks(200)
+                ))
+            );
+        }
+
+        #[test]
+        fn broadcast_rx_long_priority_pdu1() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            driver.push_can_frame(TestFrame::new2(
+                0x18ECFF01,
+                &[32, 9, 0, 2, 255, 0x00, 0xE7, 0],
+            ));
+            driver.push_can_frame(TestFrame::new2(0x1CEBFF01, &[1, 1, 2, 3, 4, 5, 6, 7]));
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEBFF01,
+                &[2, 8, 9, 255, 255, 255, 255, 255],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.get_frame(),
+                Some(Frame::new(
+                    Header::new(PGN::new(0xE700), 6, 0x01, Some(255)),
+                    &[1, 2, 3, 4, 5, 6, 7, 8, 9]
+                ))
+            );
+        }
+
+        #[test]
+        fn broadcast_rx_long_restart() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            driver.push_can_frame(TestFrame::new2(
+                0x00ECFF01,
+                &[32, 20, 0, 3, 255, 0xB0, 0xFE, 0],
+            ));
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[1, 1, 2, 3, 4, 5, 6, 7]));
+            // a new broadcast of the same source address replaces the unfinished one
+            driver.push_can_frame(TestFrame::new2(
+                0x00ECFF01,
+                &[32, 9, 0, 2, 255, 0xB1, 0xFE, 0],
+            ));
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[1, 1, 2, 3, 4, 5, 6, 7]));
+            driver.push_can_frame(TestFrame::new2(
+                0x00EBFF01,
+                &[2, 8, 9, 255, 255, 255, 255, 255],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.get_frame(),
+                Some(Frame::new(
+                    Header::new(PGN::new(0xFEB1), 0, 0x01, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 8, 9]
+                ))
+            );
+            assert_eq!(stack.get_frame(), None);
+        }
+
+        #[test]
+        fn broadcast_rx_long_invalid_announcement() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            // 65535 bytes exceed the transport protocol, the sequence number would overflow
+            driver.push_can_frame(TestFrame::new2(
+                0x00ECFF01,
+                &[32, 0xFF, 0xFF, 255, 255, 0xB0, 0xFE, 0],
+            ));
+            for sequence_number in 1..=256u16 {
+                driver.push_can_frame(TestFrame::new2(
+                    0x00EBFF01,
+                    &[sequence_number as u8, 1, 2, 3, 4, 5, 6, 7],
+                ));
+                stack.process().unwrap();
+            }
+            assert_eq!(stack.get_frame(), None);
+            // the packet count does not match the message size
+            driver.push_can_frame(TestFrame::new2(
+                0x00ECFF01,
+                &[32, 20, 0, 4, 255, 0xB0, 0xFE, 0],
+            ));
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[1, 1, 2, 3, 4, 5, 6, 7]));
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[2, 1, 2, 3, 4, 5, 6, 7]));
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[3, 1, 2, 3, 4, 5, 6, 255]));
+            stack.process().unwrap();
+            assert_eq!(stack.get_frame(), None);
+        }
+
+        #[test]
+        fn p2p_rx_short() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            driver.push_can_frame(TestFrame::new2(0x00DC2080, &[1, 2, 3, 4, 5, 6, 7, 8]));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.get_frame(),
+                Some(Frame::new(
+                    Header::new(PGN::new(0xDC00), 0, 0x80, Some(0x20)),
+                    &[1, 2, 3, 4, 5, 6, 7, 8]
+                ))
+            );
+        }
+        #[test]
+        fn p2p_rx_short_without_address() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            driver.push_can_frame(TestFrame::new2(0x00DC2001, &[1, 2, 3, 4, 5, 6, 7, 8]));
+            stack.process().unwrap();
+            assert_eq!(stack.get_frame(), None);
+        }
+        #[test]
+        fn p2p_rx_short_wrong_address() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            driver.push_can_frame(TestFrame::new2(0x00DC2001, &[1, 2, 3, 4, 5, 6, 7, 8]));
+            stack.process().unwrap();
+            assert_eq!(stack.get_frame(), None);
+        }
+        #[test]
+        fn p2p_rx_long() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            driver.push_can_frame(TestFrame::new2(0x00EC0201, &[16, 20, 0, 3, 1, 176, 254, 0]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[17, 1, 1, 255, 255, 176, 254, 0]
+                ))
+            );
+            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[1, 1, 2, 3, 4, 5, 6, 7]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[17, 1, 2, 255, 255, 176, 254, 0]
+                ))
+            );
+            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[2, 1, 2, 3, 4, 5, 6, 7]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[17, 1, 3, 255, 255, 176, 254, 0]
+                ))
+            );
+            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[3, 1, 2, 3, 4, 5, 55, 255]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[19, 20, 0, 3, 255, 176, 254, 0]
+                ))
+            );
+            assert_eq!(
+                stack.get_frame(),
+                Some(Frame::new(
+                    Header::new(PGN::new(0xFEB0), 0, 0x01, Some(0x2)),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 55]
+                ))
+            );
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            assert_eq!(stack.get_frame(), None);
+        }
+        #[test]
+        fn p2p_rx_long_abort_already_connected() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            driver.push_can_frame(TestFrame::new2(0x00EC0201, &[16, 20, 0, 3, 1, 176, 254, 0]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[17, 1, 1, 255, 255, 176, 254, 0]
+                ))
+            );
+            driver.push_can_frame(TestFrame::new2(0x00EC0201, &[16, 20, 0, 3, 1, 176, 254, 0]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[255, 1, 255, 255, 255, 176, 254, 0]
+                ))
+            );
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None)
+        }
+        /// The last packet of a message of 8 or 15 bytes contains a single byte
+        #[test]
+        fn broadcast_rx_one_byte_last_packet() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            driver.push_can_frame(TestFrame::new2(
+                0x00ECFF01,
+                &[32, 8, 0, 2, 255, 0xB0, 0xFE, 0],
+            ));
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[1, 1, 2, 3, 4, 5, 6, 7]));
+            driver.push_can_frame(TestFrame::new2(
+                0x00EBFF01,
+                &[2, 8, 255, 255, 255, 255, 255, 255],
+            ));
+            driver.push_can_frame(TestFrame::new2(
+                0x00ECFF02,
+                &[32, 15, 0, 3, 255, 0xB0, 0xFE, 0],
+            ));
+            driver.push_can_frame(TestFrame::new2(0x00EBFF02, &[1, 1, 2, 3, 4, 5, 6, 7]));
+            driver.push_can_frame(TestFrame::new2(0x00EBFF02, &[2, 8, 9, 10, 11, 12, 13, 14]));
+            driver.push_can_frame(TestFrame::new2(
+                0x00EBFF02,
+                &[3, 15, 255, 255, 255, 255, 255, 255],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.get_frame(),
+                Some(Frame::new(
+                    Header::new(PGN::new(0xFEB0), 0, 0x01, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 8]
+                ))
+            );
+            assert_eq!(
+                stack.get_frame(),
+                Some(Frame::new(
+                    Header::new(PGN::new(0xFEB0), 0, 0x02, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+                ))
+            );
+        }
+
+        #[test]
+        fn p2p_rx_one_byte_last_packet() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            driver.push_can_frame(TestFrame::new2(
+                0x00EC0201,
+                &[16, 15, 0, 3, 255, 176, 254, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[17, 3, 1, 255, 255, 176, 254, 0]
+                ))
+            );
+            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[1, 1, 2, 3, 4, 5, 6, 7]));
+            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[2, 8, 9, 10, 11, 12, 13, 14]));
+            driver.push_can_frame(TestFrame::new2(
+                0x00EB0201,
+                &[3, 15, 255, 255, 255, 255, 255, 255],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[19, 15, 0, 3, 255, 176, 254, 0]
+                ))
+            );
+            assert_eq!(
+                stack.get_frame(),
+                Some(Frame::new(
+                    Header::new(PGN::new(0xFEB0), 0, 0x01, Some(0x2)),
+                    &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+                ))
+            );
+        }
+
+        /// Messages larger than 1785 bytes must be sent by the extended transport protocol
+        #[test]
+        fn p2p_rx_long_abort_message_size() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            // 4000 bytes, the packet count is truncated to 60
+            driver.push_can_frame(TestFrame::new2(
+                0x00EC0201,
+                &[16, 0xA0, 0x0F, 60, 255, 176, 254, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[255, 9, 255, 255, 255, 176, 254, 0]
+                ))
+            );
+            assert_eq!(driver.get_can_frame(), None);
+            // the packet count does not match the message size
+            driver.push_can_frame(TestFrame::new2(
+                0x00EC0201,
+                &[16, 20, 0, 4, 255, 176, 254, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[255, 2, 255, 255, 255, 176, 254, 0]
+                ))
+            );
+            assert_eq!(driver.get_can_frame(), None);
+            // no session was opened
+            driver.push_can_frame(TestFrame::new2(0x00EC0201, &[16, 20, 0, 3, 1, 176, 254, 0]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[17, 1, 1, 255, 255, 176, 254, 0]
+                ))
+            );
+        }
+
+        #[test]
+        fn p2p_rx_long_receive_window() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new_with_config(
+                driver.clone(),
+                timer.clone(),
+                StackConfig::default().tp_receive_window(2),
+            );
+            stack.set_accepted_all(true);
+            driver.push_can_frame(TestFrame::new2(
+                0x00EC0201,
+                &[16, 20, 0, 3, 255, 176, 254, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[17, 2, 1, 255, 255, 176, 254, 0]
+                ))
+            );
+            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[1, 1, 2, 3, 4, 5, 6, 7]));
+            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[2, 1, 2, 3, 4, 5, 6, 7]));
+            stack.process().unwrap();
+            // only the remaining packet is requested
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[17, 1, 3, 255, 255, 176, 254, 0]
+                ))
+            );
+            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[3, 1, 2, 3, 4, 5, 55, 255]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[19, 20, 0, 3, 255, 176, 254, 0]
+                ))
+            );
+            assert!(stack.get_frame().is_some());
+        }
+        #[test]
+        fn p2p_tx_long_max_packets_per_cts() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new_with_config(
+                driver.clone(),
+                timer.clone(),
+                StackConfig::default().tp_max_packets_per_cts(16),
+            );
+            stack.set_accepted_all(true);
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
+                ))
+                .unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEC9B90, &[16, 20, 0, 3, 16, 0, 223, 0]))
+            );
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEC909B,
+                &[17, 3, 1, 255, 255, 0, 223, 0],
+            ));
+            stack.process().unwrap();
+            stack.process().unwrap();
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEB9B90, &[1, 1, 2, 3, 4, 5, 6, 7]))
+            );
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEB9B90, &[2, 1, 2, 3, 4, 5, 6, 7]))
+            );
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEB9B90, &[3, 1, 2, 3, 4, 5, 6, 255]))
+            );
+            assert_eq!(driver.get_can_frame(), None);
+        }
+        #[test]
+        fn p2p_rx_long_events() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            let session = TransportSession {
+                direction: TransportDirection::Receive,
+                pgn: PGN::new(0xFEB0),
+                source_address: 0x01,
+                destination_address: 0x02,
+                message_size: 20,
+                handle: None,
+            };
+            driver.push_can_frame(TestFrame::new2(0x00EC0201, &[16, 20, 0, 3, 2, 176, 254, 0]));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.get_transport_event(),
+                Some(TransportEvent::Started(session))
+            );
+            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[1, 1, 2, 3, 4, 5, 6, 7]));
+            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[2, 1, 2, 3, 4, 5, 6, 7]));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.get_transport_event(),
+                Some(TransportEvent::Progress { session, bytes: 14 })
+            );
+            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[3, 1, 2, 3, 4, 5, 55, 255]));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.get_transport_event(),
+                Some(TransportEvent::Completed(session))
+            );
+            assert_eq!(stack.get_transport_event(), None);
+        }
+        #[test]
+        fn p2p_rx_long_timeout_event() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            driver.push_can_frame(TestFrame::new2(0x00EC0201, &[16, 20, 0, 3, 1, 176, 254, 0]));
+            stack.process().unwrap();
+            timer.set_time(1300);
+            stack.process().unwrap();
+            let session = TransportSession {
+                direction: TransportDirection::Receive,
+                pgn: PGN::new(0xFEB0),
+                source_address: 0x01,
+                destination_address: 0x02,
+                message_size: 20,
+                handle: None,
+            };
+            assert_eq!(
+                stack.get_transport_event(),
+                Some(TransportEvent::Started(session))
+            );
+            assert_eq!(
+                stack.get_transport_event(),
+                Some(TransportEvent::TimedOut(session))
+            );
+        }
+        #[test]
+        fn p2p_tx_long_abort_event() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            let handle = stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
+                ))
+                .unwrap();
+            let session = TransportSession {
+                direction: TransportDirection::Transmit,
+                pgn: PGN::new(0xDF00),
+                source_address: 0x90,
+                destination_address: 0x9B,
+                message_size: 20,
+                handle: Some(handle),
+            };
+            assert_eq!(
+                stack.get_transport_event(),
+                Some(TransportEvent::Started(session))
+            );
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEC909B,
+                &[255, 2, 255, 255, 255, 0, 223, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.get_transport_event(),
+                Some(TransportEvent::Aborted {
+                    session,
+                    reason: AbortReason::NoResources,
+                    remote: true
+                })
+            );
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::Failed(TransferError::Aborted(
+                    AbortReason::NoResources
+                )))
+            );
+        }
+        #[test]
+        fn p2p_tx_long_transfer_state() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            let handle = stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3],
+                ))
+                .unwrap();
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::InProgress)
+            );
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEC909B,
+                &[17, 2, 1, 255, 255, 0, 223, 0],
+            ));
+            stack.process().unwrap();
+            stack.process().unwrap();
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::InProgress)
+            );
+            driver.push_can_frame(TestFrame::new2(0x1CEC909B, &[19, 10, 0, 2, 255, 0, 223, 0]));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::Completed)
+            );
+            // single frames are completed as soon as they are sent
+            let handle = stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
+                    &[1, 2, 3],
+                ))
+                .unwrap();
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::Completed)
+            );
+        }
+        #[test]
+        fn p2p_tx_long_cancel() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            let handle = stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3],
+                ))
+                .unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEC9B90, &[16, 10, 0, 2, 1, 0, 223, 0]))
+            );
+            assert!(stack.cancel_transfer(&handle).unwrap());
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC9B90,
+                    &[255, 2, 255, 255, 255, 0, 223, 0]
+                ))
+            );
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::Failed(TransferError::Cancelled))
+            );
+            assert!(!stack.cancel_transfer(&handle).unwrap());
+        }
+        #[test]
+        fn control_function_transfer() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            let cf_handle = stack.register_control_function(
+                0x85,
+                Name {
+                    address_capable: false,
+                    ..Name::default()
+                },
+            );
+            let frame = Frame::new(
+                Header::new(PGN::new(0xFEB0), 6, 0x00, None),
+                &[1, 2, 3, 4, 5, 6, 7, 8, 9],
+            );
+            assert_eq!(
+                stack.control_function(&cf_handle).send_frame(frame.clone()),
+                Err(Error::NoAddress)
+            );
+            stack.process().unwrap();
+            timer.set_time(300);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x18EEFF85, &[0, 0, 0, 0, 0, 255, 2, 32]))
+            );
+            let handle = stack
+                .control_function(&cf_handle)
+                .send_frame(frame.clone())
+                .unwrap();
+            assert_eq!(stack.transfer_state(&handle), Some(TransferState::Queued));
+            // a cancelled frame is never sent
+            assert!(stack.cancel_transfer(&handle).unwrap());
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::Failed(TransferError::Cancelled))
+            );
+            let handle = stack
+                .control_function(&cf_handle)
+                .send_frame(frame)
+                .unwrap();
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CECFF85,
+                    &[32, 9, 0, 2, 255, 0xB0, 0xFE, 0]
+                ))
+            );
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::InProgress)
+            );
+        }
+        #[test]
+        fn p2p_tx_long_destination_offline() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            let frame = Frame::new(
+                Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9C)),
+                &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3],
+            );
+            // 0x9B claimed its address, 0x9C may still be online
+            driver.push_can_frame(TestFrame::new2(0x18EEFF9B, &[1, 0, 0, 0, 0, 255, 2, 32]));
+            stack.process().unwrap();
+            let handle = stack.send_frame(frame.clone()).unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEC9C90, &[16, 10, 0, 2, 1, 0, 0xDF, 0]))
+            );
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::InProgress)
+            );
+            assert!(stack.cancel_transfer(&handle).unwrap());
+            driver.get_can_frame();
+
+            // only 0x9B answers a request for address claimed
+            driver.push_can_frame(TestFrame::new2(0x18EAFF9B, &[0, 0xEE, 0]));
+            driver.push_can_frame(TestFrame::new2(0x18EEFF9B, &[1, 0, 0, 0, 0, 255, 2, 32]));
+            stack.process().unwrap();
+            timer.set_time(1500);
+            let handle = stack.send_frame(frame.clone()).unwrap();
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::InProgress)
+            );
+            assert!(stack.cancel_transfer(&handle).unwrap());
+            driver.get_can_frame();
+            driver.get_can_frame();
+
+            // the address claim round is completed
+            timer.set_time(1501);
+            let handle = stack.send_frame(frame).unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::Failed(TransferError::DestinationOffline))
+            );
+        }
+        #[test]
+        fn broadcast_tx_long_events() {
+            let mut timer = TestTimer::new();
+            let driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            let handle = stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB0), 0, 0x90, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3],
+                ))
+                .unwrap();
+            let session = TransportSession {
+                direction: TransportDirection::Transmit,
+                pgn: PGN::new(0xFEB0),
+                source_address: 0x90,
+                destination_address: 0xFF,
+                message_size: 10,
+                handle: Some(handle),
+            };
+            timer.set_time(50);
+            stack.process().unwrap();
+            timer.set_time(100);
+            stack.process().unwrap();
+            assert_eq!(
+                stack.get_transport_event(),
+                Some(TransportEvent::Started(session))
+            );
+            assert_eq!(
+                stack.get_transport_event(),
+                Some(TransportEvent::Progress { session, bytes: 7 })
+            );
+            assert_eq!(
+                stack.get_transport_event(),
+                Some(TransportEvent::Completed(session))
+            );
+        }
+        #[test]
+        fn p2p_rx_long_timeout() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            driver.push_can_frame(TestFrame::new2(0x00EC0201, &[16, 20, 0, 3, 1, 176, 254, 0]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[17, 1, 1, 255, 255, 176, 254, 0]
+                ))
+            );
+            driver.push_can_frame(TestFrame::new2(0x00EB0201, &[1, 1, 2, 3, 4, 5, 6, 7]));
+            timer.set_time(100);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[17, 1, 2, 255, 255, 176, 254, 0]
+                ))
+            );
+            // T2 is not yet expired
+            timer.set_time(1300);
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            timer.set_time(1400);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[255, 3, 255, 255, 255, 176, 254, 0]
+                ))
+            );
+            // the session is freed, a new RTS is accepted
+            driver.push_can_frame(TestFrame::new2(0x00EC0201, &[16, 20, 0, 3, 1, 176, 254, 0]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[17, 1, 1, 255, 255, 176, 254, 0]
+                ))
+            );
+        }
+        #[test]
+        fn broadcast_rx_long_timeout() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            driver.push_can_frame(TestFrame::new2(
+                0x00ECFF01,
+                &[32, 20, 0, 3, 255, 0xB0, 0xFE, 0],
+            ));
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[1, 1, 2, 3, 4, 5, 6, 7]));
+            stack.process().unwrap();
+            // T1 expired, the broadcast is dropped without an abort
+            timer.set_time(800);
+            stack.process().unwrap();
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[2, 1, 2, 3, 4, 5, 6, 7]));
+            driver.push_can_frame(TestFrame::new2(0x00EBFF01, &[3, 1, 2, 3, 4, 5, 6, 255]));
+            stack.process().unwrap();
+            assert_eq!(stack.get_frame(), None);
+            assert_eq!(driver.get_can_frame(), None);
+        }
+        #[test]
+        fn p2p_tx_long_hold_resume() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
+                ))
+                .unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEC9B90, &[16, 20, 0, 3, 1, 0, 223, 0]))
+            );
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEC909B,
+                &[17, 1, 1, 255, 255, 0, 223, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEB9B90, &[1, 1, 2, 3, 4, 5, 6, 7]))
+            );
+            // hold the connection, the packet number is ignored
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEC909B,
+                &[17, 0, 255, 255, 255, 0, 223, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            // resume with the remaining packets
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEC909B,
+                &[17, 2, 2, 255, 255, 0, 223, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEB9B90, &[2, 1, 2, 3, 4, 5, 6, 7]))
+            );
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEB9B90, &[3, 1, 2, 3, 4, 5, 6, 255]))
+            );
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+        }
+        #[test]
+        fn p2p_tx_long_retransmit_limit() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
+                ))
+                .unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEC9B90, &[16, 20, 0, 3, 1, 0, 223, 0]))
+            );
+            // first transmission and two retransmissions
+            for _ in 0..3 {
+                driver.push_can_frame(TestFrame::new2(
+                    0x1CEC909B,
+                    &[17, 1, 1, 255, 255, 0, 223, 0],
+                ));
+                stack.process().unwrap();
+                assert_eq!(
+                    driver.get_can_frame(),
+                    Some(TestFrame::new2(0x1CEB9B90, &[1, 1, 2, 3, 4, 5, 6, 7]))
+                );
+            }
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEC909B,
+                &[17, 1, 1, 255, 255, 0, 223, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC9B90,
+                    &[255, 5, 255, 255, 255, 0, 223, 0]
+                ))
+            );
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+        }
+        #[test]
+        fn p2p_tx_long_cts_while_transfer() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            // the transmit queue holds a single packet of the window per process() call
+            let mut stack = Stack::new_with_config(
+                driver.clone(),
+                timer.clone(),
+                StackConfig::default().tx_queue_capacity(1),
+            );
+            stack.set_accepted_all(true);
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
+                ))
+                .unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEC9B90, &[16, 20, 0, 3, 1, 0, 223, 0]))
+            );
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEC909B,
+                &[17, 3, 1, 255, 255, 0, 223, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEB9B90, &[1, 1, 2, 3, 4, 5, 6, 7]))
+            );
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEC909B,
+                &[17, 1, 1, 255, 255, 0, 223, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC9B90,
+                    &[255, 4, 255, 255, 255, 0, 223, 0]
+                ))
+            );
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+        }
+        #[test]
+        fn p2p_tx_long_timeout() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
+                ))
+                .unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEC9B90, &[16, 20, 0, 3, 1, 0, 223, 0]))
+            );
+            // hold the connection
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEC909B,
+                &[17, 0, 1, 255, 255, 0, 223, 0],
+            ));
+            timer.set_time(1000);
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            // T4 expired
+            timer.set_time(2100);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC9B90,
+                    &[255, 3, 255, 255, 255, 0, 223, 0]
+                ))
+            );
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+        }
+        #[test]
+        fn p2p_tx_extended_timeout() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xE700), 6, 0x90, Some(0x9B)),
+                    &[0x55; 1800],
+                ))
+                .unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CC89B90, &[20, 8, 7, 0, 0, 0, 0xE7, 0]))
+            );
+            // T3 expired without a CTS
+            timer.set_time(1300);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CC89B90,
+                    &[255, 3, 255, 255, 255, 0, 0xE7, 0]
+                ))
+            );
+        }
+        #[test]
+        fn p2p_rx_extended() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            let payload: Vec<u8> = (0..1800u32).map(|i| i as u8).collect();
+            driver.push_can_frame(TestFrame::new2(0x1CC80201, &[20, 8, 7, 0, 0, 0, 0xE7, 0]));
+            stack.process().unwrap();
+            let mut next_packet = 1u32;
+            for chunk in payload.chunks(7 * 16) {
+                let packets = chunk.len().div_ceil(7) as u8;
+                let next = next_packet.to_le_bytes();
+                assert_eq!(
+                    driver.get_can_frame(),
+                    Some(TestFrame::new2(
+                        0x1CC80102,
+                        &[21, packets, next[0], next[1], next[2], 0, 0xE7, 0]
+                    ))
+                );
+                let offset = (next_packet - 1).to_le_bytes();
+                driver.push_can_frame(TestFrame::new2(
+                    0x1CC80201,
+                    &[22, packets, offset[0], offset[1], offset[2], 0, 0xE7, 0],
+                ));
+                for (sequence, packet) in chunk.chunks(7).enumerate() {
+                    let mut data = [0xFF; 8];
+                    data[0] = sequence as u8 + 1;
+                    data[1..=packet.len()].copy_from_slice(packet);
+                    driver.push_can_frame(TestFrame::new2(0x1CC70201, &data));
+                }
+                stack.process().unwrap();
+                next_packet += packets as u32;
+            }
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CC80102, &[23, 8, 7, 0, 0, 0, 0xE7, 0]))
+            );
+            assert_eq!(
+                stack.get_frame(),
+                Some(Frame::new(
+                    Header::new(PGN::new(0xE700), 7, 0x01, Some(0x02)),
+                    &payload
+                ))
+            );
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+        }
+        #[test]
+        fn p2p_rx_extended_bad_offset() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            driver.push_can_frame(TestFrame::new2(0x1CC80201, &[20, 8, 7, 0, 0, 0, 0xE7, 0]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CC80102, &[21, 16, 1, 0, 0, 0, 0xE7, 0]))
+            );
+            driver.push_can_frame(TestFrame::new2(0x1CC80201, &[22, 16, 5, 0, 0, 0, 0xE7, 0]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CC80102,
+                    &[255, 12, 255, 255, 255, 0, 0xE7, 0]
+                ))
+            );
+            // session is closed, a new RTS is accepted
+            driver.push_can_frame(TestFrame::new2(0x1CC80201, &[20, 8, 7, 0, 0, 0, 0xE7, 0]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CC80102, &[21, 16, 1, 0, 0, 0, 0xE7, 0]))
+            );
+        }
+        #[test]
+        fn p2p_rx_extended_small_message() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            // messages up to 1785 bytes are sent with the transport protocol
+            driver.push_can_frame(TestFrame::new2(
+                0x1CC80201,
+                &[20, 0xF9, 6, 0, 0, 0, 0xE7, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CC80102,
+                    &[255, 250, 255, 255, 255, 0, 0xE7, 0]
+                ))
+            );
+            driver.push_can_frame(TestFrame::new2(0x1CC80201, &[20, 0, 0, 0, 0, 0, 0xE7, 0]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CC80102,
+                    &[255, 250, 255, 255, 255, 0, 0xE7, 0]
+                ))
+            );
+            assert_eq!(driver.get_can_frame(), None);
+            // no session was opened, a valid RTS is accepted
+            driver.push_can_frame(TestFrame::new2(
+                0x1CC80201,
+                &[20, 0xFA, 6, 0, 0, 0, 0xE7, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CC80102, &[21, 16, 1, 0, 0, 0, 0xE7, 0]))
+            );
+        }
+        #[test]
+        fn broadcast_tx_short() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB2), 0, 0x21, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 8],
+                ))
+                .unwrap();
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
                 Some(TestFrame::new2(0x00FEB221, &[1, 2, 3, 4, 5, 6, 7, 8]))
             )
         }
         #[test]
+        fn broadcast_tx_short_retry() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            driver.set_transmit_error(true);
+            let handle = stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB2), 0, 0x21, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 8],
+                ))
+                .unwrap();
+            assert_eq!(stack.transfer_state(&handle), Some(TransferState::Queued));
+            assert_eq!(
+                stack.process(),
+                Err(Error::Driver(embedded_can::ErrorKind::Other))
+            );
+            assert_eq!(driver.get_can_frame(), None);
+            driver.set_transmit_error(false);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x00FEB221, &[1, 2, 3, 4, 5, 6, 7, 8]))
+            );
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::Completed)
+            );
+        }
+        #[test]
+        fn nb_driver_would_block() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(NbDriver::new(driver.clone()), timer.clone());
+            driver.set_transmit_error(true);
+            let handle = stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB2), 0, 0x21, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 8],
+                ))
+                .unwrap();
+            // a full transmit buffer is not an error
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            assert_eq!(stack.transfer_state(&handle), Some(TransferState::Queued));
+            driver.set_transmit_error(false);
+            driver.push_can_frame(TestFrame::new2(0x00FEB322, &[8, 7, 6, 5, 4, 3, 2, 1]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x00FEB221, &[1, 2, 3, 4, 5, 6, 7, 8]))
+            );
+            // the driver may replace the pending frame till the next call
+            assert_eq!(stack.transfer_state(&handle), Some(TransferState::Queued));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::Completed)
+            );
+            assert_eq!(
+                stack.get_frame(),
+                Some(Frame::new(
+                    Header::new(PGN::new(0xFEB3), 0, 0x22, None),
+                    &[8, 7, 6, 5, 4, 3, 2, 1]
+                ))
+            );
+        }
+        #[test]
+        fn tx_queue_priority() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            driver.set_transmit_error(true);
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB2), 6, 0x21, None),
+                    &[1],
+                ))
+                .unwrap();
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB3), 3, 0x21, None),
+                    &[2],
+                ))
+                .unwrap();
+            driver.set_transmit_error(false);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x0CFEB321, &[2]))
+            );
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x18FEB221, &[1]))
+            );
+        }
+        #[test]
+        fn tx_queue_full() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new_with_config(
+                driver.clone(),
+                timer.clone(),
+                StackConfig::default().tx_queue_capacity(1),
+            );
+            driver.set_transmit_error(true);
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB2), 6, 0x21, None),
+                    &[1],
+                ))
+                .unwrap();
+            assert_eq!(
+                stack.send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB3), 3, 0x21, None),
+                    &[2],
+                )),
+                Err(Error::QueueFull)
+            );
+        }
+        #[test]
+        fn tx_queue_overflow_count() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new_with_config(
+                driver.clone(),
+                timer.clone(),
+                StackConfig::default()
+                    .tx_queue_capacity(1)
+                    .tx_queue_overflow(crate::config::OverflowPolicy::DropOldest),
+            );
+            driver.set_transmit_error(true);
+            for data in 1..=3 {
+                stack
+                    .send_frame(Frame::new(
+                        Header::new(PGN::new(0xFEB2), 6, 0x21, None),
+                        &[data],
+                    ))
+                    .unwrap();
+            }
+            assert_eq!(stack.overflows().transmit_frames, 2);
+        }
+        #[test]
+        fn tx_queue_full_session() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new_with_config(
+                driver.clone(),
+                timer.clone(),
+                StackConfig::default().tx_queue_capacity(1),
+            );
+            stack.set_accepted_all(true);
+            driver.set_transmit_error(true);
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB2), 6, 0x02, None),
+                    &[1],
+                ))
+                .unwrap();
+            // the CTS of the session uses the capacity reserved for transport sessions
+            driver.push_can_frame(TestFrame::new2(
+                0x00EC0201,
+                &[16, 20, 0, 3, 255, 176, 254, 0],
+            ));
+            assert!(stack.process().is_err());
+            driver.set_transmit_error(false);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x18FEB202, &[1]))
+            );
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC0102,
+                    &[17, 3, 1, 255, 255, 176, 254, 0]
+                ))
+            );
+            assert_eq!(driver.get_can_frame(), None);
+        }
+        #[test]
+        fn rx_queue_overflow_count() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new_with_config(
+                driver.clone(),
+                timer.clone(),
+                StackConfig::default()
+                    .rx_queue_capacity(2)
+                    .cf_rx_queue_capacity(1),
+            );
+            let handle = stack.register_control_function(
+                0x85,
+                Name {
+                    address_capable: false,
+                    ..Name::default()
+                },
+            );
+            stack.process().unwrap();
+            timer.set_time(300);
+            stack.process().unwrap();
+            // the own address claim is looped back into the receive queue of the stack
+            assert_eq!(stack.overflows(), Overflows::default());
+            for data in 1..=3 {
+                driver.push_can_frame(TestFrame::new2(0x18FEB221, &[data; 8]));
+            }
+            stack.process().unwrap();
+            assert_eq!(
+                stack.overflows(),
+                Overflows {
+                    received_frames: 2,
+                    ..Overflows::default()
+                }
+            );
+            assert_eq!(stack.get_frame().unwrap().data(), &[2; 8]);
+            assert_eq!(stack.get_frame().unwrap().data(), &[3; 8]);
+            let cf = stack.control_function(&handle);
+            assert_eq!(cf.receive_overflows(), 2);
+            assert_eq!(cf.get_frame().unwrap().data(), &[3; 8]);
+        }
+        #[test]
+        fn broadcast_tx_long_transmit_error() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            let handle = stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB0), 0, 0x21, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
+                ))
+                .unwrap();
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CECFF21,
+                    &[32, 20, 0, 3, 255, 0xB0, 0xFE, 0]
+                ))
+            );
+            // the first data packet is retried with the next packet
+            driver.set_transmit_error(true);
+            timer.set_time(50);
+            assert_eq!(
+                stack.process(),
+                Err(Error::Driver(embedded_can::ErrorKind::Other))
+            );
+            driver.set_transmit_error(false);
+            timer.set_time(100);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEBFF21, &[1, 1, 2, 3, 4, 5, 6, 7]))
+            );
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEBFF21, &[2, 1, 2, 3, 4, 5, 6, 7]))
+            );
+            timer.set_time(150);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEBFF21, &[3, 1, 2, 3, 4, 5, 6, 255]))
+            );
+            timer.set_time(200);
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::Completed)
+            );
+        }
+        #[test]
         fn broadcast_tx_long() {
-            let timer = TestTimer::new();
+            let mut timer = TestTimer::new();
             let mut driver = TestDriver::new();
             let mut stack = Stack::new(driver.clone(), timer.clone());
-            stack.send_frame(Frame::new(
-                Header::new(PGN::new(0xFEB0), 0, 0x21, None),
-                &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
-            ));
-            stack.process();
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB0), 0, 0x21, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
+                ))
+                .unwrap();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(
@@ -681,34 +2767,191 @@ mod tests {
                     &[32, 20, 0, 3, 255, 0xB0, 0xFE, 0]
                 ))
             );
-            stack.process();
+            timer.set_time(50);
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x1CEBFF21, &[1, 1, 2, 3, 4, 5, 6, 7]))
             );
-            stack.process();
+            timer.set_time(100);
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x1CEBFF21, &[2, 1, 2, 3, 4, 5, 6, 7]))
             );
-            stack.process();
+            timer.set_time(150);
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x1CEBFF21, &[3, 1, 2, 3, 4, 5, 6, 255]))
             );
-            stack.process();
+            timer.set_time(200);
+            stack.process().unwrap();
             assert_eq!(driver.get_can_frame(), None)
         }
         #[test]
+        fn broadcast_tx_long_packet_gap() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new_with_config(
+                driver.clone(),
+                timer.clone(),
+                StackConfig::default().bam_packet_gap(Duration::millis(100)),
+            );
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB0), 0, 0x21, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
+                ))
+                .unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CECFF21,
+                    &[32, 20, 0, 3, 255, 0xB0, 0xFE, 0]
+                ))
+            );
+            timer.set_time(99);
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            timer.set_time(100);
+            stack.process().unwrap();
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEBFF21, &[1, 1, 2, 3, 4, 5, 6, 7]))
+            );
+            assert_eq!(driver.get_can_frame(), None);
+            timer.set_time(150);
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            timer.set_time(200);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEBFF21, &[2, 1, 2, 3, 4, 5, 6, 7]))
+            );
+        }
+        #[test]
+        fn broadcast_tx_long_queued() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB0), 0, 0x21, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5],
+                ))
+                .unwrap();
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFECA), 0, 0x21, None),
+                    &[9, 8, 7, 6, 5, 4, 3, 2, 1],
+                ))
+                .unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CECFF21,
+                    &[32, 12, 0, 2, 255, 0xB0, 0xFE, 0]
+                ))
+            );
+            // the second broadcast waits till the first one is finished
+            assert_eq!(driver.get_can_frame(), None);
+            timer.set_time(50);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEBFF21, &[1, 1, 2, 3, 4, 5, 6, 7]))
+            );
+            timer.set_time(100);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEBFF21, &[2, 1, 2, 3, 4, 5, 255, 255]))
+            );
+            timer.set_time(150);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CECFF21,
+                    &[32, 9, 0, 2, 255, 0xCA, 0xFE, 0]
+                ))
+            );
+            timer.set_time(200);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEBFF21, &[1, 9, 8, 7, 6, 5, 4, 3]))
+            );
+            timer.set_time(250);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEBFF21,
+                    &[2, 2, 1, 255, 255, 255, 255, 255]
+                ))
+            );
+            timer.set_time(300);
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+        }
+        #[test]
+        fn broadcast_tx_long_concurrent() {
+            let mut timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB0), 0, 0x21, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2],
+                ))
+                .unwrap();
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xFEB0), 0, 0x22, None),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2],
+                ))
+                .unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CECFF21,
+                    &[32, 9, 0, 2, 255, 0xB0, 0xFE, 0]
+                ))
+            );
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CECFF22,
+                    &[32, 9, 0, 2, 255, 0xB0, 0xFE, 0]
+                ))
+            );
+            timer.set_time(50);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEBFF21, &[1, 1, 2, 3, 4, 5, 6, 7]))
+            );
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEBFF22, &[1, 1, 2, 3, 4, 5, 6, 7]))
+            );
+        }
+        #[test]
         fn p2p_tx_short() {
             let timer = TestTimer::new();
             let mut driver = TestDriver::new();
             let mut stack = Stack::new(driver.clone(), timer.clone());
-            stack.send_frame(Frame::new(
-                Header::new(PGN::new(0xF000), 6, 0x21, Some(0x9B)),
-                &[1, 2, 3, 4, 5, 6, 7, 8],
-            ));
-            stack.process();
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xF000), 6, 0x21, Some(0x9B)),
+                    &[1, 2, 3, 4, 5, 6, 7, 8],
+                ))
+                .unwrap();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x18F09B21, &[1, 2, 3, 4, 5, 6, 7, 8]))
@@ -720,35 +2963,37 @@ mod tests {
             let mut driver = TestDriver::new();
             let mut stack = Stack::new(driver.clone(), timer.clone());
             stack.set_accepted_all(true);
-            stack.send_frame(Frame::new(
-                Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
-                &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
-            ));
-            stack.process();
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
+                ))
+                .unwrap();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x1CEC9B90, &[16, 20, 0, 3, 1, 0, 223, 0]))
             );
             // without cts no further messages
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(driver.get_can_frame(), None);
             driver.push_can_frame(TestFrame::new2(
                 0x1CEC909B,
                 &[17, 1, 1, 255, 255, 0, 223, 0],
             ));
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x1CEB9B90, &[1, 1, 2, 3, 4, 5, 6, 7]))
             );
             // without cts no further messages
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(driver.get_can_frame(), None);
             driver.push_can_frame(TestFrame::new2(
                 0x1CEC909B,
                 &[17, 1, 2, 255, 255, 0, 223, 0],
             ));
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x1CEB9B90, &[2, 1, 2, 3, 4, 5, 6, 7]))
@@ -758,7 +3003,7 @@ mod tests {
                 0x1CEC909B,
                 &[17, 1, 2, 255, 255, 0, 223, 0],
             ));
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x1CEB9B90, &[2, 1, 2, 3, 4, 5, 6, 7]))
@@ -767,13 +3012,248 @@ mod tests {
                 0x1CEC909B,
                 &[17, 1, 3, 255, 255, 0, 223, 0],
             ));
-            stack.process();
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEB9B90, &[3, 1, 2, 3, 4, 5, 6, 255]))
+            );
+            driver.push_can_frame(TestFrame::new2(0x1CEC909B, &[19, 20, 0, 3, 255, 0, 223, 0]));
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+        }
+        /// All packets of a CTS window are sent with one process() call
+        #[test]
+        fn p2p_tx_long_window() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
+                ))
+                .unwrap();
+            stack.process().unwrap();
+            driver.get_can_frame();
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEC909B,
+                &[17, 3, 1, 255, 255, 0, 223, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEB9B90, &[1, 1, 2, 3, 4, 5, 6, 7]))
+            );
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEB9B90, &[2, 1, 2, 3, 4, 5, 6, 7]))
+            );
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x1CEB9B90, &[3, 1, 2, 3, 4, 5, 6, 255]))
             );
+            assert_eq!(driver.get_can_frame(), None);
+        }
+
+        #[test]
+        fn p2p_tx_long_bad_cts_packet_number() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            let handle = stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
+                ))
+                .unwrap();
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CEC9B90, &[16, 20, 0, 3, 1, 0, 223, 0]))
+            );
+            // packet numbers start with 1
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEC909B,
+                &[17, 1, 0, 255, 255, 0, 223, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC9B90,
+                    &[255, 7, 255, 255, 255, 0, 223, 0]
+                ))
+            );
+            assert_eq!(driver.get_can_frame(), None);
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::Failed(TransferError::Aborted(
+                    AbortReason::BadSequenceNumber
+                )))
+            );
+        }
+        #[test]
+        fn p2p_tx_long_cts_exceeds_message() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            let handle = stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
+                ))
+                .unwrap();
+            stack.process().unwrap();
+            driver.get_can_frame();
+            // the message has 3 packets, packets 2 to 4 are requested
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEC909B,
+                &[17, 3, 2, 255, 255, 0, 223, 0],
+            ));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CEC9B90,
+                    &[255, 15, 255, 255, 255, 0, 223, 0]
+                ))
+            );
+            assert_eq!(driver.get_can_frame(), None);
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::Failed(TransferError::Aborted(
+                    AbortReason::CtsPacketsExceedMessage
+                )))
+            );
+        }
+        #[test]
+        fn p2p_tx_long_end_of_msg_other_pgn() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            let handle = stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xDF00), 0, 0x90, Some(0x9B)),
+                    &[1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6],
+                ))
+                .unwrap();
+            stack.process().unwrap();
+            driver.get_can_frame();
+            driver.push_can_frame(TestFrame::new2(
+                0x1CEC909B,
+                &[17, 3, 1, 255, 255, 0, 223, 0],
+            ));
+            stack.process().unwrap();
+            while driver.get_can_frame().is_some() {}
+            // an acknowledgement of another PGN does not complete the transfer
+            driver.push_can_frame(TestFrame::new2(0x1CEC909B, &[19, 20, 0, 3, 255, 0, 224, 0]));
+            stack.process().unwrap();
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::InProgress)
+            );
             driver.push_can_frame(TestFrame::new2(0x1CEC909B, &[19, 20, 0, 3, 255, 0, 223, 0]));
-            stack.process();
+            stack.process().unwrap();
+            assert_eq!(
+                stack.transfer_state(&handle),
+                Some(TransferState::Completed)
+            );
+        }
+        #[test]
+        fn p2p_tx_extended() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            let payload: Vec<u8> = (0..1800u32).map(|i| i as u8).collect();
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xE700), 6, 0x90, Some(0x9B)),
+                    &payload,
+                ))
+                .unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CC89B90, &[20, 8, 7, 0, 0, 0, 0xE7, 0]))
+            );
+            // without cts no further messages
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            // hold the connection
+            driver.push_can_frame(TestFrame::new2(0x1CC8909B, &[21, 0, 1, 0, 0, 0, 0xE7, 0]));
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+
+            let mut next_packet = 1u32;
+            for chunk in payload.chunks(7 * 100) {
+                let packets = chunk.len().div_ceil(7) as u8;
+                let next = next_packet.to_le_bytes();
+                driver.push_can_frame(TestFrame::new2(
+                    0x1CC8909B,
+                    &[21, packets, next[0], next[1], next[2], 0, 0xE7, 0],
+                ));
+                // the window is larger than the transmit queue
+                for _ in 0..4 {
+                    stack.process().unwrap();
+                }
+                let offset = (next_packet - 1).to_le_bytes();
+                assert_eq!(
+                    driver.get_can_frame(),
+                    Some(TestFrame::new2(
+                        0x1CC89B90,
+                        &[22, packets, offset[0], offset[1], offset[2], 0, 0xE7, 0]
+                    ))
+                );
+                for (sequence, packet) in chunk.chunks(7).enumerate() {
+                    let mut data = [0xFF; 8];
+                    data[0] = sequence as u8 + 1;
+                    data[1..=packet.len()].copy_from_slice(packet);
+                    assert_eq!(
+                        driver.get_can_frame(),
+                        Some(TestFrame::new2(0x1CC79B90, &data))
+                    );
+                }
+                assert_eq!(driver.get_can_frame(), None);
+                next_packet += packets as u32;
+            }
+            driver.push_can_frame(TestFrame::new2(0x1CC8909B, &[23, 8, 7, 0, 0, 0, 0xE7, 0]));
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            // the connection is closed, a further CTS is ignored
+            driver.push_can_frame(TestFrame::new2(0x1CC8909B, &[21, 1, 1, 0, 0, 0, 0xE7, 0]));
+            stack.process().unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+        }
+        #[test]
+        fn p2p_tx_extended_cts_exceeds_message() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack = Stack::new(driver.clone(), timer.clone());
+            stack.set_accepted_all(true);
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0xE700), 6, 0x90, Some(0x9B)),
+                    &[0x55; 1800],
+                ))
+                .unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x1CC89B90, &[20, 8, 7, 0, 0, 0, 0xE7, 0]))
+            );
+            driver.push_can_frame(TestFrame::new2(0x1CC8909B, &[21, 16, 0, 1, 0, 0, 0xE7, 0]));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x1CC89B90,
+                    &[255, 15, 255, 255, 255, 0, 0xE7, 0]
+                ))
+            );
+            stack.process().unwrap();
             assert_eq!(driver.get_can_frame(), None);
         }
     }
@@ -791,31 +3271,31 @@ mod tests {
                 0x0DF8051C,
                 &[64, 43, 59, 80, 75, 166, 229, 223],
             ));
-            stack.process();
+            stack.process().unwrap();
             driver.push_can_frame(TestFrame::new2(
                 0x0DF8051C,
                 &[65, 32, 128, 198, 181, 39, 169, 179],
             ));
-            stack.process();
+            stack.process().unwrap();
             driver.push_can_frame(TestFrame::new2(
                 0x0DF8051C,
                 &[66, 198, 6, 128, 205, 146, 152, 121],
             ));
-            stack.process();
+            stack.process().unwrap();
             driver.push_can_frame(TestFrame::new2(
                 0x0DF8051C,
                 &[67, 247, 66, 1, 128, 84, 49, 19],
             ));
-            stack.process();
+            stack.process().unwrap();
             driver.push_can_frame(TestFrame::new2(0x0DF8051C, &[68, 0, 0, 0, 0, 0, 0, 0]));
-            stack.process();
+            stack.process().unwrap();
             driver.push_can_frame(TestFrame::new2(0x0DF8051C, &[69, 100, 0, 100, 0, 0, 0, 0]));
-            stack.process();
+            stack.process().unwrap();
             driver.push_can_frame(TestFrame::new2(
                 0x0DF8051C,
                 &[70, 0, 0, 255, 255, 255, 255, 255],
             ));
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 stack.get_frame(),
                 Some(Frame::new(
@@ -835,14 +3315,16 @@ mod tests {
             let mut driver = TestDriver::new();
             let mut stack =
                 Stack::new_with_nema2000(driver.clone(), timer.clone(), &[PGN(0x1F805)]);
-            stack.send_frame(Frame::new(
-                Header::new(PGN::new(0x1F805), 3, 0x1C, None),
-                &[
-                    59, 80, 75, 166, 229, 223, 32, 128, 198, 181, 39, 169, 179, 198, 6, 128, 205,
-                    146, 152, 121, 247, 66, 1, 128, 84, 49, 19, 0, 0, 0, 0, 0, 0, 0, 100, 0, 100,
-                    0, 0, 0, 0, 0, 0,
-                ],
-            ));
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0x1F805), 3, 0x1C, None),
+                    &[
+                        59, 80, 75, 166, 229, 223, 32, 128, 198, 181, 39, 169, 179, 198, 6, 128,
+                        205, 146, 152, 121, 247, 66, 1, 128, 84, 49, 19, 0, 0, 0, 0, 0, 0, 0, 100,
+                        0, 100, 0, 0, 0, 0, 0, 0,
+                    ],
+                ))
+                .unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(
@@ -850,7 +3332,7 @@ mod tests {
                     &[0, 43, 59, 80, 75, 166, 229, 223],
                 ))
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(
@@ -858,7 +3340,7 @@ mod tests {
                     &[1, 32, 128, 198, 181, 39, 169, 179],
                 ))
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(
@@ -866,7 +3348,7 @@ mod tests {
                     &[2, 198, 6, 128, 205, 146, 152, 121],
                 ))
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(
@@ -874,17 +3356,17 @@ mod tests {
                     &[3, 247, 66, 1, 128, 84, 49, 19],
                 ))
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x0DF8051C, &[4, 0, 0, 0, 0, 0, 0, 0]))
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x0DF8051C, &[5, 100, 0, 100, 0, 0, 0, 0]))
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(
@@ -892,18 +3374,20 @@ mod tests {
                     &[6, 0, 0, 255, 255, 255, 255, 255],
                 ))
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(driver.get_can_frame(), None);
 
             // send a second time, now the sequence number must be different
-            stack.send_frame(Frame::new(
-                Header::new(PGN::new(0x1F805), 3, 0x1C, None),
-                &[
-                    59, 80, 75, 166, 229, 223, 32, 128, 198, 181, 39, 169, 179, 198, 6, 128, 205,
-                    146, 152, 121, 247, 66, 1, 128, 84, 49, 19, 0, 0, 0, 0, 0, 0, 0, 100, 0, 100,
-                    0, 0, 0, 0, 0, 0,
-                ],
-            ));
+            stack
+                .send_frame(Frame::new(
+                    Header::new(PGN::new(0x1F805), 3, 0x1C, None),
+                    &[
+                        59, 80, 75, 166, 229, 223, 32, 128, 198, 181, 39, 169, 179, 198, 6, 128,
+                        205, 146, 152, 121, 247, 66, 1, 128, 84, 49, 19, 0, 0, 0, 0, 0, 0, 0, 100,
+                        0, 100, 0, 0, 0, 0, 0, 0,
+                    ],
+                ))
+                .unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(
@@ -911,7 +3395,7 @@ mod tests {
                     &[32, 43, 59, 80, 75, 166, 229, 223],
                 ))
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(
@@ -919,7 +3403,7 @@ mod tests {
                     &[33, 32, 128, 198, 181, 39, 169, 179],
                 ))
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(
@@ -927,7 +3411,7 @@ mod tests {
                     &[34, 198, 6, 128, 205, 146, 152, 121],
                 ))
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(
@@ -935,17 +3419,17 @@ mod tests {
                     &[35, 247, 66, 1, 128, 84, 49, 19],
                 ))
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x0DF8051C, &[36, 0, 0, 0, 0, 0, 0, 0]))
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(0x0DF8051C, &[37, 100, 0, 100, 0, 0, 0, 0]))
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(
                 driver.get_can_frame(),
                 Some(TestFrame::new2(
@@ -953,8 +3437,62 @@ mod tests {
                     &[38, 0, 0, 255, 255, 255, 255, 255],
                 ))
             );
-            stack.process();
+            stack.process().unwrap();
             assert_eq!(driver.get_can_frame(), None);
         }
+
+        #[test]
+        fn transmit_fast_packet_queued() {
+            let timer = TestTimer::new();
+            let mut driver = TestDriver::new();
+            let mut stack =
+                Stack::new_with_nema2000(driver.clone(), timer.clone(), &[PGN(0x1F805)]);
+            let header = Header::new(PGN::new(0x1F805), 3, 0x1C, None);
+            let first = stack
+                .send_frame(Frame::new(header, &[1, 2, 3, 4, 5, 6, 7, 8, 9]))
+                .unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(0x0DF8051C, &[0, 9, 1, 2, 3, 4, 5, 6]))
+            );
+            assert_eq!(stack.transfer_state(&first), Some(TransferState::Queued));
+            // the second frame of the PGN waits till the first transfer is finished
+            let second = stack
+                .send_frame(Frame::new(header, &[11, 12, 13, 14, 15, 16, 17, 18, 19]))
+                .unwrap();
+            assert_eq!(driver.get_can_frame(), None);
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x0DF8051C,
+                    &[1, 7, 8, 9, 255, 255, 255, 255]
+                ))
+            );
+            assert_eq!(driver.get_can_frame(), None);
+            // the transfer is completed by the transmit of the last packet
+            assert_eq!(stack.transfer_state(&first), Some(TransferState::Completed));
+            assert_eq!(stack.transfer_state(&second), Some(TransferState::Queued));
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x0DF8051C,
+                    &[32, 9, 11, 12, 13, 14, 15, 16]
+                ))
+            );
+            stack.process().unwrap();
+            assert_eq!(
+                driver.get_can_frame(),
+                Some(TestFrame::new2(
+                    0x0DF8051C,
+                    &[33, 17, 18, 19, 255, 255, 255, 255]
+                ))
+            );
+            assert_eq!(
+                stack.transfer_state(&second),
+                Some(TransferState::Completed)
+            );
+        }
     }
 }
@@ -0,0 +1,260 @@
+use crate::address::AddressMonitor;
+use crate::address_pool::AddressPool;
+use crate::address_store::AddressStore;
+use crate::config::StackConfig;
+use crate::control_function::ControlFunction;
+use crate::filter::FilterRule;
+use crate::frame::{Frame, PGN_ADDRESSCLAIM, PGN_REQUEST};
+use crate::name::Name;
+use crate::stack::ControlFunctionHandle;
+use crate::time::{Instant, TimerDriver};
+use crate::transport::{TransferHandle, TransferState};
+use crate::Error;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use crossbeam_queue::ArrayQueue;
+
+/// Control functions, address monitor and receive queue shared by the [`Stack`](crate::stack::Stack)
+/// and the stack on top of the J1939 sockets of the linux kernel
+/// The stacks only differ in how frames are transmitted
+pub(crate) struct StackCore<TimeDriver: TimerDriver> {
+    received_frames: ArrayQueue<Frame>,
+    /// number of received frames dropped by the full receive queue
+    dropped_frames: u32,
+    accept_all_da: bool,
+    /// frames matching one of the rules are received by the stack, all frames are received without rules
+    filter: Vec<FilterRule>,
+    /// id of the next transfer handle returned by `send_frame` of the stack
+    next_transfer_id: u32,
+    cf: Vec<ControlFunction<TimeDriver>>,
+    address_monitor: AddressMonitor,
+    /// last claimed addresses of the control functions
+    address_store: Option<Box<dyn AddressStore + Send>>,
+    /// configuration of the control functions
+    config: StackConfig,
+    time: TimeDriver,
+}
+
+impl<TimeDriver: Clone + TimerDriver> StackCore<TimeDriver> {
+    pub fn new(config: StackConfig, time: TimeDriver) -> Self {
+        Self {
+            received_frames: ArrayQueue::new(config.rx_queue_capacity),
+            dropped_frames: 0,
+            accept_all_da: false,
+            filter: Vec::new(),
+            next_transfer_id: 0,
+            cf: Vec::new(),
+            address_monitor: AddressMonitor::new(),
+            address_store: None,
+            config,
+            time,
+        }
+    }
+
+    pub fn time(&self) -> &TimeDriver {
+        &self.time
+    }
+
+    /// Returns the number of received frames dropped because the receive queue was full
+    pub fn dropped_frames(&self) -> u32 {
+        self.dropped_frames
+    }
+
+    pub fn control_function_list(&self) -> &BTreeMap<u8, Name> {
+        self.address_monitor.control_function_list()
+    }
+
+    /// Returns the earliest deadline of the address management of the control functions
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.cf.iter().filter_map(|cf| cf.next_deadline()).min()
+    }
+
+    // ---------------------- control functions ----------------------------------------------------
+    pub fn set_address_store(&mut self, store: impl AddressStore + Send + 'static) {
+        self.address_store = Some(Box::new(store));
+    }
+
+    /// A stored address of the NAME replaces the preferred address
+    pub fn register_control_function(
+        &mut self,
+        preferred_address: u8,
+        name: Name,
+        address_pool: AddressPool,
+    ) -> ControlFunctionHandle {
+        let preferred_address = self
+            .address_store
+            .as_ref()
+            .and_then(|store| store.load(name))
+            .unwrap_or(preferred_address);
+        self.cf.push(ControlFunction::new(
+            name,
+            preferred_address,
+            self.cf.len(),
+            self.time.clone(),
+            &self.config,
+            address_pool,
+        ));
+        ControlFunctionHandle(self.cf.len() - 1)
+    }
+
+    pub fn control_function(
+        &mut self,
+        handle: &ControlFunctionHandle,
+    ) -> &mut ControlFunction<TimeDriver> {
+        &mut self.cf[handle.0]
+    }
+
+    /// Runs the address management of the control functions and transmits their queued frames
+    /// `transmit` returns false if the frame was skipped, e.g. because its transfer was cancelled
+    /// Transmitted frames are looped back to the other control functions and the stack
+    pub fn process_control_functions(
+        &mut self,
+        mut transmit: impl FnMut(
+            &AddressMonitor,
+            usize,
+            &Frame,
+            Option<TransferHandle>,
+        ) -> Result<bool, Error>,
+    ) -> Result<(), Error> {
+        let mut result = Ok(());
+        for cf_index in 0..self.cf.len() {
+            // check cf address management for ongoing transactions
+            self.cf[cf_index].process(&self.address_monitor);
+            // a failed store is retried with the next call
+            if let (Some(address), Some(store)) = (
+                self.cf[cf_index].unstored_claim(),
+                self.address_store.as_mut(),
+            ) {
+                match store.store(self.cf[cf_index].name(), address) {
+                    Ok(()) => self.cf[cf_index].mark_claim_stored(),
+                    Err(error) => result = result.and(Err(error)),
+                }
+            }
+            // check cf send queues and move them into stack queue
+            while let Some((frame, handle)) = self.cf[cf_index].pop_send_queue() {
+                // frames which could not be sent are not looped back
+                match transmit(&self.address_monitor, cf_index, &frame, handle) {
+                    Ok(true) => (),
+                    Ok(false) => continue,
+                    Err(error) => {
+                        result = result.and(Err(error));
+                        continue;
+                    }
+                }
+                for receiver_index in 0..self.cf.len() {
+                    // Skip own message for control functions
+                    if cf_index == receiver_index {
+                        continue;
+                    }
+                    result = result.and(
+                        self.cf[receiver_index].handle_new_frame(&frame, &self.address_monitor),
+                    );
+                }
+                result = result.and(self.handle_new_frame_stack(frame));
+            }
+        }
+        result
+    }
+
+    /// Returns the transfers dropped by the address management of the control functions
+    pub fn take_dropped_transfers(&mut self) -> Vec<TransferHandle> {
+        self.cf
+            .iter_mut()
+            .flat_map(|cf| cf.take_dropped_transfers())
+            .collect()
+    }
+
+    // --------------------------- direct stack usage ----------------------------------------------
+    pub fn get_frame(&mut self) -> Option<Frame> {
+        self.received_frames.pop()
+    }
+
+    pub fn next_transfer_handle(&mut self) -> TransferHandle {
+        let handle = TransferHandle::new(None, self.next_transfer_id);
+        self.next_transfer_id = self.next_transfer_id.wrapping_add(1);
+        handle
+    }
+
+    /// Returns the state known by the stack or `Queued` if the frame waits in a control function
+    pub fn transfer_state(
+        &self,
+        handle: &TransferHandle,
+        known: Option<TransferState>,
+    ) -> Option<TransferState> {
+        known.or_else(|| {
+            handle
+                .control_function()
+                .and_then(|index| self.cf.get(index))
+                .filter(|cf| cf.is_queued(handle))
+                .map(|_| TransferState::Queued)
+        })
+    }
+
+    pub fn set_accepted_all(&mut self, accept_all: bool) {
+        self.accept_all_da = accept_all;
+    }
+
+    pub fn add_filter(&mut self, rule: FilterRule) {
+        self.filter.push(rule);
+    }
+
+    pub fn clear_filter(&mut self) {
+        self.filter.clear();
+    }
+
+    pub fn destination_offline(&self, destination_address: Option<u8>) -> bool {
+        self.address_monitor
+            .destination_offline(destination_address, self.time.now())
+    }
+
+    /// Returns true if the frame is a broadcast or addressed to the stack or one of the control functions
+    pub fn check_destination(&self, destination_address: Option<u8>) -> bool {
+        if let Some(da) = destination_address {
+            let cf_address = self.cf.iter().any(|cf| cf.is_online() == Some(da));
+
+            self.accept_all_da || cf_address || da == 0xFF
+        } else {
+            true
+        }
+    }
+
+    /// got a new j1939 frame decoded from can frames
+    /// the frame is handled by all control functions and the stack, the first error is returned
+    pub fn handle_new_frame(&mut self, frame: Frame) -> Result<(), Error> {
+        let mut result = Ok(());
+        // check if the new frame should be handled by the cf
+        for cf in &mut self.cf {
+            result = result.and(cf.handle_new_frame(&frame, &self.address_monitor));
+        }
+        result.and(self.handle_new_frame_stack(frame))
+    }
+
+    fn handle_new_frame_stack(&mut self, frame: Frame) -> Result<(), Error> {
+        // check if the new frame is address related
+        let result =
+            if frame.header().pgn() == PGN_ADDRESSCLAIM || frame.header().pgn() == PGN_REQUEST {
+                self.address_monitor.handle_frame(&frame, self.time.now())
+            } else {
+                Ok(())
+            };
+        // check if the new frame should be handled by the stack
+        if let Some(da) = frame.header().destination_address() {
+            if !(da == 0xFF || self.accept_all_da) {
+                return result;
+            }
+        }
+        if !(self.filter.is_empty()
+            || self
+                .filter
+                .iter()
+                .any(|rule| rule.matches(&frame, self.address_monitor.control_function_list())))
+        {
+            return result;
+        }
+        if self.received_frames.force_push(frame).is_some() {
+            self.dropped_frames = self.dropped_frames.saturating_add(1);
+        }
+        result
+    }
+}
@@ -16,12 +16,14 @@ pub mod can_driver {
     pub struct TestDriver {
         output: Arc<Mutex<VecDeque<TestFrame>>>,
         input: Arc<Mutex<VecDeque<TestFrame>>>,
+        transmit_error: Arc<Mutex<bool>>,
     }
     impl Clone for TestDriver {
         fn clone(&self) -> Self {
             Self {
                 output: self.output.clone(),
                 input: self.input.clone(),
+                transmit_error: self.transmit_error.clone(),
             }
         }
     }
@@ -31,6 +33,7 @@ pub mod can_driver {
             Self {
                 output: Arc::new(Mutex::new(VecDeque::new())),
                 input: Arc::new(Mutex::new(VecDeque::new())),
+                transmit_error: Arc::new(Mutex::new(false)),
             }
         }
         pub fn push_can_frame(&mut self, frame: TestFrame) {
@@ -39,6 +42,10 @@ pub mod can_driver {
         pub fn get_can_frame(&mut self) -> Option<TestFrame> {
             self.output.lock().unwrap().pop_front()
         }
+        /// All transmits fail while set, e.g. to simulate a full tx mailbox
+        pub fn set_transmit_error(&mut self, error: bool) {
+            *self.transmit_error.lock().unwrap() = error;
+        }
     }
 
     impl embedded_can::blocking::Can for TestDriver {
@@ -47,6 +54,9 @@ pub mod can_driver {
         type Error = TestDriverError;
 
         fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+            if *self.transmit_error.lock().unwrap() {
+                return Err(TestDriverError {});
+            }
             self.output.lock().unwrap().push_back(frame.clone());
             Ok(())
         }
@@ -59,6 +69,32 @@ pub mod can_driver {
             }
         }
     }
+
+    /// A full transmit buffer or an empty receive buffer is reported with `WouldBlock`
+    impl embedded_can::nb::Can for TestDriver {
+        type Frame = crate::test_utils::frame::TestFrame;
+
+        type Error = TestDriverError;
+
+        fn transmit(
+            &mut self,
+            frame: &Self::Frame,
+        ) -> nb::Result<Option<Self::Frame>, Self::Error> {
+            if *self.transmit_error.lock().unwrap() {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.output.lock().unwrap().push_back(frame.clone());
+            Ok(None)
+        }
+
+        fn receive(&mut self) -> nb::Result<Self::Frame, Self::Error> {
+            self.input
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or(nb::Error::WouldBlock)
+        }
+    }
 }
 
 pub mod frame {
@@ -1,5 +1,3 @@
-/// Provides a point of time since start of the application
-
 /// Instants describes a point in time
 /// The inner type is a strictly monotonic timestamp in milliseconds
 pub type Instant = fugit::TimerInstantU64<1000>;
@@ -23,6 +21,12 @@ pub mod std {
     #[derive(Clone)]
     pub struct StdTimerDriver(::std::time::Instant);
 
+    impl Default for StdTimerDriver {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
     impl StdTimerDriver {
         /// Creates a new StdTimerDriver
         pub fn new() -> Self {
@@ -36,4 +40,12 @@ pub mod std {
             Instant::from_ticks(duration as u64)
         }
     }
+
+    #[cfg(feature = "tokio")]
+    impl crate::async_stack::AsyncTimer for StdTimerDriver {
+        async fn sleep_until(&self, deadline: Instant) {
+            let deadline = self.0 + ::std::time::Duration::from_millis(deadline.ticks());
+            ::tokio::time::sleep_until(deadline.into()).await
+        }
+    }
 }
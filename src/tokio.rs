@@ -0,0 +1,387 @@
+use crate::address_pool::AddressPool;
+use crate::async_stack::{process_once, AsyncDriver, BufferDriver, SharedStack};
+use crate::config::StackConfig;
+use crate::frame::Frame;
+use crate::name::Name;
+use crate::stack::{ControlFunctionHandle, Stack};
+use crate::time::std::StdTimerDriver;
+use crate::transport::{TransferHandle, TransferState};
+use crate::Error;
+use ::tokio::sync::mpsc::error::TrySendError;
+use ::tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use ::tokio::sync::Notify;
+use ::tokio::task::JoinHandle;
+use socketcan::CanFrame;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Stack driven by a [`TokioStack`] task
+pub type SyncStack = Stack<BufferDriver<CanFrame>, StdTimerDriver>;
+
+/// Async driver for the tokio socket of socketcan
+pub struct SocketCanDriver(socketcan::tokio::CanSocket);
+
+impl SocketCanDriver {
+    /// Opens the can interface with the given name, e.g. `vcan0`
+    pub fn open(ifname: &str) -> Result<Self, Error> {
+        socketcan::tokio::CanSocket::open(ifname)
+            .map(Self)
+            .map_err(Error::io)
+    }
+
+    /// Creates a driver from an opened socket
+    pub fn new(socket: socketcan::tokio::CanSocket) -> Self {
+        Self(socket)
+    }
+}
+
+impl AsyncDriver for SocketCanDriver {
+    type Frame = CanFrame;
+
+    async fn transmit(&mut self, frame: &CanFrame) -> Result<(), Error> {
+        self.0.write_frame(*frame).await.map_err(Error::io)
+    }
+
+    async fn receive(&mut self) -> Result<CanFrame, Error> {
+        self.0.read_frame().await.map_err(Error::io)
+    }
+}
+
+/// In memory stand-in for a can bus between two stacks, e.g. for tests without a can interface
+pub struct MemoryDriver {
+    tx: UnboundedSender<CanFrame>,
+    rx: UnboundedReceiver<CanFrame>,
+}
+
+impl MemoryDriver {
+    /// Creates two connected drivers, frames transmitted by one are received by the other
+    pub fn pair() -> (Self, Self) {
+        let (tx_a, rx_b) = mpsc::unbounded_channel();
+        let (tx_b, rx_a) = mpsc::unbounded_channel();
+        (Self { tx: tx_a, rx: rx_a }, Self { tx: tx_b, rx: rx_b })
+    }
+}
+
+impl AsyncDriver for MemoryDriver {
+    type Frame = CanFrame;
+
+    async fn transmit(&mut self, frame: &CanFrame) -> Result<(), Error> {
+        self.tx
+            .send(*frame)
+            .map_err(|_| Error::Driver(embedded_can::ErrorKind::Other))
+    }
+
+    async fn receive(&mut self) -> Result<CanFrame, Error> {
+        self.rx
+            .recv()
+            .await
+            .ok_or(Error::Driver(embedded_can::ErrorKind::Other))
+    }
+}
+
+/// Channel of the frames received by the stack (None) or a control function
+struct FrameChannel {
+    handle: Option<ControlFunctionHandle>,
+    sender: Sender<Frame>,
+    /// number of frames dropped because the channel was full
+    dropped_frames: u32,
+}
+
+struct Shared {
+    stack: Mutex<SyncStack>,
+    time: StdTimerDriver,
+    /// wakes the task after the stack was changed
+    notify: Notify,
+    /// capacity of the channel of the stack, see [`StackConfig::rx_queue_capacity`]
+    rx_capacity: usize,
+    /// capacity of the channels of the control functions, see [`StackConfig::cf_rx_queue_capacity`]
+    cf_rx_capacity: usize,
+    receivers: Mutex<Vec<FrameChannel>>,
+    errors: Mutex<Option<UnboundedSender<Error>>>,
+}
+
+impl SharedStack for Shared {
+    type Frame = CanFrame;
+    type Time = StdTimerDriver;
+
+    fn with_stack<R>(&self, f: impl FnOnce(&mut SyncStack) -> R) -> R {
+        f(&mut self.stack.lock().unwrap())
+    }
+
+    async fn changed(&self) {
+        self.notify.notified().await
+    }
+}
+
+/// Stack for the tokio runtime, driven by the task of [`TokioStack::run`]
+/// Received frames are forwarded into a channel per control function and a channel for the stack
+/// Frames are dropped if a channel is full, see [`TokioStack::dropped_frames`]
+/// The stack is cheap to clone and can be used by tasks on any thread
+#[derive(Clone)]
+pub struct TokioStack {
+    shared: Arc<Shared>,
+}
+
+impl Default for TokioStack {
+    fn default() -> Self {
+        Self::new(StackConfig::default())
+    }
+}
+
+impl TokioStack {
+    /// Creates a new stack, configured by the given [`StackConfig`]
+    /// The stack does nothing till [`TokioStack::run`] is awaited
+    pub fn new(config: StackConfig) -> Self {
+        let time = StdTimerDriver::new();
+        Self {
+            shared: Arc::new(Shared {
+                rx_capacity: config.rx_queue_capacity,
+                cf_rx_capacity: config.cf_rx_queue_capacity,
+                stack: Mutex::new(Stack::new_with_config(
+                    BufferDriver::new(),
+                    time.clone(),
+                    config,
+                )),
+                time,
+                notify: Notify::new(),
+                receivers: Mutex::new(Vec::new()),
+                errors: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Opens the can interface and spawns the task driving the stack
+    /// The task ends with the error of the socket
+    pub fn spawn(ifname: &str, config: StackConfig) -> Result<(Self, JoinHandle<Error>), Error> {
+        let driver = SocketCanDriver::open(ifname)?;
+        let stack = Self::new(config);
+        let runner = stack.clone();
+        let task = ::tokio::spawn(async move { runner.run(driver).await });
+        Ok((stack, task))
+    }
+
+    /// Gives access to the synchronous stack, e.g. to set the accepted addresses or poll transfers
+    /// The task processes the stack afterwards
+    pub fn with_stack<R>(&self, f: impl FnOnce(&mut SyncStack) -> R) -> R {
+        let result = f(&mut self.lock());
+        self.shared.notify.notify_one();
+        result
+    }
+
+    /// Creates a new [`ControlFunction`](crate::control_function::ControlFunction) with a preferred address and Name
+    /// Returns the channel of the frames received by the control function
+    pub fn register_control_function(
+        &self,
+        preferred_address: u8,
+        name: Name,
+    ) -> (ControlFunctionHandle, Receiver<Frame>) {
+        self.register_control_function_with_pool(preferred_address, name, AddressPool::default())
+    }
+
+    /// Creates a new [`ControlFunction`](crate::control_function::ControlFunction) with a preferred address, Name and [`AddressPool`]
+    /// Returns the channel of the frames received by the control function
+    pub fn register_control_function_with_pool(
+        &self,
+        preferred_address: u8,
+        name: Name,
+        address_pool: AddressPool,
+    ) -> (ControlFunctionHandle, Receiver<Frame>) {
+        let handle = self.with_stack(|stack| {
+            stack.register_control_function_with_pool(preferred_address, name, address_pool)
+        });
+        let (sender, receiver) = mpsc::channel(self.shared.cf_rx_capacity);
+        self.shared.receivers.lock().unwrap().push(FrameChannel {
+            handle: Some(handle),
+            sender,
+            dropped_frames: 0,
+        });
+        (handle, receiver)
+    }
+
+    /// Returns the channel of the frames received by the stack, see [`Stack::get_frame`]
+    /// A previously returned channel is closed
+    pub fn receiver(&self) -> Receiver<Frame> {
+        let (sender, receiver) = mpsc::channel(self.shared.rx_capacity);
+        let mut receivers = self.shared.receivers.lock().unwrap();
+        receivers.retain(|channel| channel.handle.is_some());
+        receivers.push(FrameChannel {
+            handle: None,
+            sender,
+            dropped_frames: 0,
+        });
+        receiver
+    }
+
+    /// Returns the number of frames dropped because the channel of the control function
+    /// or of the stack (None) was full
+    pub fn dropped_frames(&self, handle: Option<&ControlFunctionHandle>) -> u32 {
+        self.shared
+            .receivers
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|channel| channel.handle.as_ref() == handle)
+            .map_or(0, |channel| channel.dropped_frames)
+    }
+
+    /// Returns the channel of the errors reported while processing the stack
+    /// A previously returned channel is closed
+    pub fn errors(&self) -> UnboundedReceiver<Error> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        *self.shared.errors.lock().unwrap() = Some(sender);
+        receiver
+    }
+
+    /// Send a frame directly with the stack, see [`Stack::send_frame`]
+    pub fn send_frame(&self, frame: Frame) -> Result<TransferHandle, Error> {
+        self.with_stack(|stack| stack.send_frame(frame))
+    }
+
+    /// Send a frame with a control function, see [`ControlFunction::send_frame`](crate::control_function::ControlFunction::send_frame)
+    pub fn send_frame_cf(
+        &self,
+        handle: &ControlFunctionHandle,
+        frame: Frame,
+    ) -> Result<TransferHandle, Error> {
+        self.with_stack(|stack| stack.control_function(handle).send_frame(frame))
+    }
+
+    /// Returns the state of a transfer, see [`Stack::transfer_state`]
+    pub fn transfer_state(&self, handle: &TransferHandle) -> Option<TransferState> {
+        self.lock().transfer_state(handle)
+    }
+
+    /// Drives the stack with the given driver till the driver fails
+    /// Waits for received frames, frames to send or the next timer of the stack
+    pub async fn run<CanDriver: AsyncDriver<Frame = CanFrame>>(
+        &self,
+        mut driver: CanDriver,
+    ) -> Error {
+        loop {
+            match process_once(&*self.shared, &mut driver, &self.shared.time).await {
+                Ok(result) => self.forward(result),
+                Err(error) => return error,
+            }
+        }
+    }
+
+    /// Forwards the received frames and the error of processing the stack into the channels
+    fn forward(&self, result: Result<(), Error>) {
+        let mut stack = self.lock();
+        // closed channels are removed
+        self.shared
+            .receivers
+            .lock()
+            .unwrap()
+            .retain_mut(|channel| loop {
+                let frame = match &channel.handle {
+                    Some(handle) => stack.control_function(handle).get_frame(),
+                    None => stack.get_frame(),
+                };
+                let Some(frame) = frame else {
+                    break true;
+                };
+                match channel.sender.try_send(frame) {
+                    Ok(()) => (),
+                    Err(TrySendError::Full(_)) => {
+                        channel.dropped_frames = channel.dropped_frames.saturating_add(1)
+                    }
+                    Err(TrySendError::Closed(_)) => break false,
+                }
+            });
+
+        if let Err(error) = result {
+            let mut errors = self.shared.errors.lock().unwrap();
+            if errors.as_ref().is_some_and(|e| e.send(error).is_err()) {
+                *errors = None;
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SyncStack> {
+        self.shared.stack.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{Header, PGN};
+    use std::time::Duration;
+
+    fn node(address: u8) -> (TokioStack, ControlFunctionHandle, Receiver<Frame>) {
+        let stack = TokioStack::default();
+        let (handle, receiver) = stack.register_control_function(
+            address,
+            Name {
+                address_capable: false,
+                identity_number: address as u32,
+                ..Name::default()
+            },
+        );
+        (stack, handle, receiver)
+    }
+
+    #[tokio::test]
+    async fn memory_bus() {
+        let (driver_a, driver_b) = MemoryDriver::pair();
+        let (stack_a, handle_a, _) = node(0x21);
+        let (stack_b, _, mut receiver_b) = node(0x22);
+        let mut frames_b = stack_b.receiver();
+        let runner_a = stack_a.clone();
+        let runner_b = stack_b.clone();
+        tokio::spawn(async move { runner_a.run(driver_a).await });
+        tokio::spawn(async move { runner_b.run(driver_b).await });
+
+        // the address claim of the other control function is received by the stack
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while frames_b.recv().await.unwrap().header().source_address() != 0x21 {}
+        })
+        .await
+        .unwrap();
+
+        // frames are only sent by online control functions
+        while stack_a
+            .with_stack(|stack| stack.control_function(&handle_a).is_online())
+            .is_none()
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let data: Vec<u8> = (0..20).collect();
+        let handle = stack_a
+            .send_frame_cf(
+                &handle_a,
+                Frame::new(Header::new(PGN::new(0xEF00), 6, 0x21, Some(0x22)), &data),
+            )
+            .unwrap();
+        let frame = tokio::time::timeout(Duration::from_secs(1), receiver_b.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.header().source_address(), 0x21);
+        assert_eq!(frame.data(), &data[..]);
+        // the sender completes the transfer after the end of message acknowledgement
+        while stack_a.transfer_state(&handle) != Some(TransferState::Completed) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn full_channel() {
+        let (mut bus, driver) = MemoryDriver::pair();
+        let stack = TokioStack::new(StackConfig::default().rx_queue_capacity(1));
+        let mut frames = stack.receiver();
+        let runner = stack.clone();
+        tokio::spawn(async move { runner.run(driver).await });
+
+        for data in 0..3u8 {
+            let frame = Frame::new(Header::new(PGN::new(0xFEF1), 6, 0x21, None), &[data; 8]);
+            bus.transmit(&frame.can().unwrap()).await.unwrap();
+        }
+        // the frames not fitting into the channel are dropped
+        while stack.dropped_frames(None) != 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(frames.recv().await.unwrap().data(), &[0; 8]);
+        assert!(frames.try_recv().is_err());
+    }
+}
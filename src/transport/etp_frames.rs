@@ -0,0 +1,342 @@
+use crate::frame::{Frame, Header, PGN, PGN_ETP_CM, PGN_ETP_DT};
+use crate::transport::tp_frames::{from_u8, AbortReason};
+use crate::Error;
+
+// ------------------------------------------------- ETP DT --------------------------------------
+#[derive(Debug, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct ETPDT {
+    pub remote_address: u8,
+    pub local_address: u8,
+    pub sequence_number: u8,
+    pub data: [u8; 7],
+}
+
+impl From<ETPDT> for Frame {
+    fn from(etpdt: ETPDT) -> Self {
+        let mut data = [0xFF; 8];
+        data[0] = etpdt.sequence_number;
+        data[1..8].copy_from_slice(&etpdt.data);
+        Frame::new(
+            Header::new(
+                PGN_ETP_DT,
+                7,
+                etpdt.local_address,
+                Some(etpdt.remote_address),
+            ),
+            &data,
+        )
+    }
+}
+
+impl ETPDT {
+    pub fn from_frame(header: Header, data: &[u8]) -> Result<Self, Error> {
+        let (Some(local_address), Ok(data)) =
+            (header.destination_address(), <&[u8; 8]>::try_from(data))
+        else {
+            return Err(Error::MalformedFrame);
+        };
+        let [sequence_number, data @ ..] = *data;
+        Ok(Self {
+            remote_address: header.source_address(),
+            local_address,
+            sequence_number,
+            data,
+        })
+    }
+}
+
+// ------------------------------------------------- ETP CM --------------------------------------
+
+#[derive(Debug, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum ETPCM {
+    Rts {
+        message_size: u32,
+        pgn: PGN,
+        remote_address: u8,
+        local_address: u8,
+    },
+    Cts {
+        expected_packets: u8,
+        next_packet_number: u32,
+        pgn: PGN,
+        remote_address: u8,
+        local_address: u8,
+    },
+    Dpo {
+        packet_count: u8,
+        packet_offset: u32,
+        pgn: PGN,
+        remote_address: u8,
+        local_address: u8,
+    },
+    EndOfMsg {
+        message_size: u32,
+        pgn: PGN,
+        remote_address: u8,
+        local_address: u8,
+    },
+    Abort {
+        abort_reason: AbortReason,
+        pgn: PGN,
+        remote_address: u8,
+        local_address: u8,
+    },
+}
+
+const CTRL_RTS: u8 = 20;
+const CTRL_CTS: u8 = 21;
+const CTRL_DPO: u8 = 22;
+const CTRL_END_OF_MSG_ACK: u8 = 23;
+const CTRL_CONN_ABORT: u8 = 255;
+
+impl From<ETPCM> for Frame {
+    fn from(etpcm: ETPCM) -> Self {
+        let mut data = [0xFF; 8];
+        let sa;
+        let da;
+        let pgn;
+        match etpcm {
+            ETPCM::Rts {
+                message_size,
+                pgn: p,
+                remote_address,
+                local_address,
+            } => {
+                data[0] = CTRL_RTS;
+                data[1..5].copy_from_slice(&message_size.to_le_bytes());
+                pgn = p;
+                sa = local_address;
+                da = remote_address;
+            }
+            ETPCM::Cts {
+                expected_packets,
+                next_packet_number,
+                pgn: p,
+                remote_address,
+                local_address,
+            } => {
+                data[0] = CTRL_CTS;
+                data[1] = expected_packets;
+                data[2..5].copy_from_slice(&next_packet_number.to_le_bytes()[0..3]);
+                pgn = p;
+                sa = local_address;
+                da = remote_address;
+            }
+            ETPCM::Dpo {
+                packet_count,
+                packet_offset,
+                pgn: p,
+                remote_address,
+                local_address,
+            } => {
+                data[0] = CTRL_DPO;
+                data[1] = packet_count;
+                data[2..5].copy_from_slice(&packet_offset.to_le_bytes()[0..3]);
+                pgn = p;
+                sa = local_address;
+                da = remote_address;
+            }
+            ETPCM::EndOfMsg {
+                message_size,
+                pgn: p,
+                remote_address,
+                local_address,
+            } => {
+                data[0] = CTRL_END_OF_MSG_ACK;
+                data[1..5].copy_from_slice(&message_size.to_le_bytes());
+                pgn = p;
+                sa = local_address;
+                da = remote_address;
+            }
+            ETPCM::Abort {
+                abort_reason,
+                pgn: p,
+                remote_address,
+                local_address,
+            } => {
+                data[0] = CTRL_CONN_ABORT;
+                data[1] = abort_reason as u8;
+                pgn = p;
+                sa = local_address;
+                da = remote_address;
+            }
+        };
+        data[5] = pgn.raw() as u8;
+        data[6] = (pgn.raw() >> 8) as u8;
+        data[7] = (pgn.raw() >> 16) as u8;
+        Frame::new(Header::new(PGN_ETP_CM, 7, sa, Some(da)), &data)
+    }
+}
+
+impl ETPCM {
+    pub fn from_frame(header: Header, data: &[u8]) -> Result<Self, Error> {
+        let (Some(local_address), Ok(data)) =
+            (header.destination_address(), <&[u8; 8]>::try_from(data))
+        else {
+            return Err(Error::MalformedFrame);
+        };
+        let data_pgn: PGN = PGN::new(u32::from_le_bytes([data[5], data[6], data[7], 0x00]));
+        let remote_address = header.source_address();
+        Ok(match data[0] {
+            CTRL_RTS => ETPCM::Rts {
+                message_size: u32::from_le_bytes([data[1], data[2], data[3], data[4]]),
+                pgn: data_pgn,
+                remote_address,
+                local_address,
+            },
+            CTRL_CTS => ETPCM::Cts {
+                expected_packets: data[1],
+                next_packet_number: u32::from_le_bytes([data[2], data[3], data[4], 0x00]),
+                pgn: data_pgn,
+                remote_address,
+                local_address,
+            },
+            CTRL_DPO => ETPCM::Dpo {
+                packet_count: data[1],
+                packet_offset: u32::from_le_bytes([data[2], data[3], data[4], 0x00]),
+                pgn: data_pgn,
+                remote_address,
+                local_address,
+            },
+            CTRL_END_OF_MSG_ACK => ETPCM::EndOfMsg {
+                message_size: u32::from_le_bytes([data[1], data[2], data[3], data[4]]),
+                pgn: data_pgn,
+                remote_address,
+                local_address,
+            },
+            CTRL_CONN_ABORT => ETPCM::Abort {
+                abort_reason: from_u8(data[1]),
+                pgn: data_pgn,
+                remote_address,
+                local_address,
+            },
+            _ => return Err(Error::MalformedFrame),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_pdu_etpdt() {
+        let frame: Frame = ETPDT {
+            remote_address: 1,
+            local_address: 50,
+            sequence_number: 2,
+            data: [1, 2, 3, 4, 5, 6, 7],
+        }
+        .into();
+        assert_eq!(
+            frame,
+            Frame::new(Header::from(0x1CC70132), &[2, 1, 2, 3, 4, 5, 6, 7])
+        )
+    }
+    #[test]
+    fn serialize_pdu_etpcm_rts() {
+        let frame: Frame = ETPCM::Rts {
+            message_size: 100_000,
+            pgn: PGN::new(0xE700),
+            remote_address: 0x26,
+            local_address: 0x80,
+        }
+        .into();
+        assert_eq!(
+            frame,
+            Frame::new(
+                Header::from(0x1CC82680),
+                &[20, 0xA0, 0x86, 0x01, 0x00, 0x00, 0xE7, 0x00]
+            )
+        )
+    }
+    #[test]
+    fn deserialize_pdu_etpcm_rts() {
+        let pdu = ETPCM::from_frame(
+            Header::from(0x1CC82680),
+            &[20, 0xA0, 0x86, 0x01, 0x00, 0x00, 0xE7, 0x00],
+        )
+        .unwrap();
+        assert_eq!(
+            pdu,
+            ETPCM::Rts {
+                message_size: 100_000,
+                pgn: PGN::new(0xE700),
+                remote_address: 0x80,
+                local_address: 0x26,
+            }
+        );
+    }
+    #[test]
+    fn serialize_pdu_etpcm_cts() {
+        let frame: Frame = ETPCM::Cts {
+            expected_packets: 16,
+            next_packet_number: 0x012345,
+            pgn: PGN::new(0xE700),
+            remote_address: 0x80,
+            local_address: 0x26,
+        }
+        .into();
+        assert_eq!(
+            frame,
+            Frame::new(
+                Header::from(0x1CC88026),
+                &[21, 16, 0x45, 0x23, 0x01, 0x00, 0xE7, 0x00]
+            )
+        )
+    }
+    #[test]
+    fn deserialize_pdu_etpcm_dpo() {
+        let pdu = ETPCM::from_frame(
+            Header::from(0x1CC82680),
+            &[22, 16, 0x45, 0x23, 0x01, 0x00, 0xE7, 0x00],
+        )
+        .unwrap();
+        assert_eq!(
+            pdu,
+            ETPCM::Dpo {
+                packet_count: 16,
+                packet_offset: 0x012345,
+                pgn: PGN::new(0xE700),
+                remote_address: 0x80,
+                local_address: 0x26,
+            }
+        );
+    }
+    #[test]
+    fn serialize_pdu_etpcm_ack() {
+        let frame: Frame = ETPCM::EndOfMsg {
+            message_size: 100_000,
+            pgn: PGN::new(0xE700),
+            remote_address: 0x80,
+            local_address: 0x26,
+        }
+        .into();
+        assert_eq!(
+            frame,
+            Frame::new(
+                Header::from(0x1CC88026),
+                &[23, 0xA0, 0x86, 0x01, 0x00, 0x00, 0xE7, 0x00]
+            )
+        )
+    }
+    #[test]
+    fn deserialize_pdu_etpcm_abort() {
+        let pdu = ETPCM::from_frame(
+            Header::from(0x1CC82680),
+            &[255, 3, 255, 255, 255, 0x00, 0xE7, 0x00],
+        )
+        .unwrap();
+        assert_eq!(
+            pdu,
+            ETPCM::Abort {
+                abort_reason: AbortReason::Timeout,
+                pgn: PGN::new(0xE700),
+                remote_address: 0x80,
+                local_address: 0x26,
+            }
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use crate::frame::{Frame, PGN};
+use crate::transport::{AbortReason, TransferHandle};
+
+/// Direction of a transport session seen from this stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportDirection {
+    /// The message is received from a remote control function
+    Receive,
+    /// The message is sent by this stack
+    Transmit,
+}
+
+/// Identifies a transport session in a [`TransportEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransportSession {
+    /// Direction of the transfer
+    pub direction: TransportDirection,
+    /// PGN of the transferred message
+    pub pgn: PGN,
+    /// Source address of the transferred message
+    pub source_address: u8,
+    /// Destination address of the transferred message, 0xFF for broadcasts (BAM)
+    pub destination_address: u8,
+    /// Size of the whole message in bytes
+    pub message_size: usize,
+    /// Handle returned by `send_frame` for transmitted messages
+    pub handle: Option<TransferHandle>,
+}
+
+/// State changes of transport protocol sessions (TP, BAM and ETP)
+/// Progress is reported after each finished CTS window, broadcasts report progress after each packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportEvent {
+    /// A new session was opened by an RTS or BAM
+    Started(TransportSession),
+    /// Number of bytes transferred so far
+    Progress {
+        /// The session of the transfer
+        session: TransportSession,
+        /// Transferred bytes
+        bytes: usize,
+    },
+    /// The whole message was transferred
+    Completed(TransportSession),
+    /// The session was closed by a connection abort
+    Aborted {
+        /// The session of the transfer
+        session: TransportSession,
+        /// Reason of the abort
+        reason: AbortReason,
+        /// true if the abort was sent by the remote control function
+        remote: bool,
+    },
+    /// The remote control function did not respond in time
+    TimedOut(TransportSession),
+}
+
+impl TransportSession {
+    pub(crate) fn receive(pgn: PGN, remote_address: u8, local_address: u8, size: usize) -> Self {
+        Self {
+            direction: TransportDirection::Receive,
+            pgn,
+            source_address: remote_address,
+            destination_address: local_address,
+            message_size: size,
+            handle: None,
+        }
+    }
+
+    pub(crate) fn transmit(pdu: &Frame, handle: Option<TransferHandle>) -> Self {
+        Self {
+            direction: TransportDirection::Transmit,
+            pgn: pdu.header().pgn(),
+            source_address: pdu.header().source_address(),
+            destination_address: pdu.header().destination_address().unwrap_or(0xFF),
+            message_size: pdu.data().len(),
+            handle,
+        }
+    }
+}
@@ -0,0 +1,648 @@
+use crate::frame::{Frame, Header, Timestamp, PGN};
+use crate::time::Instant;
+use crate::transport::etp_frames::*;
+use crate::transport::tp_frames::AbortReason;
+use crate::transport::{
+    TransferHandle, TransportEvent, TransportSession, MAX_RETRANSMITS, T1, T2, T3, T4,
+    TP_MAX_MESSAGE_SIZE,
+};
+use crate::tx_queue::TxQueue;
+use crate::Error;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Largest message which can be transferred by the extended transport protocol
+/// (2^24 - 1 packets with 7 bytes each)
+const ETP_MAX_MESSAGE_SIZE: u32 = 117_440_505;
+
+struct ExtendedP2PReceiver {
+    pub data: Vec<u8>,
+    pub message_size: u32,
+    pub pgn: PGN,
+    pub priority: u8,
+    /// number of packets requested by the last CTS
+    pub requested_packets: u8,
+    /// packet number (1 based) of the next expected packet
+    pub next_packet_number: u32,
+    /// offset and packet count announced by the last DPO, None while waiting for a DPO
+    pub packet_offset: Option<u32>,
+    pub dpo_packets: u8,
+    pub last_sequence_number: u8,
+    pub timeout: Instant,
+    /// receive time of the RTS
+    pub started: Instant,
+}
+
+impl ExtendedP2PReceiver {
+    fn remaining_packets(&self) -> u32 {
+        (self.message_size - self.data.len() as u32).div_ceil(7)
+    }
+
+    fn session(&self, remote_address: u8, local_address: u8) -> TransportSession {
+        TransportSession::receive(
+            self.pgn,
+            remote_address,
+            local_address,
+            self.message_size as usize,
+        )
+    }
+}
+
+struct ExtendedP2PSender {
+    pub pdu: Frame,
+    pub handle: Option<TransferHandle>,
+    /// number of packets already sent (0 based index of the next packet)
+    pub last_packet_index: u32,
+    /// packets are sent until this index is reached, requested by the last CTS
+    pub send_till_index: u32,
+    /// packet offset announced with the last DPO
+    pub packet_offset: u32,
+    /// number of packets sent at least once
+    pub sent_packets: u32,
+    /// consecutive CTS requesting already sent packets
+    pub retransmits: u8,
+    /// only checked while no packets are pending
+    pub timeout: Instant,
+}
+
+impl ExtendedP2PSender {
+    fn packet_count(&self) -> u32 {
+        (self.pdu.data().len() as u32).div_ceil(7)
+    }
+
+    fn session(&self) -> TransportSession {
+        TransportSession::transmit(&self.pdu, self.handle)
+    }
+}
+
+/// Extended transport protocol as defined by ISO 11783-3
+/// Used for peer to peer messages larger than 1785 bytes
+pub struct ExtendedTransportPackager {
+    /// maximum packets requested by our CTS
+    receive_window: u8,
+    in_p2p: BTreeMap<(u8, u8), ExtendedP2PReceiver>,
+    out_p2p: BTreeMap<(u8, u8), ExtendedP2PSender>,
+    events: Vec<TransportEvent>,
+}
+
+impl ExtendedTransportPackager {
+    pub fn new(receive_window: u8) -> Self {
+        Self {
+            receive_window,
+            in_p2p: BTreeMap::new(),
+            out_p2p: BTreeMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Returns all events since the last call
+    pub fn take_events(&mut self) -> alloc::vec::Drain<'_, TransportEvent> {
+        self.events.drain(..)
+    }
+
+    pub fn process_etpcm(
+        &mut self,
+        etpcm: ETPCM,
+        priority: u8,
+        now: Instant,
+        tx_queue: &mut TxQueue,
+    ) -> Result<(), Error> {
+        match etpcm {
+            ETPCM::Rts {
+                message_size,
+                pgn,
+                remote_address,
+                local_address,
+            } => {
+                if self.in_p2p.contains_key(&(remote_address, local_address)) {
+                    Self::abort(
+                        AbortReason::AlreadyConnected,
+                        pgn,
+                        remote_address,
+                        local_address,
+                        tx_queue,
+                    )?;
+                    return Ok(());
+                }
+                if message_size as usize <= TP_MAX_MESSAGE_SIZE {
+                    // smaller messages are sent with the transport protocol, no reason covers them
+                    Self::abort(
+                        AbortReason::Other,
+                        pgn,
+                        remote_address,
+                        local_address,
+                        tx_queue,
+                    )?;
+                    return Ok(());
+                }
+                let mut data = Vec::new();
+                if message_size > ETP_MAX_MESSAGE_SIZE
+                    || data.try_reserve_exact(message_size as usize).is_err()
+                {
+                    Self::abort(
+                        AbortReason::NoResources,
+                        pgn,
+                        remote_address,
+                        local_address,
+                        tx_queue,
+                    )?;
+                    return Ok(());
+                }
+                let mut rec = ExtendedP2PReceiver {
+                    data,
+                    message_size,
+                    pgn,
+                    priority,
+                    requested_packets: 0,
+                    next_packet_number: 1,
+                    packet_offset: None,
+                    dpo_packets: 0,
+                    last_sequence_number: 0,
+                    timeout: now,
+                    started: now,
+                };
+                Self::request_packets(
+                    &mut rec,
+                    self.receive_window,
+                    remote_address,
+                    local_address,
+                    now,
+                    tx_queue,
+                )?;
+                self.events.push(TransportEvent::Started(
+                    rec.session(remote_address, local_address),
+                ));
+                self.in_p2p.insert((remote_address, local_address), rec);
+            }
+            ETPCM::Dpo {
+                packet_count,
+                packet_offset,
+                pgn,
+                remote_address,
+                local_address,
+            } => {
+                if let Some(rec) = self.in_p2p.get_mut(&(remote_address, local_address)) {
+                    let abort_reason = if rec.pgn != pgn {
+                        Some(AbortReason::UnexpectedDpoPgn)
+                    } else if packet_offset + 1 != rec.next_packet_number {
+                        Some(AbortReason::BadDpoOffset)
+                    } else if packet_count > rec.requested_packets {
+                        Some(AbortReason::DpoPacketsGreaterCts)
+                    } else {
+                        None
+                    };
+                    if let Some(abort_reason) = abort_reason {
+                        self.events.push(TransportEvent::Aborted {
+                            session: rec.session(remote_address, local_address),
+                            reason: abort_reason,
+                            remote: false,
+                        });
+                        Self::abort(
+                            abort_reason,
+                            rec.pgn,
+                            remote_address,
+                            local_address,
+                            tx_queue,
+                        )?;
+                        self.in_p2p.remove(&(remote_address, local_address));
+                    } else {
+                        rec.packet_offset = Some(packet_offset);
+                        rec.dpo_packets = packet_count;
+                        rec.last_sequence_number = 0;
+                        rec.timeout = now + T1;
+                    }
+                }
+            }
+            ETPCM::Cts {
+                expected_packets,
+                next_packet_number,
+                pgn,
+                remote_address,
+                local_address,
+            } => {
+                if let Some(sender) = self.out_p2p.get_mut(&(local_address, remote_address)) {
+                    let window_start = next_packet_number.saturating_sub(1);
+                    if expected_packets > 0 && window_start < sender.sent_packets {
+                        // the receiver requests a retransmission of already sent packets
+                        sender.retransmits += 1;
+                    } else if expected_packets > 0 {
+                        sender.retransmits = 0;
+                    }
+                    let abort_reason = if sender.pdu.header().pgn() != pgn {
+                        Some(AbortReason::UnexpectedCtsPgn)
+                    } else if next_packet_number == 0
+                        || window_start + expected_packets as u32 > sender.packet_count()
+                    {
+                        Some(AbortReason::CtsPacketsExceedMessage)
+                    } else if sender.retransmits > MAX_RETRANSMITS {
+                        Some(AbortReason::RetransmitLimit)
+                    } else {
+                        None
+                    };
+                    if let Some(abort_reason) = abort_reason {
+                        self.events.push(TransportEvent::Aborted {
+                            session: sender.session(),
+                            reason: abort_reason,
+                            remote: false,
+                        });
+                        Self::abort(
+                            abort_reason,
+                            sender.pdu.header().pgn(),
+                            remote_address,
+                            local_address,
+                            tx_queue,
+                        )?;
+                        self.out_p2p.remove(&(local_address, remote_address));
+                    } else if expected_packets == 0 {
+                        // hold the connection open, wait for the next CTS
+                        sender.send_till_index = sender.last_packet_index;
+                        sender.timeout = now + T4;
+                    } else {
+                        let dpo = ETPCM::Dpo {
+                            packet_count: expected_packets,
+                            packet_offset: window_start,
+                            pgn,
+                            remote_address,
+                            local_address,
+                        };
+                        // the window is only sent if the DPO could be queued
+                        tx_queue.push(Frame::from(dpo))?;
+                        sender.last_packet_index = window_start;
+                        sender.send_till_index = window_start + expected_packets as u32;
+                        sender.packet_offset = window_start;
+                    }
+                }
+            }
+            ETPCM::EndOfMsg {
+                message_size: _,
+                pgn,
+                remote_address,
+                local_address,
+            } => {
+                if let Some(transfer) = self.out_p2p.get(&(local_address, remote_address)) {
+                    if transfer.pdu.header().pgn() == pgn {
+                        self.events
+                            .push(TransportEvent::Completed(transfer.session()));
+                        self.out_p2p.remove(&(local_address, remote_address));
+                    }
+                }
+            }
+            ETPCM::Abort {
+                abort_reason,
+                pgn,
+                remote_address,
+                local_address,
+            } => {
+                if let Some(transfer) = self.in_p2p.get(&(remote_address, local_address)) {
+                    if transfer.pgn == pgn {
+                        self.events.push(TransportEvent::Aborted {
+                            session: transfer.session(remote_address, local_address),
+                            reason: abort_reason,
+                            remote: true,
+                        });
+                        self.in_p2p.remove(&(remote_address, local_address));
+                    }
+                }
+                if let Some(transfer) = self.out_p2p.get(&(local_address, remote_address)) {
+                    if transfer.pdu.header().pgn() == pgn {
+                        self.events.push(TransportEvent::Aborted {
+                            session: transfer.session(),
+                            reason: abort_reason,
+                            remote: true,
+                        });
+                        self.out_p2p.remove(&(local_address, remote_address));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn new_out_transfer(
+        &mut self,
+        pdu: Frame,
+        handle: Option<TransferHandle>,
+        now: Instant,
+        tx_queue: &mut TxQueue,
+    ) -> Result<(), Error> {
+        let source_address = pdu.header().source_address();
+        let destination_address = pdu.header().destination_address();
+        let sender = ExtendedP2PSender {
+            pdu,
+            handle,
+            last_packet_index: 0,
+            send_till_index: 0,
+            packet_offset: 0,
+            sent_packets: 0,
+            retransmits: 0,
+            timeout: now + T3,
+        };
+        let abort_reason = match destination_address {
+            // the extended transport protocol supports only peer to peer transfers
+            None | Some(0xFF) => Some(AbortReason::MessageSizeToHigh),
+            _ if sender.pdu.data().len() > ETP_MAX_MESSAGE_SIZE as usize => {
+                Some(AbortReason::MessageSizeToHigh)
+            }
+            // only one transfer per connection is allowed
+            Some(da) if self.out_p2p.contains_key(&(source_address, da)) => {
+                Some(AbortReason::AlreadyConnected)
+            }
+            _ => None,
+        };
+        if let Some(reason) = abort_reason {
+            self.events.push(TransportEvent::Aborted {
+                session: sender.session(),
+                reason,
+                remote: false,
+            });
+            return Ok(());
+        }
+        let destination_address = destination_address.unwrap();
+
+        let rts = ETPCM::Rts {
+            message_size: sender.pdu.data().len() as u32,
+            pgn: sender.pdu.header().pgn(),
+            remote_address: destination_address,
+            local_address: source_address,
+        };
+        tx_queue.push(Frame::from(rts))?;
+        self.events.push(TransportEvent::Started(sender.session()));
+        self.out_p2p
+            .insert((source_address, destination_address), sender);
+        Ok(())
+    }
+
+    /// Aborts the transmission of the transfer
+    /// Returns false if the handle belongs to no active transfer
+    pub fn cancel_transfer(
+        &mut self,
+        handle: TransferHandle,
+        tx_queue: &mut TxQueue,
+    ) -> Result<bool, Error> {
+        let Some((local_address, remote_address)) = self
+            .out_p2p
+            .iter()
+            .find(|(_, sender)| sender.handle == Some(handle))
+            .map(|(key, _)| *key)
+        else {
+            return Ok(false);
+        };
+        let sender = self
+            .out_p2p
+            .remove(&(local_address, remote_address))
+            .unwrap();
+        Self::abort(
+            AbortReason::NoResources,
+            sender.pdu.header().pgn(),
+            remote_address,
+            local_address,
+            tx_queue,
+        )?;
+        Ok(true)
+    }
+
+    pub fn process_out_transfers(
+        &mut self,
+        now: Instant,
+        tx_queue: &mut TxQueue,
+    ) -> Result<(), Error> {
+        // a failed send does not stop the other transfers, the first error is returned
+        let mut result = Ok(());
+        for sender in self.out_p2p.values_mut() {
+            let window_pending = sender.last_packet_index < sender.send_till_index;
+            // send the whole window requested by the receiver, the rest of the window is sent
+            // with the next call if the transmit queue is full
+            while sender.last_packet_index < sender.send_till_index && !tx_queue.is_full() {
+                let mut data = [0xFF; 7];
+                let start = sender.last_packet_index as usize * 7;
+                let stop = (start + 7).min(sender.pdu.data().len());
+                data[0..(stop - start)].copy_from_slice(&sender.pdu.data()[start..stop]);
+
+                let etpdt = ETPDT {
+                    remote_address: sender.pdu.header().destination_address().unwrap(),
+                    local_address: sender.pdu.header().source_address(),
+                    sequence_number: (sender.last_packet_index - sender.packet_offset + 1) as u8,
+                    data,
+                };
+                // the packet is retried with the next call if it could not be queued
+                if let Err(error) = tx_queue.push(Frame::from(etpdt)) {
+                    result = result.and(Err(error));
+                    break;
+                }
+                sender.last_packet_index += 1;
+                sender.sent_packets = sender.sent_packets.max(sender.last_packet_index);
+                // wait for the next CTS or the end of message acknowledgement
+                sender.timeout = now + T3;
+            }
+            // the completion is reported with the end of message acknowledgement
+            if window_pending && sender.last_packet_index < sender.packet_count() {
+                self.events.push(TransportEvent::Progress {
+                    session: sender.session(),
+                    bytes: sender.last_packet_index as usize * 7,
+                });
+            }
+        }
+        result
+    }
+
+    /// Returns the next instant a session times out or a packet has to be sent
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.in_p2p
+            .values()
+            .map(|rec| rec.timeout)
+            .chain(self.out_p2p.values().map(|sender| {
+                if sender.last_packet_index < sender.send_till_index {
+                    // packets of the requested window are pending
+                    Instant::from_ticks(0)
+                } else {
+                    sender.timeout
+                }
+            }))
+            .min()
+    }
+
+    /// Aborts all sessions whose peer did not respond in time
+    pub fn process_timeouts(&mut self, now: Instant, tx_queue: &mut TxQueue) -> Result<(), Error> {
+        // all timed out sessions are closed, the first transmit error is returned
+        let mut result = Ok(());
+        let events = &mut self.events;
+        self.in_p2p.retain(|(remote_address, local_address), rec| {
+            if now < rec.timeout {
+                return true;
+            }
+            events.push(TransportEvent::TimedOut(
+                rec.session(*remote_address, *local_address),
+            ));
+            result = result.and(Self::abort(
+                AbortReason::Timeout,
+                rec.pgn,
+                *remote_address,
+                *local_address,
+                tx_queue,
+            ));
+            false
+        });
+        self.out_p2p
+            .retain(|(local_address, remote_address), sender| {
+                if sender.last_packet_index < sender.send_till_index || now < sender.timeout {
+                    return true;
+                }
+                events.push(TransportEvent::TimedOut(sender.session()));
+                result = result.and(Self::abort(
+                    AbortReason::Timeout,
+                    sender.pdu.header().pgn(),
+                    *remote_address,
+                    *local_address,
+                    tx_queue,
+                ));
+                false
+            });
+        result
+    }
+
+    pub fn process_etpdt(
+        &mut self,
+        etpdt: ETPDT,
+        now: Instant,
+        tx_queue: &mut TxQueue,
+    ) -> Result<Option<Frame>, Error> {
+        let key = (etpdt.remote_address, etpdt.local_address);
+        let Some(rec) = self.in_p2p.get_mut(&key) else {
+            // Abort unexpected transfer
+            Self::abort(
+                AbortReason::UnexpectedTransfer,
+                PGN::new(0xFFFF_FFFF), // PGN is not known
+                etpdt.remote_address,
+                etpdt.local_address,
+                tx_queue,
+            )?;
+            return Ok(None);
+        };
+
+        let abort_reason = if rec.packet_offset.is_none() {
+            // data without a preceding DPO
+            Some(AbortReason::UnexpectedTransfer)
+        } else if rec.last_sequence_number != 0 && etpdt.sequence_number == rec.last_sequence_number
+        {
+            Some(AbortReason::DuplicateSequenceNumber)
+        } else if etpdt.sequence_number != rec.last_sequence_number + 1
+            || etpdt.sequence_number > rec.dpo_packets
+        {
+            Some(AbortReason::BadSequenceNumber)
+        } else {
+            None
+        };
+        if let Some(abort_reason) = abort_reason {
+            self.events.push(TransportEvent::Aborted {
+                session: rec.session(etpdt.remote_address, etpdt.local_address),
+                reason: abort_reason,
+                remote: false,
+            });
+            Self::abort(
+                abort_reason,
+                rec.pgn,
+                etpdt.remote_address,
+                etpdt.local_address,
+                tx_queue,
+            )?;
+            self.in_p2p.remove(&key);
+            return Ok(None);
+        }
+
+        let missing_bytes = rec.message_size as usize - rec.data.len();
+        if missing_bytes <= 7 {
+            // last packet, the session is only closed if the acknowledgement could be queued
+            let ack = ETPCM::EndOfMsg {
+                message_size: rec.message_size,
+                pgn: rec.pgn,
+                remote_address: etpdt.remote_address,
+                local_address: etpdt.local_address,
+            };
+            tx_queue.push(Frame::from(ack))?;
+            let mut entry = self.in_p2p.remove(&key).unwrap();
+            entry.data.extend_from_slice(&etpdt.data[0..missing_bytes]);
+            self.events.push(TransportEvent::Completed(
+                entry.session(etpdt.remote_address, etpdt.local_address),
+            ));
+            return Ok(Some(
+                Frame::new(
+                    Header::new(
+                        entry.pgn,
+                        entry.priority,
+                        etpdt.remote_address,
+                        Some(etpdt.local_address),
+                    ),
+                    &entry.data,
+                )
+                .with_timestamp(Timestamp::transfer(entry.started, now)),
+            ));
+        }
+        rec.last_sequence_number = etpdt.sequence_number;
+        rec.next_packet_number += 1;
+        rec.data.extend_from_slice(&etpdt.data);
+        if rec.last_sequence_number == rec.dpo_packets {
+            // all packets of this window received, the packet is only taken if the next CTS could be queued
+            if let Err(error) = Self::request_packets(
+                rec,
+                self.receive_window,
+                etpdt.remote_address,
+                etpdt.local_address,
+                now,
+                tx_queue,
+            ) {
+                rec.last_sequence_number -= 1;
+                rec.next_packet_number -= 1;
+                rec.data.truncate(rec.data.len() - 7);
+                return Err(error);
+            }
+            self.events.push(TransportEvent::Progress {
+                session: rec.session(etpdt.remote_address, etpdt.local_address),
+                bytes: rec.data.len(),
+            });
+        } else {
+            rec.timeout = now + T1;
+        }
+        Ok(None)
+    }
+
+    fn request_packets(
+        rec: &mut ExtendedP2PReceiver,
+        receive_window: u8,
+        remote_address: u8,
+        local_address: u8,
+        now: Instant,
+        tx_queue: &mut TxQueue,
+    ) -> Result<(), Error> {
+        let requested_packets = rec.remaining_packets().min(receive_window as u32) as u8;
+        let cts = ETPCM::Cts {
+            expected_packets: requested_packets,
+            next_packet_number: rec.next_packet_number,
+            pgn: rec.pgn,
+            remote_address,
+            local_address,
+        };
+        // the receiver keeps its state if the CTS could not be queued
+        tx_queue.push(Frame::from(cts))?;
+        rec.timeout = now + T2;
+        rec.requested_packets = requested_packets;
+        rec.packet_offset = None;
+        Ok(())
+    }
+
+    fn abort(
+        abort_reason: AbortReason,
+        pgn: PGN,
+        remote_address: u8,
+        local_address: u8,
+        tx_queue: &mut TxQueue,
+    ) -> Result<(), Error> {
+        let abort = ETPCM::Abort {
+            abort_reason,
+            pgn,
+            remote_address,
+            local_address,
+        };
+        tx_queue.push(Frame::from(abort))?;
+        Ok(())
+    }
+}
@@ -1,11 +1,18 @@
 use crate::frame::*;
-use alloc::{collections::BTreeMap, vec::Vec};
+use crate::time::Instant;
+use crate::transport::TransferHandle;
+use crate::tx_queue::TxQueue;
+use crate::Error;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
 
 /// A de- and encoder for NEMA2000 PGNs using the fast packet transport protocol
 pub struct FastPacketCoder {
     pgns: Vec<PGN>,
     receiver: BTreeMap<PGN, Receiver>,
     transmitter: BTreeMap<PGN, Transmitter>,
+    /// transfers waiting till the active transfer of the PGN is finished
+    pending: BTreeMap<PGN, VecDeque<(Frame, Option<TransferHandle>)>>,
     last_used_sequence: BTreeMap<PGN, u8>,
 }
 
@@ -13,19 +20,55 @@ struct Receiver {
     expected_bytes: u8,
     sequence: u8,
     data: Vec<u8>,
+    /// receive time of the first frame
+    started: Instant,
 }
 struct Transmitter {
     frame: Frame,
+    handle: Option<TransferHandle>,
+    /// item of the next packet
     item: u8,
     sequence: u8,
 }
 
+impl Transmitter {
+    /// Returns the next packet and true if it is the last packet of the transfer
+    fn packet(&self) -> (Frame, bool) {
+        let mut data = [0xFF; 8];
+        data[0] = self.sequence << 5 | (self.item & 0x1F);
+        // the first packet contains the size and 6 bytes, the following packets 7 bytes
+        let (start, offset) = if self.item == 0 {
+            data[1] = self.frame.data().len() as u8;
+            (0, 2)
+        } else {
+            (6 + (self.item as usize - 1) * 7, 1)
+        };
+        let stop = self.frame.data().len().min(start + 8 - offset);
+        data[offset..offset + stop - start].copy_from_slice(&self.frame.data()[start..stop]);
+        (
+            Frame::new(*self.frame.header(), &data),
+            stop >= self.frame.data().len(),
+        )
+    }
+
+    /// Queues the next packet, the last packet carries the transfer handle
+    /// Returns true if the transfer is finished
+    fn push_packet(&mut self, tx_queue: &mut TxQueue) -> Result<bool, Error> {
+        let (packet, last) = self.packet();
+        let handle = if last { self.handle } else { None };
+        tx_queue.push_session(packet, handle)?;
+        self.item += 1;
+        Ok(last)
+    }
+}
+
 impl FastPacketCoder {
     pub fn new(pgns: &[PGN]) -> Self {
         Self {
             pgns: pgns.to_vec(),
             receiver: BTreeMap::new(),
             transmitter: BTreeMap::new(),
+            pending: BTreeMap::new(),
             last_used_sequence: BTreeMap::new(),
         }
     }
@@ -34,7 +77,15 @@ impl FastPacketCoder {
         self.pgns.contains(&pgn)
     }
 
-    pub fn handle_frame(&mut self, header: Header, data: &[u8]) -> Option<Frame> {
+    pub fn handle_frame(
+        &mut self,
+        header: Header,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<Option<Frame>, Error> {
+        if data.len() < 2 {
+            return Err(Error::MalformedFrame);
+        }
         let identifier = data[0];
         // extract sequence identifier
         let sequence = (identifier & 0xE0) >> 5;
@@ -54,6 +105,7 @@ impl FastPacketCoder {
                     expected_bytes,
                     sequence,
                     data: rx_bytes,
+                    started: now,
                 });
             }
         } else {
@@ -61,85 +113,119 @@ impl FastPacketCoder {
             if let Some(rec) = self.receiver.get_mut(&header.pgn()) {
                 // check for correct sequence else ignore message
                 if rec.sequence == sequence {
-                    let copy_till = 8.min((rec.expected_bytes as usize - rec.data.len()) + 1);
+                    let copy_till = data
+                        .len()
+                        .min((rec.expected_bytes as usize).saturating_sub(rec.data.len()) + 1);
                     rec.data.extend_from_slice(&data[1..copy_till]);
 
                     // check if receive is done
                     if rec.data.len() >= rec.expected_bytes as usize {
                         let entry = self.receiver.remove(&header.pgn()).unwrap();
-                        return Some(Frame::new(header, &entry.data));
+                        return Ok(Some(
+                            Frame::new(header, &entry.data)
+                                .with_timestamp(Timestamp::transfer(entry.started, now)),
+                        ));
                     }
                 }
             }
         }
-        None
+        Ok(None)
     }
 
-    pub fn process_out_transfers<CanDriver: embedded_can::blocking::Can>(
-        &mut self,
-        can_driver: &mut CanDriver,
-    ) {
-        // ToDo: Refactor this
+    /// Returns true if packets of a transfer are pending
+    pub fn has_pending_transfers(&self) -> bool {
+        !self.transmitter.is_empty()
+    }
+
+    pub fn process_out_transfers(&mut self, tx_queue: &mut TxQueue) -> Result<(), Error> {
         let mut finished = Vec::new();
+        // a failed send does not stop the other transfers, the first error is returned
+        let mut result = Ok(());
 
-        for transmitter in self.transmitter.values_mut() {
-            let bytes_send_min = 6 + transmitter.item as usize * 7;
-            let bytes_send_max = transmitter.frame.data().len().min(bytes_send_min + 7);
-            let bytes_to_copy = bytes_send_max - bytes_send_min;
-
-            transmitter.item += 1;
-            let mut data = [0xFF; 8];
-            data[0] = transmitter.sequence << 5 | (transmitter.item & 0x1F);
-
-            data[1..=bytes_to_copy]
-                .copy_from_slice(&transmitter.frame.data()[bytes_send_min..bytes_send_max]);
-            let id = embedded_can::ExtendedId::new((*transmitter.frame.header()).into()).unwrap();
-            can_driver
-                .transmit(&embedded_can::Frame::new(id, &data).unwrap())
-                .unwrap();
-
-            if bytes_send_max >= transmitter.frame.data().len() {
-                let pgn = transmitter.frame.header().pgn();
-                finished.push(pgn);
+        for (pgn, transmitter) in &mut self.transmitter {
+            if tx_queue.is_full() {
+                break;
+            }
+            // a packet which could not be queued is retried with the next call
+            match transmitter.push_packet(tx_queue) {
+                Ok(true) => finished.push(*pgn),
+                Ok(false) => (),
+                Err(error) => result = result.and(Err(error)),
             }
         }
 
-        for f in finished {
-            self.transmitter.remove(&f);
+        for pgn in finished {
+            self.transmitter.remove(&pgn);
+            // the next transfer of the PGN starts with the next call
+            if let Some((pdu, handle)) = self.pending.get_mut(&pgn).and_then(VecDeque::pop_front) {
+                let transmitter = self.transmitter(pdu, handle);
+                self.transmitter.insert(pgn, transmitter);
+            }
         }
+        self.pending.retain(|_, queue| !queue.is_empty());
+        result
     }
 
-    pub fn send_frame<CanDriver: embedded_can::blocking::Can>(
+    /// Queues the first packet of the frame, the transfer of the handle is completed by the last packet
+    /// Frames of a PGN with an active transfer are sent after that transfer
+    pub fn send_frame(
         &mut self,
         pdu: Frame,
-        can_driver: &mut CanDriver,
-    ) {
+        handle: Option<TransferHandle>,
+        tx_queue: &mut TxQueue,
+    ) -> Result<(), Error> {
+        let pgn = pdu.header().pgn();
+        if self.transmitter.contains_key(&pgn) {
+            self.pending
+                .entry(pgn)
+                .or_default()
+                .push_back((pdu, handle));
+            return Ok(());
+        }
+        let mut transmitter = self.transmitter(pdu, handle);
+        transmitter.push_packet(tx_queue)?;
+        self.transmitter.insert(pgn, transmitter);
+        Ok(())
+    }
+
+    /// Removes a queued or active transfer, returns false if the handle belongs to no transfer
+    pub fn cancel_transfer(&mut self, handle: TransferHandle) -> bool {
+        for queue in self.pending.values_mut() {
+            if let Some(index) = queue.iter().position(|(_, h)| *h == Some(handle)) {
+                queue.remove(index);
+                return true;
+            }
+        }
+        let Some(pgn) = self
+            .transmitter
+            .iter()
+            .find(|(_, transmitter)| transmitter.handle == Some(handle))
+            .map(|(pgn, _)| *pgn)
+        else {
+            return false;
+        };
+        self.transmitter.remove(&pgn);
+        if let Some((pdu, handle)) = self.pending.get_mut(&pgn).and_then(VecDeque::pop_front) {
+            let transmitter = self.transmitter(pdu, handle);
+            self.transmitter.insert(pgn, transmitter);
+        }
+        true
+    }
+
+    /// creates the transmitter of a new transfer with the next sequence number of the PGN
+    fn transmitter(&mut self, pdu: Frame, handle: Option<TransferHandle>) -> Transmitter {
         let sequence = self
             .last_used_sequence
             .entry(pdu.header().pgn())
             .or_default();
-        let item = 0;
-        let bytes = pdu.data().len();
-
-        // ignore packet if pgn is already send
-        if let alloc::collections::btree_map::Entry::Vacant(e) =
-            self.transmitter.entry(pdu.header().pgn())
-        {
-            let mut data = [0xFF; 8];
-            data[0] = *sequence << 5 | (item & 0x1F);
-            data[1] = bytes as u8;
-            data[2..8].copy_from_slice(&pdu.data()[0..6]);
-            let id = embedded_can::ExtendedId::new((*pdu.header()).into()).unwrap();
-            can_driver
-                .transmit(&embedded_can::Frame::new(id, &data).unwrap())
-                .unwrap();
-
-            e.insert(Transmitter {
-                frame: pdu,
-                sequence: *sequence,
-                item,
-            });
-            *sequence += 1;
-        }
+        let transmitter = Transmitter {
+            frame: pdu,
+            handle,
+            item: 0,
+            sequence: *sequence,
+        };
+        // the sequence counter has 3 bits
+        *sequence = (*sequence + 1) & 0x07;
+        transmitter
     }
 }
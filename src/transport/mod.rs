@@ -1,22 +1,68 @@
+use crate::config::StackConfig;
 use crate::frame::*;
+use crate::time::{Duration, Instant};
+use crate::tx_queue::TxQueue;
+use crate::Error;
+use crossbeam_queue::ArrayQueue;
 
+mod etp_frames;
+mod event;
+mod extended_transport_packager;
 mod fast_packet;
 mod tp_frames;
+mod transfer;
 mod transport_packager;
 
 use self::fast_packet::FastPacketCoder;
+use crate::transport::extended_transport_packager::ExtendedTransportPackager;
 use crate::transport::transport_packager::TransportPackager;
 
-pub struct TransportManager {
+pub use self::event::{TransportDirection, TransportEvent, TransportSession};
+pub use self::tp_frames::AbortReason;
+pub(crate) use self::transfer::Transfers;
+pub use self::transfer::{TransferError, TransferHandle, TransferState};
+
+// Timeouts as defined by J1939-21
+// Tr (200ms) and Th (500ms) are met, because the stack responds within the same process() call
+// and never holds a connection as receiver.
+/// Receiver: maximum time between two data packets
+const T1: Duration = Duration::millis(750);
+/// Receiver: maximum time between sending a CTS and receiving the next data packet
+const T2: Duration = Duration::millis(1250);
+/// Sender: maximum time between the last data packet of a window (or RTS) and the next CTS or EOMA
+const T3: Duration = Duration::millis(1250);
+/// Sender: maximum time after a hold CTS till the next CTS
+const T4: Duration = Duration::millis(1050);
+/// Maximum size of a message sent by the transport protocol, larger messages use the extended transport protocol
+const TP_MAX_MESSAGE_SIZE: usize = 1785;
+/// Sender: number of retransmission requests for the same packets before the session is aborted
+const MAX_RETRANSMITS: u8 = 2;
+
+pub(crate) struct TransportManager<TimeDriver: crate::time::TimerDriver> {
     transport_packager: TransportPackager,
+    extended_transport_packager: ExtendedTransportPackager,
     fast_packet: FastPacketCoder,
+    events: ArrayQueue<TransportEvent>,
+    /// number of events dropped by the full event queue
+    dropped_events: u32,
+    transfers: Transfers,
+    time: TimeDriver,
 }
 
-impl TransportManager {
-    pub fn new(pgns: &[PGN]) -> Self {
+impl<TimeDriver: crate::time::TimerDriver> TransportManager<TimeDriver> {
+    pub fn new(pgns: &[PGN], config: &StackConfig, time: TimeDriver) -> Self {
         Self {
-            transport_packager: TransportPackager::new(),
+            transport_packager: TransportPackager::new(
+                config.tp_max_packets_per_cts,
+                config.tp_receive_window,
+                config.bam_packet_gap,
+            ),
+            extended_transport_packager: ExtendedTransportPackager::new(config.etp_receive_window),
             fast_packet: FastPacketCoder::new(pgns),
+            events: ArrayQueue::new(config.event_queue_capacity),
+            dropped_events: 0,
+            transfers: Transfers::new(),
+            time,
         }
     }
 
@@ -28,48 +74,173 @@ impl TransportManager {
             || self.fast_packet.is_fastpacket(pgn)
     }
 
-    pub fn handle_frame<CanDriver: embedded_can::blocking::Can>(
+    /// Returns a frame when a transfer is completed
+    /// Events of the transport protocols are collected, even if the frame is malformed or a response could not be sent
+    pub fn handle_frame(
+        &mut self,
+        header: Header,
+        data: &[u8],
+        tx_queue: &mut TxQueue,
+    ) -> Result<Option<Frame>, Error> {
+        let result = self.handle_transport_frame(header, data, tx_queue);
+        self.collect_events();
+        result
+    }
+
+    fn handle_transport_frame(
         &mut self,
         header: Header,
         data: &[u8],
-        can_driver: &mut CanDriver,
-    ) -> Option<Frame> {
-        let mut result = None;
+        tx_queue: &mut TxQueue,
+    ) -> Result<Option<Frame>, Error> {
+        let now = self.time.now();
         match header.pgn() {
             PGN_TP_CM => {
-                let tpcm = tp_frames::TPCM::from_frame(header, data);
-                self.transport_packager.process_tpcm(tpcm, can_driver);
+                let tpcm = tp_frames::TPCM::from_frame(header, data)?;
+                self.transport_packager
+                    .process_tpcm(tpcm, header.priority(), now, tx_queue)?;
+                Ok(None)
             }
             PGN_TP_DT => {
-                let tpdt = tp_frames::TPDT::from_frame(header, data);
-                result = self.transport_packager.process_tpdt(tpdt, can_driver);
+                let tpdt = tp_frames::TPDT::from_frame(header, data)?;
+                self.transport_packager.process_tpdt(tpdt, now, tx_queue)
+            }
+            PGN_ETP_CM => {
+                let etpcm = etp_frames::ETPCM::from_frame(header, data)?;
+                self.extended_transport_packager.process_etpcm(
+                    etpcm,
+                    header.priority(),
+                    now,
+                    tx_queue,
+                )?;
+                Ok(None)
+            }
+            PGN_ETP_DT => {
+                let etpdt = etp_frames::ETPDT::from_frame(header, data)?;
+                self.extended_transport_packager
+                    .process_etpdt(etpdt, now, tx_queue)
             }
-            PGN_ETP_CM => todo!(),
-            PGN_ETP_DT => todo!(),
             _ if self.fast_packet.is_fastpacket(header.pgn()) => {
-                result = self.fast_packet.handle_frame(header, data);
+                self.fast_packet.handle_frame(header, data, now)
             }
-            _ => panic!("Invalid PGN handled by transport manager"),
+            _ => Err(Error::MalformedFrame),
         }
-        result
     }
 
-    pub fn process<CanDriver: embedded_can::blocking::Can>(&mut self, can_driver: &mut CanDriver) {
-        self.transport_packager.process_out_transfers(can_driver);
-        self.fast_packet.process_out_transfers(can_driver);
+    /// Runs all steps even if one fails, the first error is returned
+    pub fn process(&mut self, tx_queue: &mut TxQueue) -> Result<(), Error> {
+        let now = self.time.now();
+        let results = [
+            self.transport_packager.process_timeouts(now, tx_queue),
+            self.transport_packager.process_out_transfers(now, tx_queue),
+            self.extended_transport_packager
+                .process_timeouts(now, tx_queue),
+            self.extended_transport_packager
+                .process_out_transfers(now, tx_queue),
+            self.fast_packet.process_out_transfers(tx_queue),
+        ];
+        self.collect_events();
+        results.into_iter().collect()
+    }
+
+    /// Returns the next instant `process` has to be called
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.fast_packet.has_pending_transfers() {
+            return Some(Instant::from_ticks(0));
+        }
+        [
+            self.transport_packager.next_deadline(),
+            self.extended_transport_packager.next_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
     }
 
-    pub fn send_frame<CanDriver: embedded_can::blocking::Can>(
+    pub fn send_frame(
         &mut self,
         frame: Frame,
-        can_driver: &mut CanDriver,
-    ) {
-        if self.fast_packet.is_fastpacket(frame.header().pgn()) {
-            self.fast_packet.send_frame(frame, can_driver);
-        } else if frame.data().len() > 1785 {
-            todo!("ETP not yet supported");
+        handle: Option<TransferHandle>,
+        tx_queue: &mut TxQueue,
+    ) -> Result<(), Error> {
+        if let Some(handle) = handle {
+            self.transfers.update(handle, TransferState::Queued);
+        }
+        let result = if self.fast_packet.is_fastpacket(frame.header().pgn()) {
+            // fast packet transfers are not acknowledged, the last packet completes the transfer when it is transmitted
+            self.fast_packet.send_frame(frame, handle, tx_queue)
+        } else if frame.data().len() > TP_MAX_MESSAGE_SIZE {
+            self.extended_transport_packager.new_out_transfer(
+                frame,
+                handle,
+                self.time.now(),
+                tx_queue,
+            )
         } else {
-            self.transport_packager.new_out_transfer(frame, can_driver);
+            self.transport_packager
+                .new_out_transfer(frame, handle, self.time.now(), tx_queue)
+        };
+        self.collect_events();
+        result
+    }
+
+    /// Stops a queued or active transfer, peer to peer sessions are aborted
+    /// Returns false if the transfer is already finished or unknown
+    pub fn cancel_transfer(
+        &mut self,
+        handle: TransferHandle,
+        tx_queue: &mut TxQueue,
+    ) -> Result<bool, Error> {
+        let now = self.time.now();
+        let cancelled = match self
+            .transport_packager
+            .cancel_transfer(handle, now, tx_queue)
+        {
+            Ok(false) if self.fast_packet.cancel_transfer(handle) => Ok(true),
+            Ok(false) => self
+                .extended_transport_packager
+                .cancel_transfer(handle, tx_queue),
+            result => result,
+        };
+        // the session is closed even if the abort could not be sent
+        if cancelled != Ok(false) {
+            self.set_transfer_state(handle, TransferState::Failed(TransferError::Cancelled));
+        }
+        self.collect_events();
+        cancelled
+    }
+
+    /// Returns the state of an active or recently finished transfer
+    pub fn transfer_state(&self, handle: &TransferHandle) -> Option<TransferState> {
+        self.transfers.state(handle)
+    }
+
+    /// Updates the state of a transfer not handled by the transport protocols
+    pub fn set_transfer_state(&mut self, handle: TransferHandle, state: TransferState) {
+        self.transfers.update(handle, state);
+    }
+
+    /// Returns the oldest transport event
+    pub fn get_event(&mut self) -> Option<TransportEvent> {
+        self.events.pop()
+    }
+
+    /// Returns the number of events dropped by the full event queue
+    pub fn dropped_events(&self) -> u32 {
+        self.dropped_events
+    }
+
+    /// moves the events of the packagers into the event queue, the oldest events are dropped if the queue is full
+    fn collect_events(&mut self) {
+        for event in self
+            .transport_packager
+            .take_events()
+            .chain(self.extended_transport_packager.take_events())
+        {
+            self.transfers.handle_event(&event);
+            if self.events.force_push(event).is_some() {
+                self.dropped_events = self.dropped_events.saturating_add(1);
+            }
         }
     }
 }
@@ -1,4 +1,5 @@
 use crate::frame::{Frame, Header, PGN, PGN_TP_CM, PGN_TP_DT};
+use crate::Error;
 const ADDRESS_GLOBAL: u8 = 0xFF;
 
 // ------------------------------------------------- TP DT ---------------------------------------
@@ -24,13 +25,19 @@ impl From<TPDT> for Frame {
 }
 
 impl TPDT {
-    pub fn from_frame(header: Header, data: &[u8]) -> Self {
-        Self {
+    pub fn from_frame(header: Header, data: &[u8]) -> Result<Self, Error> {
+        let (Some(local_address), Ok(data)) =
+            (header.destination_address(), <&[u8; 8]>::try_from(data))
+        else {
+            return Err(Error::MalformedFrame);
+        };
+        let [sequence_number, data @ ..] = *data;
+        Ok(Self {
             remote_address: header.source_address(),
-            local_address: header.destination_address().unwrap(),
-            sequence_number: data[0],
-            data: data[1..8].try_into().unwrap(),
-        }
+            local_address,
+            sequence_number,
+            data,
+        })
     }
 }
 
@@ -177,73 +184,100 @@ impl From<TPCM> for Frame {
 }
 
 impl TPCM {
-    pub fn from_frame(header: Header, data: &[u8]) -> Self {
+    pub fn from_frame(header: Header, data: &[u8]) -> Result<Self, Error> {
+        let (Some(local_address), Ok(data)) =
+            (header.destination_address(), <&[u8; 8]>::try_from(data))
+        else {
+            return Err(Error::MalformedFrame);
+        };
         let data_pgn: PGN = PGN::new(u32::from_le_bytes([data[5], data[6], data[7], 0x00]));
-        if header.destination_address().unwrap() == ADDRESS_GLOBAL && data[0] == CTRL_BAM {
+        if local_address == ADDRESS_GLOBAL && data[0] == CTRL_BAM {
             let bytes = u16::from_le_bytes([data[1], data[2]]);
-            TPCM::Bam {
+            Ok(TPCM::Bam {
                 message_size: bytes,
                 packet_count: data[3],
                 pgn: data_pgn,
                 remote_address: header.source_address(),
-                local_address: header.destination_address().unwrap(),
-            }
+                local_address,
+            })
         } else if data[0] == CTRL_RTS {
             let bytes = u16::from_le_bytes([data[1], data[2]]);
-            TPCM::Rts {
+            Ok(TPCM::Rts {
                 message_size: bytes,
                 packet_count: data[3],
                 max_packets_per_cts: data[4],
                 pgn: data_pgn,
                 remote_address: header.source_address(),
-                local_address: header.destination_address().unwrap(),
-            }
+                local_address,
+            })
         } else if data[0] == CTRL_CTS {
-            TPCM::Cts {
+            Ok(TPCM::Cts {
                 expected_packets: data[1],
                 next_packet_number: data[2],
                 pgn: data_pgn,
                 remote_address: header.source_address(),
-                local_address: header.destination_address().unwrap(),
-            }
+                local_address,
+            })
         } else if data[0] == CTRL_END_OF_MSG_ACK {
             let bytes = u16::from_le_bytes([data[1], data[2]]);
-            TPCM::EndOfMsg {
+            Ok(TPCM::EndOfMsg {
                 message_size: bytes,
                 packet_count: data[3],
                 pgn: data_pgn,
                 remote_address: header.source_address(),
-                local_address: header.destination_address().unwrap(),
-            }
+                local_address,
+            })
         } else if data[0] == CTRL_CONN_ABORT {
-            TPCM::Abort {
+            Ok(TPCM::Abort {
                 abort_reason: from_u8(data[1]),
                 pgn: data_pgn,
                 remote_address: header.source_address(),
-                local_address: header.destination_address().unwrap(),
-            }
+                local_address,
+            })
         } else {
-            panic!("TPCM Message with invalid control byte")
+            Err(Error::MalformedFrame)
         }
     }
 }
 
+/// Reason of a transport protocol connection abort as defined by J1939-21 and ISO 11783-3
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AbortReason {
+    /// Reserved value
     Reserved = 0,
+    /// Already in one or more connection managed sessions and cannot support another
     AlreadyConnected = 1,
+    /// System resources were needed for another task
     NoResources = 2,
+    /// A timeout occurred
     Timeout = 3,
+    /// CTS received while a data transfer is in progress
     CTSWhileTransfer = 4,
+    /// Maximum retransmit request limit reached
     RetransmitLimit = 5,
+    /// Unexpected data transfer packet
     UnexpectedTransfer = 6,
+    /// Bad sequence number
     BadSequenceNumber = 7,
+    /// Duplicate sequence number
     DuplicateSequenceNumber = 8,
+    /// Total message size is greater than supported
     MessageSizeToHigh = 9,
+    /// Unexpected DPO PGN (extended transport protocol)
+    UnexpectedDpoPgn = 10,
+    /// DPO packet count greater than the CTS (extended transport protocol)
+    DpoPacketsGreaterCts = 11,
+    /// Bad DPO offset (extended transport protocol)
+    BadDpoOffset = 12,
+    /// Unexpected CTS PGN (extended transport protocol)
+    UnexpectedCtsPgn = 14,
+    /// Number of requested packets exceeds the message size (extended transport protocol)
+    CtsPacketsExceedMessage = 15,
+    /// Any other reason
     Other = 250,
 }
 
-const fn from_u8(n: u8) -> AbortReason {
+pub const fn from_u8(n: u8) -> AbortReason {
     match n {
         0 => AbortReason::Reserved,
         1 => AbortReason::AlreadyConnected,
@@ -255,6 +289,11 @@ const fn from_u8(n: u8) -> AbortReason {
         7 => AbortReason::BadSequenceNumber,
         8 => AbortReason::DuplicateSequenceNumber,
         9 => AbortReason::MessageSizeToHigh,
+        10 => AbortReason::UnexpectedDpoPgn,
+        11 => AbortReason::DpoPacketsGreaterCts,
+        12 => AbortReason::BadDpoOffset,
+        14 => AbortReason::UnexpectedCtsPgn,
+        15 => AbortReason::CtsPacketsExceedMessage,
         250 => AbortReason::Other,
         _ => AbortReason::Other,
     }
@@ -281,7 +320,7 @@ mod tests {
 
     #[test]
     fn deserialize_pdu_tpdt() {
-        let pdu = TPDT::from_frame(Header::from(0x00EBFF01), &[1, 1, 2, 3, 4, 5, 6, 7]);
+        let pdu = TPDT::from_frame(Header::from(0x00EBFF01), &[1, 1, 2, 3, 4, 5, 6, 7]).unwrap();
         assert_eq!(
             pdu,
             TPDT {
@@ -316,7 +355,8 @@ mod tests {
         let pdu = TPCM::from_frame(
             Header::from(0x00ECFF01),
             &[32, 20, 0, 3, 255, 0xB0, 0xFE, 0],
-        );
+        )
+        .unwrap();
         assert_eq!(
             pdu,
             TPCM::Bam {
@@ -346,7 +386,8 @@ mod tests {
     }
     #[test]
     fn deserialize_pdu_tpcm_rts() {
-        let pdu = TPCM::from_frame(Header::from(0x18EC9B90), &[16, 20, 0, 3, 1, 0, 223, 0]);
+        let pdu =
+            TPCM::from_frame(Header::from(0x18EC9B90), &[16, 20, 0, 3, 1, 0, 223, 0]).unwrap();
         assert_eq!(
             pdu,
             TPCM::Rts {
@@ -376,7 +417,8 @@ mod tests {
     }
     #[test]
     fn deserialize_pdu_tpcm_cts() {
-        let pdu = TPCM::from_frame(Header::from(0x1CEC909B), &[17, 1, 1, 255, 255, 0, 223, 0]);
+        let pdu =
+            TPCM::from_frame(Header::from(0x1CEC909B), &[17, 1, 1, 255, 255, 0, 223, 0]).unwrap();
         assert_eq!(
             pdu,
             TPCM::Cts {
@@ -405,7 +447,8 @@ mod tests {
     }
     #[test]
     fn deserialize_pdu_tpcm_ack() {
-        let pdu = TPCM::from_frame(Header::from(0x1CEC909B), &[19, 20, 0, 3, 255, 0, 223, 0]);
+        let pdu =
+            TPCM::from_frame(Header::from(0x1CEC909B), &[19, 20, 0, 3, 255, 0, 223, 0]).unwrap();
         assert_eq!(
             pdu,
             TPCM::EndOfMsg {
@@ -439,7 +482,8 @@ mod tests {
         let pdu = TPCM::from_frame(
             Header::from(0x1CEC909B),
             &[255, 1, 255, 255, 255, 0xB0, 0xFE, 0],
-        );
+        )
+        .unwrap();
         assert_eq!(
             pdu,
             TPCM::Abort {
@@ -0,0 +1,124 @@
+use crate::transport::{AbortReason, TransportEvent};
+use crate::Error;
+use alloc::collections::{BTreeMap, VecDeque};
+
+/// Number of finished transfers whose state is kept for polling
+const MAX_FINISHED_TRANSFERS: usize = 20;
+
+/// Identifies a frame sent by [`Stack::send_frame`](crate::stack::Stack::send_frame) or
+/// [`ControlFunction::send_frame`](crate::control_function::ControlFunction::send_frame)
+/// The state of the transfer is polled with [`Stack::transfer_state`](crate::stack::Stack::transfer_state)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TransferHandle {
+    control_function: Option<usize>,
+    id: u32,
+}
+
+impl TransferHandle {
+    pub(crate) const fn new(control_function: Option<usize>, id: u32) -> Self {
+        Self {
+            control_function,
+            id,
+        }
+    }
+    pub(crate) const fn control_function(&self) -> Option<usize> {
+        self.control_function
+    }
+    pub(crate) const fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+/// Reason of a failed transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferError {
+    /// The frame could not be transmitted by the can driver
+    Send(Error),
+    /// The frame was dropped from the full transmit queue
+    Dropped,
+    /// The destination address is not claimed by any control function on the bus
+    DestinationOffline,
+    /// The transport protocol session was aborted by the stack or the receiver
+    Aborted(AbortReason),
+    /// The receiver did not respond in time
+    TimedOut,
+    /// The transfer was cancelled by [`Stack::cancel_transfer`](crate::stack::Stack::cancel_transfer)
+    Cancelled,
+}
+
+/// State of a sent frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+    /// The frame waits in a send queue
+    Queued,
+    /// The transport protocol session is active
+    InProgress,
+    /// The frame was sent, transport protocol peer to peer transfers are acknowledged by the receiver
+    Completed,
+    /// The frame was not sent completely
+    Failed(TransferError),
+}
+
+impl TransferState {
+    /// Returns true if the transfer is completed or failed
+    pub const fn is_finished(&self) -> bool {
+        matches!(self, TransferState::Completed | TransferState::Failed(_))
+    }
+}
+
+/// States of all transfers which are active or recently finished
+pub(crate) struct Transfers {
+    states: BTreeMap<TransferHandle, TransferState>,
+    /// finished transfers, the oldest are removed first
+    finished: VecDeque<TransferHandle>,
+}
+
+impl Transfers {
+    pub fn new() -> Self {
+        Self {
+            states: BTreeMap::new(),
+            finished: VecDeque::new(),
+        }
+    }
+
+    pub fn state(&self, handle: &TransferHandle) -> Option<TransferState> {
+        self.states.get(handle).copied()
+    }
+
+    /// Finished transfers are not updated anymore
+    pub fn update(&mut self, handle: TransferHandle, state: TransferState) {
+        if self.state(&handle).is_some_and(|state| state.is_finished()) {
+            return;
+        }
+        self.states.insert(handle, state);
+        if state.is_finished() {
+            self.finished.push_back(handle);
+            while self.finished.len() > MAX_FINISHED_TRANSFERS {
+                if let Some(handle) = self.finished.pop_front() {
+                    self.states.remove(&handle);
+                }
+            }
+        }
+    }
+
+    pub fn handle_event(&mut self, event: &TransportEvent) {
+        let (session, state) = match event {
+            TransportEvent::Started(session) | TransportEvent::Progress { session, .. } => {
+                (session, TransferState::InProgress)
+            }
+            TransportEvent::Completed(session) => (session, TransferState::Completed),
+            TransportEvent::Aborted {
+                session, reason, ..
+            } => (
+                session,
+                TransferState::Failed(TransferError::Aborted(*reason)),
+            ),
+            TransportEvent::TimedOut(session) => {
+                (session, TransferState::Failed(TransferError::TimedOut))
+            }
+        };
+        if let Some(handle) = session.handle {
+            self.update(handle, state);
+        }
+    }
+}
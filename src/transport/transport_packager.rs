@@ -1,89 +1,216 @@
-use crate::frame::{Frame, Header, PGN};
+use crate::frame::{Frame, Header, Timestamp, PGN};
+use crate::time::{Duration, Instant};
 use crate::transport::tp_frames::*;
-use alloc::collections::BTreeMap;
+use crate::transport::{
+    TransferHandle, TransportEvent, TransportSession, MAX_RETRANSMITS, T1, T2, T3, T4,
+    TP_MAX_MESSAGE_SIZE,
+};
+use crate::tx_queue::TxQueue;
+use crate::Error;
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::vec::Vec;
 
 struct BroadcastReceiver {
     pub data: Vec<u8>,
+    /// announced size of the message
+    pub message_size: usize,
     pub pgn: PGN,
     pub priority: u8,
     pub last_packet_index: u8,
+    pub timeout: Instant,
+    /// receive time of the BAM
+    pub started: Instant,
 }
 struct BroadcastSender {
     pub pdu: Frame,
+    pub handle: Option<TransferHandle>,
     pub last_packet_index: u8,
     pub packet_count: u8,
+    /// the BAM control message was already sent
+    pub announced: bool,
+    /// the next packet must not be sent before this instant
+    pub next_packet: Instant,
+}
+
+impl BroadcastSender {
+    fn new(pdu: Frame, handle: Option<TransferHandle>, next_packet: Instant) -> Self {
+        Self {
+            packet_count: pdu.data().len().div_ceil(7) as u8,
+            pdu,
+            handle,
+            last_packet_index: 0,
+            announced: false,
+            next_packet,
+        }
+    }
+
+    fn bam(&self) -> TPCM {
+        TPCM::Bam {
+            message_size: self.pdu.data().len() as u16,
+            packet_count: self.packet_count,
+            pgn: self.pdu.header().pgn(),
+            remote_address: 0xFF,
+            local_address: self.pdu.header().source_address(),
+        }
+    }
+
+    fn session(&self) -> TransportSession {
+        TransportSession::transmit(&self.pdu, self.handle)
+    }
 }
 
 struct P2PReceiver {
     pub data: Vec<u8>,
+    /// announced size of the message
+    pub message_size: usize,
     pub pgn: PGN,
     pub priority: u8,
+    pub packet_count: u8,
     pub max_packets_per_cts: u8,
     pub last_packet_index: u8,
-    pub last_requested_index: u8,
+    pub requested_till_index: u8,
+    pub timeout: Instant,
+    /// receive time of the RTS
+    pub started: Instant,
+}
+
+impl P2PReceiver {
+    /// requests the next window of packets from the sender
+    fn cts(&mut self, remote_address: u8, local_address: u8) -> TPCM {
+        let expected_packets = self
+            .max_packets_per_cts
+            .min(self.packet_count - self.last_packet_index);
+        self.requested_till_index = self.last_packet_index + expected_packets;
+        TPCM::Cts {
+            expected_packets,
+            next_packet_number: self.last_packet_index + 1,
+            pgn: self.pgn,
+            remote_address,
+            local_address,
+        }
+    }
 }
 
 struct P2PSender {
     pub pdu: Frame,
+    pub handle: Option<TransferHandle>,
     pub last_packet_index: u8,
     pub send_till_index: u8,
+    /// number of packets sent at least once
+    pub sent_packets: u8,
+    /// consecutive CTS requesting already sent packets
+    pub retransmits: u8,
+    /// only checked while no packets are pending
+    pub timeout: Instant,
+}
+
+impl P2PSender {
+    fn packet_count(&self) -> u8 {
+        self.pdu.data().len().div_ceil(7) as u8
+    }
+
+    fn session(&self) -> TransportSession {
+        TransportSession::transmit(&self.pdu, self.handle)
+    }
 }
 
 pub struct TransportPackager {
+    /// packets per CTS announced in our RTS
+    max_packets_per_cts: u8,
+    /// maximum packets requested by our CTS
+    receive_window: u8,
+    /// time between two packets of a broadcast transfer
+    bam_packet_gap: Duration,
+    /// incoming broadcast per source address
+    /// TP.DT packets do not contain the PGN, so J1939-21 allows only one BAM per source address
+    /// and a new BAM replaces the unfinished broadcast of the source address
     in_broadcast: BTreeMap<u8, BroadcastReceiver>,
-    out_broadcast: Option<BroadcastSender>,
+    /// active broadcast per source address
+    out_broadcast: BTreeMap<u8, BroadcastSender>,
+    /// broadcasts waiting till the active broadcast of the source address is finished
+    pending_broadcast: BTreeMap<u8, VecDeque<(Frame, Option<TransferHandle>)>>,
     in_p2p: BTreeMap<(u8, u8), P2PReceiver>,
     out_p2p: BTreeMap<(u8, u8), P2PSender>,
+    events: Vec<TransportEvent>,
 }
 
 impl TransportPackager {
-    pub fn new() -> Self {
+    pub fn new(max_packets_per_cts: u8, receive_window: u8, bam_packet_gap: Duration) -> Self {
         Self {
+            max_packets_per_cts,
+            receive_window,
+            bam_packet_gap,
             in_broadcast: BTreeMap::new(),
-            out_broadcast: None,
+            out_broadcast: BTreeMap::new(),
+            pending_broadcast: BTreeMap::new(),
             in_p2p: BTreeMap::new(),
             out_p2p: BTreeMap::new(),
+            events: Vec::new(),
         }
     }
 
-    pub fn process_tpcm<CanDriver: embedded_can::blocking::Can>(
+    /// Returns all events since the last call
+    pub fn take_events(&mut self) -> alloc::vec::Drain<'_, TransportEvent> {
+        self.events.drain(..)
+    }
+
+    pub fn process_tpcm(
         &mut self,
         tpcm: TPCM,
-        can_driver: &mut CanDriver,
-    ) {
+        priority: u8,
+        now: Instant,
+        tx_queue: &mut TxQueue,
+    ) -> Result<(), Error> {
         match tpcm {
             TPCM::Rts {
                 message_size,
-                packet_count: _,
+                packet_count,
                 max_packets_per_cts,
                 pgn,
                 remote_address,
                 local_address,
             } => {
-                if let alloc::collections::btree_map::Entry::Vacant(e) =
-                    self.in_p2p.entry((remote_address, local_address))
-                {
-                    e.insert(P2PReceiver {
-                        data: Vec::with_capacity(message_size as usize),
+                // larger messages are sent by the extended transport protocol
+                let abort_reason = if message_size as usize > TP_MAX_MESSAGE_SIZE {
+                    Some(AbortReason::MessageSizeToHigh)
+                } else if (message_size as usize).div_ceil(7) != packet_count as usize {
+                    Some(AbortReason::NoResources)
+                } else {
+                    None
+                };
+                if let Some(abort_reason) = abort_reason {
+                    let abort = TPCM::Abort {
+                        abort_reason,
                         pgn,
-                        priority: 0,
-                        max_packets_per_cts,
-                        last_packet_index: 0,
-                        last_requested_index: 0,
-                    });
-
-                    let cts = TPCM::Cts {
-                        expected_packets: self.in_p2p[&(remote_address, local_address)]
-                            .max_packets_per_cts,
-                        next_packet_number: 1,
-                        pgn: self.in_p2p[&(remote_address, local_address)].pgn,
                         remote_address,
                         local_address,
                     };
-                    can_driver
-                        .transmit(&Frame::from(cts).can())
-                        .expect("Can Transmit Error!");
+                    tx_queue.push(Frame::from(abort))?;
+                } else if !self.in_p2p.contains_key(&(remote_address, local_address)) {
+                    let mut rec = P2PReceiver {
+                        data: Vec::with_capacity(message_size as usize),
+                        message_size: message_size as usize,
+                        pgn,
+                        priority,
+                        packet_count: (message_size as usize).div_ceil(7) as u8,
+                        // 0xFF in the RTS means no limit by the sender
+                        max_packets_per_cts: max_packets_per_cts.min(self.receive_window).max(1),
+                        last_packet_index: 0,
+                        requested_till_index: 0,
+                        timeout: now + T2,
+                        started: now,
+                    };
+                    // the session is only opened if the CTS could be queued
+                    let cts = rec.cts(remote_address, local_address);
+                    tx_queue.push(Frame::from(cts))?;
+                    self.events
+                        .push(TransportEvent::Started(TransportSession::receive(
+                            pgn,
+                            remote_address,
+                            local_address,
+                            message_size as usize,
+                        )));
+                    self.in_p2p.insert((remote_address, local_address), rec);
                 } else {
                     let abort = TPCM::Abort {
                         abort_reason: AbortReason::AlreadyConnected,
@@ -91,100 +218,224 @@ impl TransportPackager {
                         remote_address,
                         local_address,
                     };
-                    can_driver
-                        .transmit(&Frame::from(abort).can())
-                        .expect("Can Transmit Error!");
+                    tx_queue.push(Frame::from(abort))?;
                 }
             }
             TPCM::Cts {
                 expected_packets,
                 next_packet_number,
-                pgn: _,
+                pgn,
                 remote_address,
                 local_address,
             } => {
-                // check pgn
-                if let Some(sender) = self.out_p2p.get_mut(&(local_address, remote_address)) {
-                    sender.last_packet_index = next_packet_number - 1;
-                    sender.send_till_index = sender.last_packet_index + expected_packets;
+                let Some(sender) = self.out_p2p.get_mut(&(local_address, remote_address)) else {
+                    return Ok(());
+                };
+                if sender.pdu.header().pgn() != pgn {
+                    return Ok(());
+                }
+                let abort_reason = if sender.last_packet_index < sender.send_till_index {
+                    // the receiver must not send a CTS till the requested packets are received
+                    Some(AbortReason::CTSWhileTransfer)
+                } else if expected_packets == 0 {
+                    // hold the connection open, the receiver sends the next CTS later
+                    sender.timeout = now + T4;
+                    None
+                } else if next_packet_number == 0 {
+                    // packet numbers start with 1
+                    Some(AbortReason::BadSequenceNumber)
+                } else {
+                    let window_start = next_packet_number - 1;
+                    if window_start < sender.sent_packets {
+                        // the receiver requests a retransmission of already sent packets
+                        sender.retransmits += 1;
+                    } else {
+                        sender.retransmits = 0;
+                    }
+                    if sender.retransmits > MAX_RETRANSMITS {
+                        Some(AbortReason::RetransmitLimit)
+                    } else if window_start as u16 + expected_packets as u16
+                        > sender.packet_count() as u16
+                    {
+                        Some(AbortReason::CtsPacketsExceedMessage)
+                    } else {
+                        sender.last_packet_index = window_start.min(sender.sent_packets);
+                        sender.send_till_index = window_start + expected_packets;
+                        None
+                    }
+                };
+                if let Some(abort_reason) = abort_reason {
+                    self.events.push(TransportEvent::Aborted {
+                        session: sender.session(),
+                        reason: abort_reason,
+                        remote: false,
+                    });
+                    let abort = TPCM::Abort {
+                        abort_reason,
+                        pgn,
+                        remote_address,
+                        local_address,
+                    };
+                    self.out_p2p.remove(&(local_address, remote_address));
+                    tx_queue.push(Frame::from(abort))?;
                 }
             }
             TPCM::EndOfMsg {
                 message_size: _,
                 packet_count: _,
-                pgn: _,
+                pgn,
                 remote_address,
                 local_address,
             } => {
-                // check pgn
-                self.out_p2p.remove(&(remote_address, local_address));
+                let Some(sender) = self.out_p2p.get(&(local_address, remote_address)) else {
+                    return Ok(());
+                };
+                if sender.pdu.header().pgn() != pgn {
+                    return Ok(());
+                }
+                self.events
+                    .push(TransportEvent::Completed(sender.session()));
+                self.out_p2p.remove(&(local_address, remote_address));
             }
             TPCM::Abort {
-                abort_reason: _,
+                abort_reason,
                 pgn,
                 remote_address,
                 local_address,
             } => {
-                // ToDo receiver or sender abort -> check pgn?
-                if let Some(transfer) = self.in_p2p.get(&(local_address, remote_address)) {
+                if let Some(transfer) = self.in_p2p.get(&(remote_address, local_address)) {
                     if transfer.pgn == pgn {
-                        self.in_p2p.remove(&(local_address, remote_address));
+                        self.events.push(TransportEvent::Aborted {
+                            session: TransportSession::receive(
+                                pgn,
+                                remote_address,
+                                local_address,
+                                transfer.message_size,
+                            ),
+                            reason: abort_reason,
+                            remote: true,
+                        });
+                        self.in_p2p.remove(&(remote_address, local_address));
                     }
                 }
-                if let Some(transfer) = self.out_p2p.get(&(remote_address, local_address)) {
+                if let Some(transfer) = self.out_p2p.get(&(local_address, remote_address)) {
                     if transfer.pdu.header().pgn() == pgn {
-                        self.out_p2p.remove(&(remote_address, local_address));
+                        self.events.push(TransportEvent::Aborted {
+                            session: transfer.session(),
+                            reason: abort_reason,
+                            remote: true,
+                        });
+                        self.out_p2p.remove(&(local_address, remote_address));
                     }
                 }
             }
             TPCM::Bam {
                 message_size,
-                packet_count: _,
+                packet_count,
                 pgn,
                 remote_address,
                 local_address: _,
             } => {
-                self.in_broadcast
-                    .entry(remote_address)
-                    .or_insert(BroadcastReceiver {
+                // broadcasts are not aborted on the bus, an invalid announcement is ignored
+                if message_size as usize > TP_MAX_MESSAGE_SIZE
+                    || (message_size as usize).div_ceil(7) != packet_count as usize
+                {
+                    return Ok(());
+                }
+                // data packets do not contain the pgn, therefore only one broadcast per source address is possible
+                // a new BAM replaces an unfinished broadcast of the same source address
+                self.in_broadcast.insert(
+                    remote_address,
+                    BroadcastReceiver {
                         data: Vec::with_capacity(message_size as usize),
+                        message_size: message_size as usize,
                         pgn,
-                        priority: 0,
+                        priority,
                         last_packet_index: 0,
-                    });
+                        timeout: now + T1,
+                        started: now,
+                    },
+                );
+                self.events
+                    .push(TransportEvent::Started(TransportSession::receive(
+                        pgn,
+                        remote_address,
+                        0xFF,
+                        message_size as usize,
+                    )));
             }
         }
+        Ok(())
     }
 
-    pub fn process_tpdt<CanDriver: embedded_can::blocking::Can>(
+    pub fn process_tpdt(
         &mut self,
         tpdt: TPDT,
-        can_driver: &mut CanDriver,
-    ) -> Option<Frame> {
+        now: Instant,
+        tx_queue: &mut TxQueue,
+    ) -> Result<Option<Frame>, Error> {
         let mut result = None;
         if tpdt.local_address == 0xFF {
             if let Some(rec) = &mut self.in_broadcast.get_mut(&tpdt.remote_address) {
-                if rec.last_packet_index + 1 == tpdt.sequence_number {
+                // an overflowing sequence number drops the session
+                if rec.last_packet_index.checked_add(1) == Some(tpdt.sequence_number) {
                     rec.last_packet_index += 1;
-                    let missing_bytes = rec.data.capacity() - rec.data.len();
-                    if missing_bytes <= 8 {
+                    rec.timeout = now + T1;
+                    let missing_bytes = rec.message_size - rec.data.len();
+                    if missing_bytes <= 7 {
                         // last packet
                         rec.data.extend_from_slice(&tpdt.data[0..missing_bytes]);
                         // finalize packet
                         let entry = self.in_broadcast.remove(&tpdt.remote_address).unwrap();
-                        result = Some(Frame::new(
-                            Header::new(
+                        self.events
+                            .push(TransportEvent::Completed(TransportSession::receive(
                                 entry.pgn,
-                                entry.priority,
                                 tpdt.remote_address,
-                                Some(tpdt.local_address),
-                            ),
-                            &entry.data,
-                        ));
+                                0xFF,
+                                entry.data.len(),
+                            )));
+                        // PDU2 pgns have no destination address
+                        let destination_address = if entry.pgn.is_broadcast() {
+                            None
+                        } else {
+                            Some(tpdt.local_address)
+                        };
+                        result = Some(
+                            Frame::new(
+                                Header::new(
+                                    entry.pgn,
+                                    entry.priority,
+                                    tpdt.remote_address,
+                                    destination_address,
+                                ),
+                                &entry.data,
+                            )
+                            .with_timestamp(Timestamp::transfer(entry.started, now)),
+                        );
                     } else {
                         rec.data.extend_from_slice(&tpdt.data);
+                        self.events.push(TransportEvent::Progress {
+                            session: TransportSession::receive(
+                                rec.pgn,
+                                tpdt.remote_address,
+                                0xFF,
+                                rec.message_size,
+                            ),
+                            bytes: rec.data.len(),
+                        });
                     }
                 } else {
+                    // broadcasts are not aborted on the bus, the session is just dropped
+                    self.events.push(TransportEvent::Aborted {
+                        session: TransportSession::receive(
+                            rec.pgn,
+                            tpdt.remote_address,
+                            0xFF,
+                            rec.message_size,
+                        ),
+                        reason: AbortReason::BadSequenceNumber,
+                        remote: false,
+                    });
                     self.in_broadcast.remove(&tpdt.remote_address);
                 }
             }
@@ -193,64 +444,90 @@ impl TransportPackager {
             .get_mut(&(tpdt.remote_address, tpdt.local_address))
         {
             if rec.last_packet_index + 1 == tpdt.sequence_number {
-                rec.last_packet_index += 1;
-                let missing_bytes = rec.data.capacity() - rec.data.len();
-                if missing_bytes <= 8 {
-                    // last packet
-                    rec.data.extend_from_slice(&tpdt.data[0..missing_bytes]);
+                let missing_bytes = rec.message_size - rec.data.len();
+                if missing_bytes <= 7 {
+                    // last packet, the session is only closed if the acknowledgement could be queued
+                    let ack = TPCM::EndOfMsg {
+                        message_size: rec.message_size as u16,
+                        packet_count: tpdt.sequence_number,
+                        pgn: rec.pgn,
+                        remote_address: tpdt.remote_address,
+                        local_address: tpdt.local_address,
+                    };
+                    tx_queue.push(Frame::from(ack))?;
                     // finalize packet
-                    let entry = self
+                    let mut entry = self
                         .in_p2p
                         .remove(&(tpdt.remote_address, tpdt.local_address))
                         .unwrap();
+                    entry.data.extend_from_slice(&tpdt.data[0..missing_bytes]);
                     let received_bytes = entry.data.len();
-                    result = Some(Frame::new(
-                        Header::new(
+                    self.events
+                        .push(TransportEvent::Completed(TransportSession::receive(
                             entry.pgn,
-                            entry.priority,
                             tpdt.remote_address,
-                            Some(tpdt.local_address),
+                            tpdt.local_address,
+                            received_bytes,
+                        )));
+                    result = Some(
+                        Frame::new(
+                            Header::new(
+                                entry.pgn,
+                                entry.priority,
+                                tpdt.remote_address,
+                                Some(tpdt.local_address),
+                            ),
+                            &entry.data,
+                        )
+                        .with_timestamp(Timestamp::transfer(entry.started, now)),
+                    );
+                } else if rec.requested_till_index == tpdt.sequence_number {
+                    // last packet of the window, the packet is only taken if the next CTS could be queued
+                    let requested_till_index = rec.requested_till_index;
+                    rec.last_packet_index += 1;
+                    let cts = rec.cts(tpdt.remote_address, tpdt.local_address);
+                    if let Err(error) = tx_queue.push(Frame::from(cts)) {
+                        rec.last_packet_index -= 1;
+                        rec.requested_till_index = requested_till_index;
+                        return Err(error);
+                    }
+                    rec.data.extend_from_slice(&tpdt.data);
+                    rec.timeout = now + T2;
+                    self.events.push(TransportEvent::Progress {
+                        session: TransportSession::receive(
+                            rec.pgn,
+                            tpdt.remote_address,
+                            tpdt.local_address,
+                            rec.message_size,
                         ),
-                        &entry.data,
-                    ));
-                    let ack = TPCM::EndOfMsg {
-                        message_size: received_bytes as u16,
-                        packet_count: entry.last_packet_index,
-                        pgn: entry.pgn,
-                        remote_address: tpdt.remote_address,
-                        local_address: tpdt.local_address,
-                    };
-                    can_driver
-                        .transmit(&Frame::from(ack).can())
-                        .expect("Can Transmit Error!");
+                        bytes: rec.data.len(),
+                    });
                 } else {
+                    rec.last_packet_index += 1;
+                    rec.timeout = now + T1;
                     rec.data.extend_from_slice(&tpdt.data);
-                    if rec.last_requested_index + rec.max_packets_per_cts == rec.last_packet_index {
-                        let cts = TPCM::Cts {
-                            expected_packets: rec.max_packets_per_cts,
-                            next_packet_number: rec.last_packet_index + 1,
-                            pgn: rec.pgn,
-                            remote_address: tpdt.remote_address,
-                            local_address: tpdt.local_address,
-                        };
-                        rec.last_requested_index += rec.max_packets_per_cts;
-                        can_driver
-                            .transmit(&Frame::from(cts).can())
-                            .expect("Can Transmit Error!");
-                    }
                 }
             } else {
                 // Abort wrong Sequence Number
+                self.events.push(TransportEvent::Aborted {
+                    session: TransportSession::receive(
+                        rec.pgn,
+                        tpdt.remote_address,
+                        tpdt.local_address,
+                        rec.message_size,
+                    ),
+                    reason: AbortReason::UnexpectedTransfer,
+                    remote: false,
+                });
                 let abort = TPCM::Abort {
                     abort_reason: AbortReason::UnexpectedTransfer,
                     pgn: rec.pgn, // PGN is not known
                     remote_address: tpdt.remote_address,
                     local_address: tpdt.local_address,
                 };
-                can_driver
-                    .transmit(&Frame::from(abort).can())
-                    .expect("Can Transmit Error!");
-                self.in_broadcast.remove(&tpdt.remote_address);
+                self.in_p2p
+                    .remove(&(tpdt.remote_address, tpdt.local_address));
+                tx_queue.push(Frame::from(abort))?;
             }
         } else {
             // Abort unexpected transfer
@@ -260,70 +537,168 @@ impl TransportPackager {
                 remote_address: tpdt.remote_address,
                 local_address: tpdt.local_address,
             };
-            can_driver
-                .transmit(&Frame::from(abort).can())
-                .expect("Can Transmit Error!");
+            tx_queue.push(Frame::from(abort))?;
         }
-        result
+        Ok(result)
     }
 
-    pub fn new_out_transfer<CanDriver: embedded_can::blocking::Can>(
+    pub fn new_out_transfer(
         &mut self,
         pdu: Frame,
-        can_driver: &mut CanDriver,
-    ) {
+        handle: Option<TransferHandle>,
+        now: Instant,
+        tx_queue: &mut TxQueue,
+    ) -> Result<(), Error> {
         let bytes_to_send = pdu.data().len() as u16;
-        let packets_to_send = ((pdu.data().len() + 7/* ceil division */) / 8) as u8;
+        let packets_to_send = pdu.data().len().div_ceil(7) as u8;
 
         if pdu.header().pgn().is_broadcast() || pdu.header().destination_address() == Some(0xFF) {
+            let source_address = pdu.header().source_address();
+            if self.out_broadcast.contains_key(&source_address) {
+                // only one broadcast per source address, send it after the active one
+                self.pending_broadcast
+                    .entry(source_address)
+                    .or_default()
+                    .push_back((pdu, handle));
+                return Ok(());
+            }
             // create bam transfer
-            let bam = TPCM::Bam {
-                message_size: bytes_to_send,
-                packet_count: packets_to_send,
-                pgn: pdu.header().pgn(),
-                remote_address: 0xFF,
-                local_address: pdu.header().source_address(),
-            };
-            can_driver
-                .transmit(&Frame::from(bam).can())
-                .expect("Can Transmit Error!");
-            self.out_broadcast = Some(BroadcastSender {
+            let mut sender = BroadcastSender::new(pdu, handle, now + self.bam_packet_gap);
+            tx_queue.push(Frame::from(sender.bam()))?;
+            sender.announced = true;
+            self.events.push(TransportEvent::Started(sender.session()));
+            self.out_broadcast.insert(source_address, sender);
+        } else {
+            let key = (
+                pdu.header().source_address(),
+                pdu.header().destination_address().unwrap(),
+            );
+            let sender = P2PSender {
                 pdu,
+                handle,
                 last_packet_index: 0,
-                packet_count: packets_to_send,
-            });
-        } else {
+                send_till_index: 0,
+                sent_packets: 0,
+                retransmits: 0,
+                timeout: now + T3,
+            };
+            if self.out_p2p.contains_key(&key) {
+                // only one transfer per connection is allowed
+                self.events.push(TransportEvent::Aborted {
+                    session: sender.session(),
+                    reason: AbortReason::AlreadyConnected,
+                    remote: false,
+                });
+                return Ok(());
+            }
+            let pdu = &sender.pdu;
             let rts = TPCM::Rts {
                 message_size: bytes_to_send,
                 packet_count: packets_to_send,
-                max_packets_per_cts: 1,
+                max_packets_per_cts: self.max_packets_per_cts,
                 pgn: pdu.header().pgn(),
                 remote_address: pdu.header().destination_address().unwrap(),
                 local_address: pdu.header().source_address(),
             };
-            can_driver
-                .transmit(&Frame::from(rts).can())
-                .expect("Can Transmit Error!");
-            self.out_p2p.insert(
-                (
-                    pdu.header().source_address(),
-                    pdu.header().destination_address().unwrap(),
-                ),
-                P2PSender {
-                    pdu,
-                    last_packet_index: 0,
-                    send_till_index: 0,
-                },
+            tx_queue.push(Frame::from(rts))?;
+            self.events.push(TransportEvent::Started(sender.session()));
+            self.out_p2p.insert(key, sender);
+        }
+        Ok(())
+    }
+
+    /// Stops the transmission of the transfer, peer to peer sessions are aborted
+    /// Returns false if the handle belongs to no active or queued transfer
+    pub fn cancel_transfer(
+        &mut self,
+        handle: TransferHandle,
+        now: Instant,
+        tx_queue: &mut TxQueue,
+    ) -> Result<bool, Error> {
+        for queue in self.pending_broadcast.values_mut() {
+            if let Some(index) = queue.iter().position(|(_, h)| *h == Some(handle)) {
+                queue.remove(index);
+                return Ok(true);
+            }
+        }
+        if let Some(source_address) = self
+            .out_broadcast
+            .iter()
+            .find(|(_, sender)| sender.handle == Some(handle))
+            .map(|(source_address, _)| *source_address)
+        {
+            // broadcasts are not aborted on the bus
+            self.out_broadcast.remove(&source_address);
+            self.start_pending_broadcast(source_address, now);
+            return Ok(true);
+        }
+        if let Some((local_address, remote_address)) = self
+            .out_p2p
+            .iter()
+            .find(|(_, sender)| sender.handle == Some(handle))
+            .map(|(key, _)| *key)
+        {
+            let sender = self
+                .out_p2p
+                .remove(&(local_address, remote_address))
+                .unwrap();
+            let abort = TPCM::Abort {
+                abort_reason: AbortReason::NoResources,
+                pgn: sender.pdu.header().pgn(),
+                remote_address,
+                local_address,
+            };
+            tx_queue.push(Frame::from(abort))?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// starts the next queued broadcast of the source address after the packet gap
+    fn start_pending_broadcast(&mut self, source_address: u8, now: Instant) {
+        if let Some((pdu, handle)) = self
+            .pending_broadcast
+            .get_mut(&source_address)
+            .and_then(VecDeque::pop_front)
+        {
+            self.out_broadcast.insert(
+                source_address,
+                BroadcastSender::new(pdu, handle, now + self.bam_packet_gap),
             );
         }
+        self.pending_broadcast.retain(|_, queue| !queue.is_empty());
     }
 
-    pub fn process_out_transfers<CanDriver: embedded_can::blocking::Can>(
+    pub fn process_out_transfers(
         &mut self,
-        can_driver: &mut CanDriver,
-    ) {
+        now: Instant,
+        tx_queue: &mut TxQueue,
+    ) -> Result<(), Error> {
+        // a failed send does not stop the other transfers, the first error is returned
+        let mut result = Ok(());
         // process broadcasts
-        if let Some(sender) = &mut self.out_broadcast {
+        let mut finished = Vec::new();
+        for (source_address, sender) in self
+            .out_broadcast
+            .iter_mut()
+            .filter(|(_, sender)| now >= sender.next_packet)
+        {
+            // packets are only created if they fit into the transmit queue
+            if tx_queue.is_full() {
+                break;
+            }
+            sender.next_packet = now + self.bam_packet_gap;
+            if !sender.announced {
+                // the announcement is repeated after the packet gap if it could not be sent
+                match tx_queue.push(Frame::from(sender.bam())) {
+                    Ok(()) => {
+                        sender.announced = true;
+                        self.events.push(TransportEvent::Started(sender.session()));
+                    }
+                    Err(error) => result = result.and(Err(error)),
+                }
+                continue;
+            }
             let mut data = [0xFF; 7];
             let start = sender.last_packet_index as usize * 7;
             let stop = (start + 7).min(sender.pdu.data().len());
@@ -334,18 +709,31 @@ impl TransportPackager {
                 sequence_number: sender.last_packet_index + 1,
                 data,
             };
+            // the packet is repeated after the packet gap if it could not be queued
+            if let Err(error) = tx_queue.push(Frame::from(tpdt)) {
+                result = result.and(Err(error));
+                continue;
+            }
             sender.last_packet_index += 1;
-            can_driver
-                .transmit(&Frame::from(tpdt).can())
-                .expect("Can Transmit Error!");
+            let session = sender.session();
             if sender.last_packet_index >= sender.packet_count {
-                self.out_broadcast = None;
+                self.events.push(TransportEvent::Completed(session));
+                finished.push(*source_address);
+            } else {
+                self.events.push(TransportEvent::Progress {
+                    session,
+                    bytes: stop,
+                });
             }
         }
+        for source_address in finished {
+            self.out_broadcast.remove(&source_address);
+            self.start_pending_broadcast(source_address, now);
+        }
         // process peer to peer transfers
         for sender in self.out_p2p.values_mut() {
-            // we can send something
-            if sender.last_packet_index < sender.send_till_index {
+            // send the whole requested window while the transmit queue has room
+            while sender.last_packet_index < sender.send_till_index && !tx_queue.is_full() {
                 let mut data = [0xFF; 7];
                 let start = sender.last_packet_index as usize * 7;
                 let stop = (start + 7).min(sender.pdu.data().len());
@@ -357,11 +745,101 @@ impl TransportPackager {
                     sequence_number: sender.last_packet_index + 1,
                     data,
                 };
+                // the packet is retried with the next call if it could not be queued
+                if let Err(error) = tx_queue.push(Frame::from(tpdt)) {
+                    result = result.and(Err(error));
+                    break;
+                }
                 sender.last_packet_index += 1;
-                can_driver
-                    .transmit(&Frame::from(tpdt).can())
-                    .expect("Can Transmit Error!");
+                sender.sent_packets = sender.sent_packets.max(sender.last_packet_index);
+                // wait for the next CTS or the end of message acknowledgement
+                sender.timeout = now + T3;
+                // the whole window is sent, the completion is reported with the end of message acknowledgement
+                if sender.last_packet_index == sender.send_till_index
+                    && sender.last_packet_index < sender.packet_count()
+                {
+                    self.events.push(TransportEvent::Progress {
+                        session: sender.session(),
+                        bytes: stop,
+                    });
+                }
             }
         }
+        result
+    }
+
+    /// Returns the next instant a session times out or a packet has to be sent
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.in_broadcast
+            .values()
+            .map(|rec| rec.timeout)
+            .chain(self.in_p2p.values().map(|rec| rec.timeout))
+            .chain(self.out_broadcast.values().map(|sender| sender.next_packet))
+            .chain(self.out_p2p.values().map(|sender| {
+                if sender.last_packet_index < sender.send_till_index {
+                    // packets of the requested window are pending
+                    Instant::from_ticks(0)
+                } else {
+                    sender.timeout
+                }
+            }))
+            .min()
+    }
+
+    /// Closes all sessions whose peer did not respond in time
+    /// Peer to peer sessions are aborted with [`AbortReason::Timeout`], broadcasts are dropped
+    pub fn process_timeouts(&mut self, now: Instant, tx_queue: &mut TxQueue) -> Result<(), Error> {
+        // all timed out sessions are closed, the first transmit error is returned
+        let mut result = Ok(());
+        let events = &mut self.events;
+        self.in_broadcast.retain(|remote_address, rec| {
+            if now < rec.timeout {
+                return true;
+            }
+            events.push(TransportEvent::TimedOut(TransportSession::receive(
+                rec.pgn,
+                *remote_address,
+                0xFF,
+                rec.message_size,
+            )));
+            false
+        });
+
+        self.in_p2p.retain(|(remote_address, local_address), rec| {
+            if now < rec.timeout {
+                return true;
+            }
+            events.push(TransportEvent::TimedOut(TransportSession::receive(
+                rec.pgn,
+                *remote_address,
+                *local_address,
+                rec.message_size,
+            )));
+            let abort = TPCM::Abort {
+                abort_reason: AbortReason::Timeout,
+                pgn: rec.pgn,
+                remote_address: *remote_address,
+                local_address: *local_address,
+            };
+            result = result.and(tx_queue.push(Frame::from(abort)));
+            false
+        });
+
+        self.out_p2p
+            .retain(|(local_address, remote_address), sender| {
+                if sender.last_packet_index < sender.send_till_index || now < sender.timeout {
+                    return true;
+                }
+                events.push(TransportEvent::TimedOut(sender.session()));
+                let abort = TPCM::Abort {
+                    abort_reason: AbortReason::Timeout,
+                    pgn: sender.pdu.header().pgn(),
+                    remote_address: *remote_address,
+                    local_address: *local_address,
+                };
+                result = result.and(tx_queue.push(Frame::from(abort)));
+                false
+            });
+        result
     }
 }
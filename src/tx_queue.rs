@@ -0,0 +1,443 @@
+use crate::config::OverflowPolicy;
+use crate::driver::Driver;
+use crate::frame::Frame;
+use crate::transport::{TransferError, TransferHandle, TransferState};
+use crate::Error;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Number of frames of transport sessions which may be queued beyond the capacity
+/// Control frames of the sessions are still queued if the queue is filled by other frames
+const SESSION_RESERVE: usize = 8;
+
+struct Queued {
+    frame: Frame,
+    handle: Option<TransferHandle>,
+    /// control or data frame of a transport session, never dropped by the overflow policy
+    session: bool,
+}
+
+/// Frames waiting for the can driver, ordered by J1939 priority and then by arrival
+/// Frames which could not be transmitted stay in the queue and are retried with the next call of `transmit`
+pub(crate) struct TxQueue {
+    /// key is the priority and a sequence number of the arrival
+    frames: BTreeMap<(u8, u64), Queued>,
+    next_sequence: u64,
+    capacity: usize,
+    overflow: OverflowPolicy,
+    /// transfers which are transmitted or dropped since the last call of `take_finished`
+    finished: Vec<(TransferHandle, TransferState)>,
+    /// transfers handed to a driver which may still replace their frame
+    pending: Vec<(Frame, TransferHandle)>,
+    /// number of frames dropped by the overflow policy
+    dropped: u32,
+}
+
+impl TxQueue {
+    pub fn new(capacity: usize, overflow: OverflowPolicy) -> Self {
+        Self {
+            frames: BTreeMap::new(),
+            // sequence numbers below are used for frames replaced by the can driver
+            next_sequence: u32::MAX as u64,
+            capacity,
+            overflow,
+            finished: Vec::new(),
+            pending: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Returns true if no frame is queued and no transfer waits for its completion
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty() && self.pending.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.frames.len() >= self.capacity
+    }
+
+    /// Returns the number of frames dropped by the overflow policy
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Adds a control or data frame of a transport session
+    pub fn push(&mut self, frame: Frame) -> Result<(), Error> {
+        self.push_session(frame, None)
+    }
+
+    /// Adds a frame of a transport session, the state of the transfer is reported by `take_finished`
+    /// The frame is not dropped by the overflow policy and may use the reserved capacity for sessions
+    pub fn push_session(
+        &mut self,
+        frame: Frame,
+        handle: Option<TransferHandle>,
+    ) -> Result<(), Error> {
+        if self.frames.len() >= self.capacity + SESSION_RESERVE {
+            return Err(Error::QueueFull);
+        }
+        self.insert(frame, handle, true);
+        Ok(())
+    }
+
+    /// Adds a frame, the state of the transfer is reported by `take_finished` after it is transmitted or dropped
+    /// Frames of transport sessions are not dropped to make room for the frame
+    pub fn push_transfer(
+        &mut self,
+        frame: Frame,
+        handle: Option<TransferHandle>,
+    ) -> Result<(), Error> {
+        let priority = frame.header().priority();
+        if self.is_full() {
+            let mut droppable = self.frames.iter().filter(|(_, queued)| !queued.session);
+            let dropped = match self.overflow {
+                OverflowPolicy::Reject => None,
+                OverflowPolicy::DropOldest => droppable
+                    .min_by_key(|((_, sequence), _)| *sequence)
+                    .map(|(key, _)| *key),
+                OverflowPolicy::DropLowestPriority => droppable
+                    .next_back()
+                    .map(|(key, _)| *key)
+                    .filter(|lowest| lowest.0 > priority),
+            };
+            let Some(dropped) = dropped.and_then(|key| self.frames.remove(&key)) else {
+                return Err(Error::QueueFull);
+            };
+            self.dropped = self.dropped.saturating_add(1);
+            if let Some(handle) = dropped.handle {
+                self.finished
+                    .push((handle, TransferState::Failed(TransferError::Dropped)));
+            }
+        }
+        self.insert(frame, handle, false);
+        Ok(())
+    }
+
+    fn insert(&mut self, frame: Frame, handle: Option<TransferHandle>, session: bool) {
+        let key = (frame.header().priority(), self.next_sequence);
+        self.next_sequence += 1;
+        self.frames.insert(
+            key,
+            Queued {
+                frame,
+                handle,
+                session,
+            },
+        );
+    }
+
+    /// Removes a queued frame, returns false if the frame is not queued
+    pub fn remove(&mut self, handle: &TransferHandle) -> bool {
+        let key = self
+            .frames
+            .iter()
+            .find(|(_, queued)| queued.handle.as_ref() == Some(handle))
+            .map(|(key, _)| *key);
+        key.and_then(|key| self.frames.remove(&key)).is_some()
+    }
+
+    /// Removes a queued frame transmitted outside of the can driver and finishes its transfer
+    pub fn finish(&mut self, frame: &Frame, state: TransferState) {
+        let key = self
+            .frames
+            .iter()
+            .find(|(_, queued)| queued.frame == *frame)
+            .map(|(key, _)| *key);
+        if let Some(handle) = key
+            .and_then(|key| self.frames.remove(&key))
+            .and_then(|queued| queued.handle)
+        {
+            self.finished.push((handle, state));
+        }
+    }
+
+    /// Transmits the queued frames till the queue is empty or the transmit buffer of the can driver is full
+    pub fn transmit<CanDriver: Driver>(&mut self, can_driver: &mut CanDriver) -> Result<(), Error> {
+        self.complete_pending();
+        while let Some(entry) = self.frames.first_entry() {
+            let can_frame = match entry.get().frame.can() {
+                Ok(can_frame) => can_frame,
+                Err(error) => {
+                    // the frame is dropped, it would block the queue forever
+                    if let Some(handle) = entry.remove().handle {
+                        self.finished
+                            .push((handle, TransferState::Failed(TransferError::Send(error))));
+                    }
+                    return Err(error);
+                }
+            };
+            // the frame stays in the queue if it could not be transmitted
+            match can_driver.transmit(&can_frame) {
+                Ok(replaced) => {
+                    let Queued { frame, handle, .. } = entry.remove();
+                    if let Some(handle) = handle {
+                        if CanDriver::REPLACES_PENDING {
+                            self.pending.push((frame, handle));
+                        } else {
+                            self.finished.push((handle, TransferState::Completed));
+                        }
+                    }
+                    // a pending frame with a lower priority was replaced by the driver
+                    if let Some(frame) = replaced.as_ref().and_then(Frame::from_can) {
+                        self.requeue(frame);
+                    }
+                }
+                Err(nb::Error::WouldBlock) => return Ok(()),
+                Err(nb::Error::Other(error)) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    /// Completes the pending transfers which cannot be replaced by a queued frame with a higher priority
+    fn complete_pending(&mut self) {
+        let highest = self
+            .frames
+            .first_key_value()
+            .map(|((priority, _), _)| *priority);
+        let finished = &mut self.finished;
+        self.pending.retain(|(frame, handle)| {
+            if highest.is_some_and(|highest| highest <= frame.header().priority()) {
+                return true;
+            }
+            finished.push((*handle, TransferState::Completed));
+            false
+        });
+    }
+
+    /// Adds a frame replaced by the can driver in front of all frames with the same priority
+    /// The capacity is ignored and the frame is not dropped again, it was already part of the queue
+    /// The frame keeps its transfer, if the transfer is still pending
+    fn requeue(&mut self, frame: Frame) {
+        let handle = self
+            .pending
+            .iter()
+            .position(|(pending, _)| *pending == frame)
+            .map(|index| self.pending.remove(index).1);
+        let priority = frame.header().priority();
+        let sequence = self
+            .frames
+            .range((priority, 0)..(priority + 1, 0))
+            .next()
+            .map_or(self.next_sequence, |((_, sequence), _)| *sequence)
+            .saturating_sub(1);
+        self.frames.insert(
+            (priority, sequence),
+            Queued {
+                frame,
+                handle,
+                session: true,
+            },
+        );
+    }
+
+    /// Returns the transfers which are transmitted or dropped
+    pub fn take_finished(&mut self) -> alloc::vec::Drain<'_, (TransferHandle, TransferState)> {
+        self.finished.drain(..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::NbDriver;
+    use crate::frame::{Header, PGN};
+    use crate::test_utils::can_driver::TestDriver;
+    use crate::test_utils::frame::TestFrame;
+    use embedded_can::Frame as _;
+
+    fn frame(priority: u8, data: u8) -> Frame {
+        Frame::new(Header::new(PGN::new(0xFEB2), priority, 0x21, None), &[data])
+    }
+
+    #[test]
+    fn priority_order() {
+        let mut driver = TestDriver::new();
+        let mut queue = TxQueue::new(4, OverflowPolicy::Reject);
+        queue.push_transfer(frame(6, 1), None).unwrap();
+        queue.push_transfer(frame(3, 2), None).unwrap();
+        queue.push_transfer(frame(6, 3), None).unwrap();
+        queue.push_transfer(frame(3, 4), None).unwrap();
+        assert_eq!(
+            queue.push_transfer(frame(0, 5), None),
+            Err(Error::QueueFull)
+        );
+        queue.transmit(&mut driver).unwrap();
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x0CFEB221, &[2]))
+        );
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x0CFEB221, &[4]))
+        );
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x18FEB221, &[1]))
+        );
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x18FEB221, &[3]))
+        );
+        assert_eq!(driver.get_can_frame(), None);
+    }
+
+    #[test]
+    fn retry() {
+        let mut driver = TestDriver::new();
+        let mut queue = TxQueue::new(4, OverflowPolicy::Reject);
+        let handle = TransferHandle::new(None, 0);
+        queue.push_transfer(frame(6, 1), Some(handle)).unwrap();
+        driver.set_transmit_error(true);
+        assert_eq!(
+            queue.transmit(&mut driver),
+            Err(Error::Driver(embedded_can::ErrorKind::Other))
+        );
+        assert_eq!(queue.take_finished().next(), None);
+        driver.set_transmit_error(false);
+        queue.transmit(&mut driver).unwrap();
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x18FEB221, &[1]))
+        );
+        assert_eq!(
+            queue.take_finished().next(),
+            Some((handle, TransferState::Completed))
+        );
+    }
+
+    /// Driver with a single transmit buffer, which is replaced by frames with a higher priority
+    struct MailboxDriver {
+        mailbox: Option<TestFrame>,
+    }
+
+    impl embedded_can::nb::Can for MailboxDriver {
+        type Frame = TestFrame;
+        type Error = crate::test_utils::can_driver::TestDriverError;
+
+        fn transmit(&mut self, frame: &TestFrame) -> nb::Result<Option<TestFrame>, Self::Error> {
+            match &self.mailbox {
+                Some(pending) if pending.id() <= frame.id() => Err(nb::Error::WouldBlock),
+                _ => Ok(self.mailbox.replace(frame.clone())),
+            }
+        }
+
+        fn receive(&mut self) -> nb::Result<TestFrame, Self::Error> {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    #[test]
+    fn replaced_frame() {
+        let mut driver = NbDriver::new(MailboxDriver { mailbox: None });
+        let mut queue = TxQueue::new(4, OverflowPolicy::Reject);
+        let replaced = TransferHandle::new(None, 0);
+        let replacing = TransferHandle::new(None, 1);
+        queue.push_transfer(frame(6, 1), Some(replaced)).unwrap();
+        queue.transmit(&mut driver).unwrap();
+        queue.push_transfer(frame(6, 2), None).unwrap();
+        queue.push_transfer(frame(3, 3), Some(replacing)).unwrap();
+        // the higher priority frame replaces the pending frame
+        queue.transmit(&mut driver).unwrap();
+        assert_eq!(
+            driver.inner().mailbox,
+            Some(TestFrame::new2(0x0CFEB221, &[3]))
+        );
+        assert_eq!(queue.take_finished().next(), None);
+        // the replaced frame is sent before the newer frame with the same priority
+        driver.inner().mailbox = None;
+        queue.transmit(&mut driver).unwrap();
+        assert_eq!(
+            driver.inner().mailbox,
+            Some(TestFrame::new2(0x18FEB221, &[1]))
+        );
+        assert_eq!(
+            queue.take_finished().next(),
+            Some((replacing, TransferState::Completed))
+        );
+        driver.inner().mailbox = None;
+        queue.transmit(&mut driver).unwrap();
+        assert_eq!(
+            driver.inner().mailbox,
+            Some(TestFrame::new2(0x18FEB221, &[2]))
+        );
+        // the frame of the same priority could have replaced the pending frame
+        assert_eq!(queue.take_finished().next(), None);
+        queue.transmit(&mut driver).unwrap();
+        assert_eq!(
+            queue.take_finished().next(),
+            Some((replaced, TransferState::Completed))
+        );
+    }
+
+    #[test]
+    fn overflow_drop_oldest() {
+        let mut driver = TestDriver::new();
+        let mut queue = TxQueue::new(2, OverflowPolicy::DropOldest);
+        let handle = TransferHandle::new(None, 0);
+        queue.push_transfer(frame(6, 1), Some(handle)).unwrap();
+        queue.push_transfer(frame(3, 2), None).unwrap();
+        queue.push_transfer(frame(7, 3), None).unwrap();
+        assert_eq!(
+            queue.take_finished().next(),
+            Some((handle, TransferState::Failed(TransferError::Dropped)))
+        );
+        queue.transmit(&mut driver).unwrap();
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x0CFEB221, &[2]))
+        );
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x1CFEB221, &[3]))
+        );
+        assert_eq!(driver.get_can_frame(), None);
+    }
+
+    #[test]
+    fn overflow_drop_lowest_priority() {
+        let mut driver = TestDriver::new();
+        let mut queue = TxQueue::new(2, OverflowPolicy::DropLowestPriority);
+        queue.push_transfer(frame(6, 1), None).unwrap();
+        queue.push_transfer(frame(3, 2), None).unwrap();
+        assert_eq!(
+            queue.push_transfer(frame(6, 3), None),
+            Err(Error::QueueFull)
+        );
+        queue.push_transfer(frame(0, 4), None).unwrap();
+        queue.transmit(&mut driver).unwrap();
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x00FEB221, &[4]))
+        );
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x0CFEB221, &[2]))
+        );
+        assert_eq!(driver.get_can_frame(), None);
+    }
+    #[test]
+    fn overflow_session_frames() {
+        let mut driver = TestDriver::new();
+        let mut queue = TxQueue::new(2, OverflowPolicy::DropOldest);
+        queue.push(frame(6, 1)).unwrap();
+        queue.push_transfer(frame(6, 2), None).unwrap();
+        // the frame of the transport session is not dropped
+        queue.push_transfer(frame(6, 3), None).unwrap();
+        assert_eq!(queue.dropped(), 1);
+        // frames of sessions use the reserved capacity
+        for data in 4..4 + SESSION_RESERVE as u8 {
+            queue.push(frame(7, data)).unwrap();
+        }
+        assert_eq!(queue.push(frame(7, 0)), Err(Error::QueueFull));
+        queue.transmit(&mut driver).unwrap();
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x18FEB221, &[1]))
+        );
+        assert_eq!(
+            driver.get_can_frame(),
+            Some(TestFrame::new2(0x18FEB221, &[3]))
+        );
+    }
+}